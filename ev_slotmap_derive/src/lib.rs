@@ -0,0 +1,112 @@
+//! # ev_slotmap_derive
+//!
+//! Derive macro for `ev_slotmap::ShallowCopy`. Use it through the re-export
+//! in ev_slotmap rather than depending on this crate directly.
+
+#![warn(missing_docs, rust_2018_idioms)]
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, parse_quote, Data, DeriveInput, Error, Fields, Path,
+};
+
+/// Derive `ShallowCopy` for a struct or enum by shallow copying each of its
+/// fields. Every field type has to implement `ShallowCopy` itself, and each
+/// type parameter gets a `ShallowCopy` bound.
+#[proc_macro_derive(ShallowCopy)]
+pub fn derive_shallow_copy(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(mut input: DeriveInput) -> syn::Result<TokenStream2> {
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::ev_slotmap::ShallowCopy));
+    }
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let (pattern, copy) = copy_fields(parse_quote!(Self), &data.fields);
+            quote! {
+                let #pattern = self;
+                #copy
+            }
+        }
+        Data::Enum(data) if data.variants.is_empty() => quote! {
+            match *self {}
+        },
+        Data::Enum(data) => {
+            let arms = data.variants.iter().map(|variant| {
+                let name = &variant.ident;
+                let (pattern, copy) =
+                    copy_fields(parse_quote!(Self::#name), &variant.fields);
+                quote! {
+                    #pattern => #copy,
+                }
+            });
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(data) => {
+            return Err(Error::new(
+                data.union_token.span,
+                "ShallowCopy can't be derived for unions",
+            ));
+        }
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) =
+        input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::ev_slotmap::ShallowCopy for #name #ty_generics
+        #where_clause
+        {
+            unsafe fn shallow_copy(&self) -> ::core::mem::ManuallyDrop<Self> {
+                ::core::mem::ManuallyDrop::new({ #body })
+            }
+        }
+    })
+}
+
+/// Build a pattern binding a reference to each of the given fields, and an
+/// expression that rebuilds the value from shallow copies of them
+fn copy_fields(path: Path, fields: &Fields) -> (TokenStream2, TokenStream2) {
+    let bindings: Vec<_> = (0..fields.len())
+        .map(|i| format_ident!("__field_{}", i, span = Span::mixed_site()))
+        .collect();
+
+    let copies = fields.iter().zip(&bindings).map(|(field, binding)| {
+        let ty = &field.ty;
+        quote_spanned! {ty.span()=>
+            ::core::mem::ManuallyDrop::into_inner(
+                <#ty as ::ev_slotmap::ShallowCopy>::shallow_copy(#binding)
+            )
+        }
+    });
+
+    match fields {
+        Fields::Named(named) => {
+            let names: Vec<_> =
+                named.named.iter().map(|field| &field.ident).collect();
+            (
+                quote! { #path { #(#names: #bindings),* } },
+                quote! { #path { #(#names: #copies),* } },
+            )
+        }
+        Fields::Unnamed(_) => (
+            quote! { #path ( #(#bindings),* ) },
+            quote! { #path ( #(#copies),* ) },
+        ),
+        Fields::Unit => (quote! { #path }, quote! { #path }),
+    }
+}
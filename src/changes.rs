@@ -0,0 +1,164 @@
+use one_way_slot_map::SlotMapKeyData;
+use std::collections::{HashSet, VecDeque};
+use std::{error, fmt};
+
+/// The number of operations kept in the change log if no capacity is given
+pub(crate) const DEFAULT_CHANGE_LOG_CAPACITY: usize = 1024;
+
+/// The kind of operation recorded in the change log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChangeKind {
+    Added(SlotMapKeyData),
+    Replaced(SlotMapKeyData),
+    Removed(SlotMapKeyData),
+    Cleared,
+}
+
+/// Bounded record of the operations behind the most recently published
+/// generations. Only key data and the kind of operation are kept, never
+/// values.
+#[derive(Debug)]
+pub(crate) struct ChangeLog {
+    entries: VecDeque<(u64, ChangeKind)>,
+    capacity: usize,
+    /// Every change up to and including this generation has been forgotten
+    forgotten_through: u64,
+}
+
+impl ChangeLog {
+    pub(crate) fn new(generation: u64) -> Self {
+        ChangeLog {
+            entries: VecDeque::new(),
+            capacity: DEFAULT_CHANGE_LOG_CAPACITY,
+            forgotten_through: generation,
+        }
+    }
+
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.trim();
+    }
+
+    /// Record the change that produced the given generation
+    pub(crate) fn push(&mut self, generation: u64, change: ChangeKind) {
+        self.entries.push_back((generation, change));
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        while self.entries.len() > self.capacity {
+            if let Some((generation, _)) = self.entries.pop_front() {
+                self.forgotten_through = generation;
+            }
+        }
+    }
+
+    pub(crate) fn changes_since(
+        &self,
+        generation: u64,
+    ) -> Result<ChangeSet, TooOld> {
+        if generation < self.forgotten_through {
+            return Err(TooOld {
+                oldest: self.forgotten_through,
+            });
+        }
+
+        let mut result = ChangeSet {
+            generation: self
+                .entries
+                .back()
+                .map_or(self.forgotten_through, |(g, _)| *g)
+                .max(generation),
+            cleared: false,
+            keys: HashSet::new(),
+        };
+
+        for (_, change) in self.entries.iter().filter(|(g, _)| *g > generation)
+        {
+            match change {
+                ChangeKind::Added(key)
+                | ChangeKind::Replaced(key)
+                | ChangeKind::Removed(key) => {
+                    result.keys.insert(*key);
+                }
+                ChangeKind::Cleared => {
+                    // nothing touched before a clear is worth re-reading
+                    result.cleared = true;
+                    result.keys.clear();
+                }
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// The set of slots touched since a given generation, returned by
+/// [`ReadHandle::changes_since`].
+///
+/// [`ReadHandle::changes_since`]: crate::ReadHandle::changes_since
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeSet {
+    generation: u64,
+    cleared: bool,
+    keys: HashSet<SlotMapKeyData>,
+}
+
+impl ChangeSet {
+    /// The latest generation covered by this change set. Pass this to the next
+    /// call to [`ReadHandle::changes_since`] to continue from here.
+    ///
+    /// [`ReadHandle::changes_since`]: crate::ReadHandle::changes_since
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns true if the map was cleared in the covered range. If so, every
+    /// slot not listed in [`ChangeSet::keys`] is now empty.
+    pub fn was_cleared(&self) -> bool {
+        self.cleared
+    }
+
+    /// Get an iterator over the key data for every slot that was inserted,
+    /// replaced or removed in the covered range.
+    pub fn keys(&self) -> impl Iterator<Item = &SlotMapKeyData> {
+        self.keys.iter()
+    }
+
+    /// Returns true if the given slot was touched in the covered range.
+    pub fn contains(&self, key: &SlotMapKeyData) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// Returns the number of slots touched in the covered range.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Returns true if nothing changed in the covered range.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty() && !self.cleared
+    }
+}
+
+/// Error returned by [`ReadHandle::changes_since`] when the requested
+/// generation has already been dropped from the change log.
+///
+/// [`ReadHandle::changes_since`]: crate::ReadHandle::changes_since
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooOld {
+    /// The oldest generation changes can currently be requested from.
+    pub oldest: u64,
+}
+
+impl fmt::Display for TooOld {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "changes are only available since generation {}",
+            self.oldest
+        )
+    }
+}
+
+impl error::Error for TooOld {}
@@ -0,0 +1,163 @@
+use crate::read::{ReadGuard, ReadHandle};
+use crate::write::WriteHandle;
+use one_way_slot_map::SlotMapKey as Key;
+use std::fmt;
+use std::ops::Deref;
+
+pub(crate) fn new<K, P, V>(
+) -> (ClonedReadHandle<K, P, V>, ClonedWriteHandle<K, P, V>)
+where
+    K: Key<P>,
+{
+    let (r, w) = crate::new();
+    let r = ClonedReadHandle { handle: r };
+    let w = ClonedWriteHandle {
+        reader: r.clone(),
+        handle: w,
+    };
+    (r, w)
+}
+
+/// A handle that may be used to read from a slot map created with
+/// [`new_cloned`](crate::new_cloned).
+pub struct ClonedReadHandle<K, P, V>
+where
+    K: Key<P>,
+{
+    handle: ReadHandle<K, P, Box<V>>,
+}
+
+impl<K, P, V> fmt::Debug for ClonedReadHandle<K, P, V>
+where
+    K: fmt::Debug + Key<P>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClonedReadHandle")
+            .field("handle", &self.handle)
+            .finish()
+    }
+}
+
+impl<K, P, V> Clone for ClonedReadHandle<K, P, V>
+where
+    K: Key<P>,
+{
+    fn clone(&self) -> Self {
+        ClonedReadHandle {
+            handle: self.handle.clone(),
+        }
+    }
+}
+
+impl<K, P, V> ClonedReadHandle<K, P, V>
+where
+    K: Key<P>,
+{
+    /// Returns a guarded reference to the value corresponding to the key.
+    ///
+    /// While the guard lives, the map cannot be refreshed.
+    ///
+    /// If no writes have happened or if the write handle has been dropped, then
+    /// None is returned here
+    pub fn get<'rh>(&'rh self, key: &K) -> Option<ReadGuard<'rh, V>> {
+        Some(self.handle.get(key)?.map_ref(|value| &**value))
+    }
+
+    /// Returns a clone of the value corresponding to the key, so no guard has
+    /// to be held while using it.
+    pub fn get_cloned(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.get(key).map(|value| V::clone(&value))
+    }
+
+    /// Returns true if the map contains a value for the specified key.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.handle.contains_key(key)
+    }
+
+    /// Returns the number of values present in the map.
+    pub fn len(&self) -> usize {
+        self.handle.len()
+    }
+
+    /// Returns true if the map contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.handle.is_empty()
+    }
+
+    /// Returns the generation of the currently published version of the map.
+    pub fn generation(&self) -> u64 {
+        self.handle.generation()
+    }
+
+    /// Returns true if the writer has destroyed this map.
+    pub fn is_destroyed(&self) -> bool {
+        self.handle.is_destroyed()
+    }
+}
+
+/// A handle that may be used to modify a slot map created with
+/// [`new_cloned`](crate::new_cloned).
+///
+/// Each value is moved into its own heap allocation on insert, and both
+/// copies of the map point at that one allocation, so values don't need to
+/// implement [`ShallowCopy`](crate::ShallowCopy). Only this handle ever frees
+/// a value, once it has been replaced or removed in both copies. Like
+/// [`WriteHandle`], this derefs to a read handle for the same map.
+pub struct ClonedWriteHandle<K, P, V>
+where
+    K: Key<P>,
+{
+    handle: WriteHandle<K, P, Box<V>>,
+    reader: ClonedReadHandle<K, P, V>,
+}
+
+impl<K, P, V> fmt::Debug for ClonedWriteHandle<K, P, V>
+where
+    K: fmt::Debug + Key<P>,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClonedWriteHandle")
+            .field("handle", &self.handle)
+            .field("reader", &self.reader)
+            .finish()
+    }
+}
+
+impl<K, P, V> ClonedWriteHandle<K, P, V>
+where
+    K: Key<P>,
+{
+    /// Insert the given value into the slot map and return the associated key
+    pub fn insert(&mut self, p: P, v: V) -> K {
+        self.handle.insert(p, Box::new(v))
+    }
+
+    /// Replace the value of the given key with the given value.
+    pub fn update(&mut self, k: K, v: V) {
+        self.handle.update(k, Box::new(v));
+    }
+
+    /// Remove the value from the map for the given key
+    pub fn remove(&mut self, k: &K) {
+        self.handle.remove(k);
+    }
+
+    /// Clear the slot map.
+    pub fn clear(&mut self) {
+        self.handle.clear();
+    }
+}
+
+impl<K, P, V> Deref for ClonedWriteHandle<K, P, V>
+where
+    K: Key<P>,
+{
+    type Target = ClonedReadHandle<K, P, V>;
+    fn deref(&self) -> &Self::Target {
+        &self.reader
+    }
+}
@@ -0,0 +1,539 @@
+use crate::inner::Inner;
+use crate::read::{MapReadRef, ReadHandle};
+use crate::slot_table::{Entry, Slot, SlotTable};
+use crate::write::WriteHandle;
+use crate::ShallowCopy;
+use one_way_slot_map::SlotMapKey as Key;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem::ManuallyDrop;
+use std::sync::Arc;
+
+/// The layout of a dump written by [`MapReadRef::dump`].
+///
+/// Every format lists each slot of the map in order, with its index, its
+/// generation, and its state. Filled slots show their value's `Debug`
+/// output, and empty slots show the next empty slot to be filled after
+/// them, or the end of that list. Unused slots are empty slots that have
+/// never held a value. A map of strings with its first slot emptied looks
+/// like this as text:
+///
+/// ```text
+///      0 gen 1        vacant   -> end
+///      1 gen 0        occupied "one"
+/// ```
+///
+/// as CSV:
+///
+/// ```text
+/// slot,generation,state,next,value
+/// 0,1,vacant,,
+/// 1,0,occupied,,"""one"""
+/// ```
+///
+/// and as JSON:
+///
+/// ```text
+/// [
+///   {"slot": 0, "generation": 1, "state": "vacant", "next": null},
+///   {"slot": 1, "generation": 0, "state": "occupied", "value": "\"one\""}
+/// ]
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DumpFormat {
+    /// A JSON array with an object for each slot.
+    Json,
+    /// A header followed by a line for each slot, with the columns `slot`,
+    /// `generation`, `state`, `next` and `value`.
+    Csv,
+    /// Aligned plain text with a line for each slot. Blank lines and lines
+    /// starting with `#` are skipped when loading.
+    Text,
+}
+
+const OCCUPIED: &str = "occupied";
+const VACANT: &str = "vacant";
+const UNUSED: &str = "unused";
+
+impl<'rh, K, P, V, M> MapReadRef<'rh, K, P, V, M>
+where
+    K: Key<P>,
+    V: fmt::Debug,
+{
+    /// Write every slot of this version of the map to the given writer in a
+    /// human readable format, for debugging. See [`DumpFormat`].
+    ///
+    /// A dump can be loaded back with [`load_dump`](crate::load_dump), as
+    /// long as each value can be parsed from its `Debug` output.
+    pub fn dump(
+        &self,
+        writer: impl Write,
+        format: DumpFormat,
+    ) -> io::Result<()> {
+        let mut writer = io::BufWriter::new(writer);
+        let (slots, _) = self.inner().data.layout();
+
+        match format {
+            DumpFormat::Json => writeln!(writer, "[")?,
+            DumpFormat::Csv => {
+                writeln!(writer, "slot,generation,state,next,value")?
+            }
+            DumpFormat::Text => (),
+        }
+
+        for (index, slot) in slots.iter().enumerate() {
+            let (state, next, value) = match &slot.entry {
+                Entry::Occupied(value) => {
+                    (OCCUPIED, None, Some(format!("{:?}", &**value)))
+                }
+                Entry::Vacant(next) => (VACANT, Some(*next), None),
+                Entry::Unused(next) => (UNUSED, Some(*next), None),
+            };
+            let generation = slot.generation;
+
+            match format {
+                DumpFormat::Json => {
+                    let separator =
+                        if index + 1 < slots.len() { "," } else { "" };
+                    write!(
+                        writer,
+                        "  {{\"slot\": {}, \"generation\": {}, \"state\": \"{}\"",
+                        index, generation, state
+                    )?;
+                    match next {
+                        Some(Some(next)) => {
+                            write!(writer, ", \"next\": {}", next)?
+                        }
+                        Some(None) => write!(writer, ", \"next\": null")?,
+                        None => (),
+                    }
+                    if let Some(value) = value {
+                        write!(writer, ", \"value\": ")?;
+                        write_json_string(&mut writer, &value)?;
+                    }
+                    writeln!(writer, "}}{}", separator)?;
+                }
+                DumpFormat::Csv => {
+                    write!(writer, "{},{},{},", index, generation, state)?;
+                    if let Some(Some(next)) = next {
+                        write!(writer, "{}", next)?;
+                    }
+                    write!(writer, ",")?;
+                    if let Some(value) = value {
+                        write!(writer, "\"{}\"", value.replace('"', "\"\""))?;
+                    }
+                    writeln!(writer)?;
+                }
+                DumpFormat::Text => {
+                    write!(
+                        writer,
+                        "{:>6} gen {:<8} {:<8}",
+                        index, generation, state
+                    )?;
+                    match (next, value) {
+                        (Some(Some(next)), _) => {
+                            write!(writer, " -> {}", next)?
+                        }
+                        (Some(None), _) => write!(writer, " -> end")?,
+                        (None, Some(value)) => write!(writer, " {}", value)?,
+                        (None, None) => (),
+                    }
+                    writeln!(writer)?;
+                }
+            }
+        }
+
+        if format == DumpFormat::Json {
+            writeln!(writer, "]")?;
+        }
+        writer.flush()
+    }
+}
+
+fn write_json_string(writer: &mut dyn Write, value: &str) -> io::Result<()> {
+    write!(writer, "\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => write!(writer, "\\\"")?,
+            '\\' => write!(writer, "\\\\")?,
+            '\n' => write!(writer, "\\n")?,
+            '\r' => write!(writer, "\\r")?,
+            '\t' => write!(writer, "\\t")?,
+            c if (c as u32) < 0x20 => write!(writer, "\\u{:04x}", c as u32)?,
+            c => write!(writer, "{}", c)?,
+        }
+    }
+    write!(writer, "\"")
+}
+
+/// A slot as read from a dump, with its value still unparsed
+struct Row {
+    slot: usize,
+    generation: u32,
+    entry: Entry<String>,
+}
+
+fn invalid(message: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn parse_number<T: std::str::FromStr>(text: &str, what: &str) -> io::Result<T> {
+    text.trim().parse().map_err(|_| {
+        invalid(format_args!("{} {:?} isn't a number", what, text))
+    })
+}
+
+/// Parse the next empty slot after an empty one, which is missing at the end
+/// of the list
+fn parse_next(next: Option<&str>) -> io::Result<Option<usize>> {
+    next.map(|next| parse_number(next, "next slot")).transpose()
+}
+
+/// Build a row from its fields, as they appear in any of the formats
+fn row(
+    slot: &str,
+    generation: &str,
+    state: &str,
+    next: Option<&str>,
+    value: Option<String>,
+) -> io::Result<Row> {
+    let entry = match state {
+        OCCUPIED => Entry::Occupied(
+            value.ok_or_else(|| invalid("filled slot has no value"))?,
+        ),
+        VACANT => Entry::Vacant(parse_next(next)?),
+        UNUSED => Entry::Unused(parse_next(next)?),
+        _ => return Err(invalid(format_args!("unknown state {:?}", state))),
+    };
+    Ok(Row {
+        slot: parse_number(slot, "slot")?,
+        generation: parse_number(generation, "generation")?,
+        entry,
+    })
+}
+
+fn read_text(reader: impl BufRead) -> io::Result<Vec<Row>> {
+    let mut rows = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let mut rest = line.trim();
+        if rest.is_empty() || rest.starts_with('#') {
+            continue;
+        }
+
+        let mut token = || {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let (token, after) = rest.split_at(end);
+            rest = after.trim_start();
+            token
+        };
+        let slot = token();
+        if token() != "gen" {
+            return Err(invalid(format_args!(
+                "expected a generation in {:?}",
+                line
+            )));
+        }
+        let generation = token();
+        let state = token();
+
+        let (next, value) = match state {
+            VACANT | UNUSED => match rest.strip_prefix("->").map(str::trim) {
+                Some("end") => (None, None),
+                Some(next) => (Some(next), None),
+                None => {
+                    return Err(invalid(format_args!(
+                        "expected -> in {:?}",
+                        line
+                    )))
+                }
+            },
+            _ => (None, Some(rest.to_string())),
+        };
+        rows.push(row(slot, generation, state, next, value)?);
+    }
+    Ok(rows)
+}
+
+/// Split a CSV line into its fields, undoing quoting
+fn csv_fields(line: &str) -> io::Result<Vec<String>> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        let field = fields.last_mut().unwrap();
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(invalid(format_args!("unterminated quote in {:?}", line)));
+    }
+    Ok(fields)
+}
+
+fn read_csv(reader: impl BufRead) -> io::Result<Vec<Row>> {
+    let mut rows = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if number == 0 || line.trim().is_empty() {
+            // the header
+            continue;
+        }
+
+        let fields = csv_fields(&line)?;
+        if fields.len() != 5 {
+            return Err(invalid(format_args!(
+                "expected 5 columns in {:?}",
+                line
+            )));
+        }
+        let next = Some(fields[3].as_str()).filter(|next| !next.is_empty());
+        let value = Some(fields[4].clone()).filter(|_| fields[2] == OCCUPIED);
+        rows.push(row(&fields[0], &fields[1], &fields[2], next, value)?);
+    }
+    Ok(rows)
+}
+
+/// Just enough JSON to read back an array of flat objects
+enum Json {
+    Null,
+    Number(String),
+    String(String),
+}
+
+struct JsonReader<'a> {
+    text: &'a str,
+}
+
+impl<'a> JsonReader<'a> {
+    fn skip_whitespace(&mut self) {
+        self.text = self.text.trim_start();
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        self.skip_whitespace();
+        match self.text.strip_prefix(expected) {
+            Some(rest) => {
+                self.text = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> io::Result<()> {
+        if self.eat(expected) {
+            Ok(())
+        } else {
+            Err(invalid(format_args!(
+                "expected {:?} in JSON dump",
+                expected
+            )))
+        }
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        self.expect('"')?;
+        let mut result = String::new();
+        let mut chars = self.text.char_indices();
+        while let Some((at, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.text = &self.text[at + 1..];
+                    return Ok(result);
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => result.push('\n'),
+                    Some('r') => result.push('\r'),
+                    Some('t') => result.push('\t'),
+                    Some('b') => result.push('\u{8}'),
+                    Some('f') => result.push('\u{c}'),
+                    Some('u') => {
+                        let hex: String = (0..4)
+                            .filter_map(|_| chars.next())
+                            .map(|(_, c)| c)
+                            .collect();
+                        let c = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| {
+                                invalid("bad unicode escape in JSON dump")
+                            })?;
+                        result.push(c);
+                    }
+                    Some(c) => result.push(c),
+                    None => break,
+                },
+                c => result.push(c),
+            }
+        }
+        Err(invalid("unterminated string in JSON dump"))
+    }
+
+    fn value(&mut self) -> io::Result<Json> {
+        self.skip_whitespace();
+        if self.text.starts_with('"') {
+            return Ok(Json::String(self.string()?));
+        }
+        if let Some(rest) = self.text.strip_prefix("null") {
+            self.text = rest;
+            return Ok(Json::Null);
+        }
+        let end = self
+            .text
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.text.len());
+        if end == 0 {
+            return Err(invalid(
+                "expected a string, number or null in JSON dump",
+            ));
+        }
+        let (number, rest) = self.text.split_at(end);
+        self.text = rest;
+        Ok(Json::Number(number.to_string()))
+    }
+
+    fn row(&mut self) -> io::Result<Row> {
+        let (mut slot, mut generation, mut state, mut next, mut value) =
+            (None, None, None, None, None);
+        self.expect('{')?;
+        if !self.eat('}') {
+            loop {
+                let name = self.string()?;
+                self.expect(':')?;
+                match (name.as_str(), self.value()?) {
+                    ("slot", Json::Number(n)) => slot = Some(n),
+                    ("generation", Json::Number(n)) => generation = Some(n),
+                    ("state", Json::String(s)) => state = Some(s),
+                    ("next", Json::Number(n)) => next = Some(n),
+                    ("next", Json::Null) => next = None,
+                    ("value", Json::String(s)) => value = Some(s),
+                    (name, _) => {
+                        return Err(invalid(format_args!(
+                            "unexpected field {:?} in JSON dump",
+                            name
+                        )))
+                    }
+                }
+                if !self.eat(',') {
+                    break;
+                }
+            }
+            self.expect('}')?;
+        }
+
+        let missing = |what| {
+            invalid(format_args!("slot without a {} in JSON dump", what))
+        };
+        row(
+            &slot.ok_or_else(|| missing("slot"))?,
+            &generation.ok_or_else(|| missing("generation"))?,
+            &state.ok_or_else(|| missing("state"))?,
+            next.as_deref(),
+            value,
+        )
+    }
+}
+
+fn read_json(mut reader: impl Read) -> io::Result<Vec<Row>> {
+    let mut text = String::new();
+    reader.read_to_string(&mut text)?;
+    let mut json = JsonReader { text: &text };
+
+    let mut rows = Vec::new();
+    json.expect('[')?;
+    if !json.eat(']') {
+        loop {
+            rows.push(json.row()?);
+            if !json.eat(',') {
+                break;
+            }
+        }
+        json.expect(']')?;
+    }
+    json.skip_whitespace();
+    if !json.text.is_empty() {
+        return Err(invalid("unexpected text after JSON dump"));
+    }
+    Ok(rows)
+}
+
+/// Note that the given empty slot is pointed at by another one, so it isn't
+/// the head of the free list
+fn point_at(pointed_at: &mut [bool], next: Option<usize>) {
+    if let Some(next) = next.and_then(|next| pointed_at.get_mut(next)) {
+        *next = true;
+    }
+}
+
+pub(crate) fn load<K, P, V, E>(
+    reader: impl Read,
+    format: DumpFormat,
+    mut parse: impl FnMut(&str) -> Result<V, E>,
+) -> io::Result<(ReadHandle<K, P, V>, WriteHandle<K, P, V>)>
+where
+    K: Key<P>,
+    V: ShallowCopy,
+    E: fmt::Display,
+{
+    let rows = match format {
+        DumpFormat::Json => read_json(reader)?,
+        DumpFormat::Csv => read_csv(BufReader::new(reader))?,
+        DumpFormat::Text => read_text(BufReader::new(reader))?,
+    };
+
+    let mut slots = Vec::with_capacity(rows.len());
+    let mut pointed_at = vec![false; rows.len()];
+    for (index, row) in rows.into_iter().enumerate() {
+        if row.slot != index {
+            return Err(invalid(format_args!(
+                "expected slot {} but found slot {}",
+                index, row.slot
+            )));
+        }
+        let entry = match row.entry {
+            Entry::Occupied(text) => {
+                Entry::Occupied(parse(&text).map_err(|e| {
+                    invalid(format_args!(
+                        "slot {} value {:?}: {}",
+                        index, text, e
+                    ))
+                })?)
+            }
+            Entry::Vacant(next) => {
+                point_at(&mut pointed_at, next);
+                Entry::Vacant(next)
+            }
+            Entry::Unused(next) => {
+                point_at(&mut pointed_at, next);
+                Entry::Unused(next)
+            }
+        };
+        slots.push(Slot {
+            generation: row.generation,
+            entry,
+        });
+    }
+
+    // the free list starts at the one empty slot no other one points to
+    let free_head = slots.iter().enumerate().position(|(index, slot)| {
+        matches!(slot.entry, Entry::Vacant(_) | Entry::Unused(_))
+            && !pointed_at[index]
+    });
+    let data = SlotTable::from_layout(slots, free_head).map_err(invalid)?;
+
+    let epochs = Default::default();
+    let (inner_r, inner_w) =
+        Inner::from_table(data.into_map(ManuallyDrop::new), ());
+    let r = crate::read::new(inner_r, Arc::clone(&epochs));
+    let w = crate::write::new(inner_w, epochs, r.clone());
+    Ok((r, w))
+}
@@ -0,0 +1,52 @@
+use crate::read::ReadHandle;
+use one_way_slot_map::SlotMapKey as Key;
+use std::fmt;
+use std::ops::Deref;
+
+/// The final, read-only state of a map whose writer has been frozen with
+/// [`WriteHandle::freeze`](crate::WriteHandle::freeze).
+///
+/// This derefs to a [`ReadHandle`], and hands out more of them with
+/// [`handle`](FrozenMap::handle) or [`ReadHandle::factory`]. The map is freed
+/// once this and every read handle and factory for it have been dropped.
+pub struct FrozenMap<K, P, V, M = ()>
+where
+    K: Key<P>,
+{
+    handle: ReadHandle<K, P, V, M>,
+}
+
+impl<K, P, V, M> fmt::Debug for FrozenMap<K, P, V, M>
+where
+    K: fmt::Debug + Key<P>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FrozenMap")
+            .field("handle", &self.handle)
+            .finish()
+    }
+}
+
+impl<K, P, V, M> FrozenMap<K, P, V, M>
+where
+    K: Key<P>,
+{
+    pub(crate) fn new(handle: ReadHandle<K, P, V, M>) -> Self {
+        FrozenMap { handle }
+    }
+
+    /// Produce a new [`ReadHandle`] to the frozen map.
+    pub fn handle(&self) -> ReadHandle<K, P, V, M> {
+        self.handle.clone()
+    }
+}
+
+impl<K, P, V, M> Deref for FrozenMap<K, P, V, M>
+where
+    K: Key<P>,
+{
+    type Target = ReadHandle<K, P, V, M>;
+    fn deref(&self) -> &Self::Target {
+        &self.handle
+    }
+}
@@ -0,0 +1,186 @@
+use one_way_slot_map::SlotMapKeyData;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::hash::Hash;
+use std::iter::Flatten;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::RangeBounds;
+use std::option;
+use std::sync::Arc;
+
+/// The slots holding values with the same indexed key
+pub(crate) type Bucket = HashSet<SlotMapKeyData>;
+
+/// The slots found for one indexed key, if any
+pub(crate) type Slots<'a> = Flatten<option::IntoIter<&'a Bucket>>;
+
+/// A secondary index over the values stored in one copy of the map.
+///
+/// Each copy of the map holds its own instance of every index, and both are
+/// kept up to date as operations are replayed, so an index always agrees with
+/// the data it is stored next to.
+pub(crate) trait Index<T>: Send + Sync {
+    /// Record that the given value now lives in the given slot
+    fn insert(&mut self, key: SlotMapKeyData, value: &T);
+
+    /// Record that the given value no longer lives in the given slot
+    fn remove(&mut self, key: SlotMapKeyData, value: &T);
+
+    /// Forget everything
+    fn clear(&mut self);
+
+    /// Get the slots for the given indexed key, or `None` if the given key is
+    /// not of the type this index was built with
+    fn lookup(&self, ik: &dyn Any) -> Option<Slots<'_>>;
+
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// Storage for the slots belonging to each indexed key
+pub(crate) trait IndexStorage<IK>:
+    Default + Send + Sync + 'static
+{
+    fn add_slot(&mut self, ik: IK, key: SlotMapKeyData);
+
+    fn remove_slot(&mut self, ik: &IK, key: SlotMapKeyData);
+
+    fn slots(&self, ik: &IK) -> Slots<'_>;
+
+    fn clear(&mut self);
+}
+
+macro_rules! impl_index_storage {
+    ($map:ident, $($bound:tt)+) => {
+        impl<IK> IndexStorage<IK> for $map<IK, Bucket>
+        where
+            IK: $($bound)+ + Send + Sync + 'static,
+        {
+            fn add_slot(&mut self, ik: IK, key: SlotMapKeyData) {
+                let _ = self.entry(ik).or_default().insert(key);
+            }
+
+            fn remove_slot(&mut self, ik: &IK, key: SlotMapKeyData) {
+                if let Some(slots) = self.get_mut(ik) {
+                    let _ = slots.remove(&key);
+                    if slots.is_empty() {
+                        let _ = self.remove(ik);
+                    }
+                }
+            }
+
+            fn slots(&self, ik: &IK) -> Slots<'_> {
+                self.get(ik).into_iter().flatten()
+            }
+
+            fn clear(&mut self) {
+                $map::clear(self)
+            }
+        }
+    };
+}
+
+impl_index_storage!(HashMap, Hash + Eq);
+impl_index_storage!(BTreeMap, Ord);
+
+pub(crate) type Extractor<V, IK> = Arc<dyn Fn(&V) -> IK + Send + Sync>;
+
+/// An index from a key extracted from each value to the slots holding values
+/// with that key
+pub(crate) struct ValueIndex<V, IK, S> {
+    extractor: Extractor<V, IK>,
+    storage: S,
+    _phantom_v: PhantomData<fn(&V)>,
+}
+
+pub(crate) type OrderedIndex<V, IK> = ValueIndex<V, IK, BTreeMap<IK, Bucket>>;
+
+impl<V, IK> OrderedIndex<V, IK>
+where
+    IK: Ord,
+{
+    /// Get the slots for all indexed keys in the given range, in key order
+    pub(crate) fn range<R>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = &SlotMapKeyData>
+    where
+        R: RangeBounds<IK>,
+    {
+        self.storage
+            .range(range)
+            .flat_map(|(_, slots)| slots.iter())
+    }
+}
+
+impl<V, IK, S> Index<ManuallyDrop<V>> for ValueIndex<V, IK, S>
+where
+    V: 'static,
+    IK: Send + Sync + 'static,
+    S: IndexStorage<IK>,
+{
+    fn insert(&mut self, key: SlotMapKeyData, value: &ManuallyDrop<V>) {
+        self.storage.add_slot((self.extractor)(value), key);
+    }
+
+    fn remove(&mut self, key: SlotMapKeyData, value: &ManuallyDrop<V>) {
+        self.storage.remove_slot(&(self.extractor)(value), key);
+    }
+
+    fn clear(&mut self) {
+        self.storage.clear();
+    }
+
+    fn lookup(&self, ik: &dyn Any) -> Option<Slots<'_>> {
+        Some(self.storage.slots(ik.downcast_ref::<IK>()?))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Everything needed to build one instance of an index for each copy of the
+/// map. This is what gets sent through the operation replay.
+pub(crate) struct IndexDef<V> {
+    pub(crate) name: String,
+    build: Arc<dyn Fn() -> Box<dyn Index<ManuallyDrop<V>>> + Send + Sync>,
+}
+
+impl<V> IndexDef<V>
+where
+    V: 'static,
+{
+    pub(crate) fn new<IK, S>(name: String, extractor: Extractor<V, IK>) -> Self
+    where
+        IK: Send + Sync + 'static,
+        S: IndexStorage<IK>,
+    {
+        IndexDef {
+            name,
+            build: Arc::new(move || {
+                Box::new(ValueIndex {
+                    extractor: Arc::clone(&extractor),
+                    storage: S::default(),
+                    _phantom_v: PhantomData,
+                })
+            }),
+        }
+    }
+}
+
+impl<V> IndexDef<V> {
+    /// Create an empty instance of this index
+    pub(crate) fn build(&self) -> Box<dyn Index<ManuallyDrop<V>>> {
+        (self.build)()
+    }
+}
+
+impl<V> fmt::Debug for IndexDef<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IndexDef")
+            .field("name", &self.name)
+            .finish()
+    }
+}
@@ -1,13 +1,21 @@
-use evmap::ShallowCopy;
-use one_way_slot_map::{define_key_type, SlotMap, SlotMapKey};
+use crate::index::{Index, IndexDef};
+use crate::secondary::{SecondaryOp, SecondaryStore, SecondaryValue};
+use crate::slot_table::{
+    generation_of, index_of, key_at, previous_generation, SlotTable,
+};
+use crate::ShallowCopy;
+use one_way_slot_map::{define_key_type, SlotMap, SlotMapKey, SlotMapKeyData};
+use std::borrow::Borrow;
+use std::collections::HashMap;
 use std::fmt;
-use std::mem::ManuallyDrop;
+use std::mem::{self, ManuallyDrop};
+use std::ptr;
 
-define_key_type!(pub(crate) InnerKey<()> : Copy + Clone);
+define_key_type!(InnerKey<()>);
 
-/// Recast the given data as a map from the inner key type to the original
-/// value. This is safe because SlotMap is repr(transparent) to a type that
-/// does not include K or P
+/// Recast the given data as a map from a key type without a pointer, so
+/// values can be inserted into it without one. This is safe because SlotMap
+/// is repr(transparent) to a type that does not include K or P
 fn adapt_slot_map_key_type<K, P, V>(
     data: SlotMap<K, P, V>,
 ) -> SlotMap<InnerKey, (), V>
@@ -29,71 +37,354 @@ where
     unsafe { std::mem::transmute(data) }
 }
 
-impl InnerKey {
-    pub(crate) fn to_outer_key<K, P>(self, embedded: P) -> K
-    where
-        K: SlotMapKey<P>,
-    {
-        K::from((embedded, self.slot_key))
+/// Move the values out of the given slot map into a slot table that hands
+/// out keys in the same order the slot map would have.
+///
+/// The slot map only shows its filled slots, so its empty ones are found by
+/// inserting into it, which also drops the value each removal left behind.
+/// What gets inserted are copies of a live value, and none of them are
+/// dropped. If there is no live value to copy, a copy of the map without
+/// its values is probed instead, and the values left behind by removals are
+/// leaked.
+fn take_values<K, P, V>(data: SlotMap<K, P, V>) -> SlotTable<ManuallyDrop<V>>
+where
+    K: SlotMapKey<P>,
+{
+    let mut data = adapt_slot_map_key_type(data);
+
+    // the map keeps copies of these, which are never dropped
+    let occupied: Vec<_> = data
+        .iter_raw()
+        .map(|(key, value)| {
+            (key, ManuallyDrop::new(unsafe { ptr::read(value) }))
+        })
+        .collect();
+
+    let vacant = match occupied.first() {
+        Some((_, value)) => find_vacant(&mut data, occupied.len(), || unsafe {
+            ptr::read(&**value)
+        }),
+        None => find_vacant(&mut data.map(|_| ()), 0, || ()),
+    };
+
+    // everything still in the map is either owned by `occupied`, a copy of
+    // a value in it, or was leaked above
+    drop(adapt_slot_map_value_type(data));
+
+    SlotTable::from_parts(occupied, &vacant)
+}
+
+/// Insert into the given map until it hands out a slot that has never been
+/// used, and return the keys of the empty slots it handed out before that,
+/// at the generations they were left at. `filled` is the number of values
+/// in the map.
+fn find_vacant<T>(
+    data: &mut SlotMap<InnerKey, (), T>,
+    filled: usize,
+    mut filler: impl FnMut() -> T,
+) -> Vec<SlotMapKeyData> {
+    let mut vacant = Vec::new();
+    loop {
+        let key = *data.insert((), filler()).borrow();
+
+        // new slots are added right after the existing ones, at generation
+        // 0. An empty slot that was left at the last generation is reused
+        // at generation 0 too, so in that one spot it can't be told apart
+        // from a new one. It and the empty slots after it are then left
+        // unused, which still hands them out at generation 0, and leaks the
+        // values removals left behind in them
+        if generation_of(&key) == 0 && index_of(&key) == filled + vacant.len() {
+            return vacant;
+        }
+        vacant.push(key_at(
+            index_of(&key),
+            previous_generation(generation_of(&key)),
+        ));
     }
 }
 
-pub(crate) struct Inner<V> {
-    pub(crate) data: SlotMap<InnerKey, (), V>,
+pub(crate) struct Inner<V, M> {
+    pub(crate) data: SlotTable<V>,
+    pub(crate) meta: M,
+    pub(crate) generation: u64,
+    pub(crate) indexes: HashMap<String, Box<dyn Index<V>>>,
+    pub(crate) secondaries: HashMap<u64, Box<dyn SecondaryStore>>,
     ready: bool,
 }
 
-impl<V> Inner<ManuallyDrop<V>> {
-    pub(crate) unsafe fn do_drop(&mut self) -> &mut Inner<V> {
-        &mut *(self as *mut Self as *mut Inner<V>)
+impl<V, M> Inner<ManuallyDrop<V>, ManuallyDrop<M>> {
+    /// Replace the metadata, dropping the old metadata if `second` is set
+    pub(crate) fn replace_meta(&mut self, meta: ManuallyDrop<M>, second: bool) {
+        let mut old = mem::replace(&mut self.meta, meta);
+        if second {
+            unsafe { ManuallyDrop::drop(&mut old) };
+        }
+    }
+
+    /// Put the given value in the given slot. If `second` is set, this copy
+    /// owns the value it replaces, so it is handed back. Writers check that
+    /// the key is in the map before the write starts, since a panic here
+    /// would leave the write half applied
+    pub(crate) fn replace_value(
+        &mut self,
+        key: &SlotMapKeyData,
+        value: ManuallyDrop<V>,
+        second: bool,
+    ) -> Option<V> {
+        let slot = self.data.get_mut(key).expect("Tried to replace empty key");
+        let old = mem::replace(slot, value);
+        second.then(|| ManuallyDrop::into_inner(old))
+    }
+
+    /// Remove the value in the given slot. If `second` is set, this copy owns
+    /// the removed value, so it is handed back
+    pub(crate) fn remove_value(
+        &mut self,
+        key: &SlotMapKeyData,
+        second: bool,
+    ) -> Option<V> {
+        let old = self.data.remove(key)?;
+        second.then(|| ManuallyDrop::into_inner(old))
+    }
+
+    /// Remove all values, dropping them if `second` is set
+    pub(crate) fn clear_values(&mut self, second: bool) {
+        self.data.clear_with(|mut old| {
+            if second {
+                unsafe { ManuallyDrop::drop(&mut old) };
+            }
+        });
+    }
+
+    /// Remove all values, handing each one to `dispose`. Only the second copy
+    /// owns its values, so this is only for the second application of a clear
+    pub(crate) fn drain_values(&mut self, mut dispose: impl FnMut(V)) {
+        self.data
+            .clear_with(|old| dispose(ManuallyDrop::into_inner(old)));
+    }
+
+    /// Drop every value held in this copy. This is only safe once no other
+    /// copy of the map will drop the same values
+    pub(crate) unsafe fn drop_values(&mut self) {
+        self.clear_values(true);
+        self.clear_secondaries(true);
+        ManuallyDrop::drop(&mut self.meta);
+    }
+
+    /// Add the value in the given slot to every index
+    pub(crate) fn index_slot(&mut self, key: &SlotMapKeyData) {
+        if self.indexes.is_empty() {
+            return;
+        }
+
+        if let Some(value) = self.data.get(key) {
+            for index in self.indexes.values_mut() {
+                index.insert(*key, value);
+            }
+        }
+    }
+
+    /// Remove the value in the given slot from every index
+    pub(crate) fn unindex_slot(&mut self, key: &SlotMapKeyData) {
+        if self.indexes.is_empty() {
+            return;
+        }
+
+        if let Some(value) = self.data.get(key) {
+            for index in self.indexes.values_mut() {
+                index.remove(*key, value);
+            }
+        }
+    }
+
+    /// Remove everything from every index
+    pub(crate) fn clear_indexes(&mut self) {
+        self.indexes.values_mut().for_each(|index| index.clear());
+    }
+
+    /// Apply the given change to a secondary map for the first time, so values
+    /// are only shallow copied in and nothing is dropped
+    pub(crate) fn apply_secondary_first(&mut self, op: &SecondaryOp) {
+        match op {
+            SecondaryOp::Create(id, create) => {
+                let _ = self.secondaries.insert(*id, create());
+            }
+            SecondaryOp::Update(key, value) => {
+                self.secondary_update_first(key, value);
+            }
+            SecondaryOp::Remove(id, key) => {
+                self.secondary_mut(*id).remove(key, false);
+            }
+        }
+    }
+
+    /// Apply the given change to a secondary map for the second time, which
+    /// takes ownership of new values and drops old ones
+    pub(crate) fn apply_secondary_second(&mut self, op: SecondaryOp) {
+        match op {
+            SecondaryOp::Create(id, create) => {
+                let _ = self.secondaries.insert(id, create());
+            }
+            SecondaryOp::Update(key, value) => {
+                self.secondary_update_second(key, value);
+            }
+            SecondaryOp::Remove(id, key) => {
+                self.secondary_mut(id).remove(&key, true);
+            }
+        }
+    }
+
+    /// Apply the given update to its secondary map without taking ownership of
+    /// anything, as long as the key is present in the primary map
+    pub(crate) fn secondary_update_first(
+        &mut self,
+        key: &SlotMapKeyData,
+        value: &SecondaryValue,
+    ) {
+        if self.data.get(key).is_some() {
+            self.secondary_mut(value.id).update_first(*key, value);
+        }
+    }
+
+    /// Apply the given update to its secondary map, taking ownership of its
+    /// values, as long as the key is present in the primary map
+    pub(crate) fn secondary_update_second(
+        &mut self,
+        key: SlotMapKeyData,
+        value: SecondaryValue,
+    ) {
+        if self.data.get(&key).is_some() {
+            self.secondary_mut(value.id).update_second(key, value);
+        }
+    }
+
+    fn secondary_mut(&mut self, id: u64) -> &mut dyn SecondaryStore {
+        self.secondaries
+            .get_mut(&id)
+            .expect("Secondary map used with a different primary map")
+            .as_mut()
+    }
+
+    /// Remove the values for the given key from every secondary map
+    pub(crate) fn remove_from_secondaries(
+        &mut self,
+        key: &SlotMapKeyData,
+        second: bool,
+    ) {
+        for store in self.secondaries.values_mut() {
+            store.remove(key, second);
+        }
+    }
+
+    /// Remove all values from every secondary map
+    pub(crate) fn clear_secondaries(&mut self, second: bool) {
+        for store in self.secondaries.values_mut() {
+            store.clear(second);
+        }
+    }
+
+    /// Build the given index from the data in this copy, replacing any
+    /// existing index with the same name
+    pub(crate) fn add_index(&mut self, def: &IndexDef<V>) {
+        let mut index = def.build();
+        for (key, value) in self.data.iter_raw() {
+            index.insert(key, value);
+        }
+        let _ = self.indexes.insert(def.name.clone(), index);
     }
 }
 
-impl<V> fmt::Debug for Inner<V>
+impl<V, M> fmt::Debug for Inner<V, M>
 where
     V: fmt::Debug,
+    M: fmt::Debug,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Inner")
             .field("data", &self.data)
+            .field("meta", &self.meta)
+            .field("generation", &self.generation)
+            .field("indexes", &self.indexes.keys())
+            .field("secondaries", &self.secondaries.keys())
             .field("ready", &self.ready)
             .finish()
     }
 }
 
-impl<V> Inner<ManuallyDrop<V>>
+impl<V, M> Inner<ManuallyDrop<V>, ManuallyDrop<M>>
 where
     V: ShallowCopy,
+    M: ShallowCopy,
 {
-    pub(crate) fn new() -> Self {
-        Inner {
-            data: SlotMap::new(),
-            ready: false,
-        }
+    /// Build both copies of a map from the given data and metadata. The
+    /// first copy only holds shallow copies, and the second owns everything.
+    fn pair(
+        data: SlotTable<ManuallyDrop<V>>,
+        meta: M,
+        ready: bool,
+    ) -> (Self, Self) {
+        let copy = |data, meta| Inner {
+            data,
+            meta,
+            generation: 0,
+            indexes: HashMap::new(),
+            secondaries: HashMap::new(),
+            ready,
+        };
+
+        (
+            copy(data.map(|v| unsafe { (**v).shallow_copy() }), unsafe {
+                meta.shallow_copy()
+            }),
+            copy(data, ManuallyDrop::new(meta)),
+        )
     }
 
-    pub(crate) fn new_with_data<K, P>(data: SlotMap<K, P, V>) -> (Self, Self)
+    /// Build both copies of a map that holds exactly the given slots
+    pub(crate) fn from_table(
+        data: SlotTable<ManuallyDrop<V>>,
+        meta: M,
+    ) -> (Self, Self) {
+        Inner::pair(data, meta, true)
+    }
+
+    pub(crate) fn with_capacity(capacity: usize, meta: M) -> (Self, Self) {
+        Inner::pair(SlotTable::with_capacity(capacity), meta, false)
+    }
+
+    pub(crate) fn new_with_data<K, P>(
+        data: SlotMap<K, P, V>,
+        meta: M,
+    ) -> (Self, Self)
     where
         K: SlotMapKey<P>,
     {
-        let adapted_data = adapt_slot_map_key_type(data);
-        let data1 = adapted_data.map(|v| unsafe { v.shallow_copy() });
-        let data2 = adapt_slot_map_value_type(adapted_data);
+        Inner::pair(take_values(data), meta, true)
+    }
 
-        (
-            Inner {
-                data: data1,
-                ready: true,
-            },
-            Inner {
-                data: data2,
-                ready: true,
-            },
-        )
+    /// Like `new_with_data`, but each value is moved through `wrap` first, so
+    /// the given values don't have to be shallow copyable themselves
+    pub(crate) fn new_with_wrapped_data<K, P, U>(
+        data: SlotMap<K, P, U>,
+        mut wrap: impl FnMut(U) -> V,
+        meta: M,
+    ) -> (Self, Self)
+    where
+        K: SlotMapKey<P>,
+    {
+        let data = take_values(data).into_map(|value| {
+            ManuallyDrop::new(wrap(ManuallyDrop::into_inner(value)))
+        });
+        Inner::pair(data, meta, true)
     }
 }
 
-impl<V> Inner<V> {
+impl<V, M> Inner<V, M> {
+    /// Returns the bytes used by this copy and its slot table
+    pub(crate) fn bytes(&self) -> usize {
+        mem::size_of::<Self>() + self.data.heap_bytes()
+    }
+
     pub(crate) fn mark_ready(&mut self) {
         self.ready = true;
     }
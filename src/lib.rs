@@ -6,12 +6,14 @@
 //! but with a few notable simplifications
 //!
 //! - The value-bag map is replaced with a [one-way slotmap](https://docs.rs/one_way_slot_map/0.2.0/one_way_slot_map/)
+//!   (value bags are still available per key through [`EvSlotMultiMap`])
 //! - No batched edits (required because slot map keys need to be returned on insert)
-//! - No associated metadata
+//! - Associated metadata is set on the writer and published along with the
+//!   next write
 //!
-//! The core synchronization component's of evmap are still present. Out of
-//! simplicity, we also use the [ShallowCopy](https://docs.rs/evmap/10.0.2/evmap/shallow_copy/trait.ShallowCopy.html)
-//! straight out of evmap instead of copy-pasting it in. Also the following
+//! The core synchronization component's of evmap are still present, including
+//! its [`ShallowCopy`] trait, which this crate carries its own copy of along
+//! with a `#[derive(ShallowCopy)]` for structs and enums. Also the following
 //! blurb is almost straight from evmap.
 //!
 //! This map implementation allows reads and writes to execute entirely in parallel, with no
@@ -29,21 +31,38 @@
     missing_docs,
     rust_2018_idioms,
     missing_debug_implementations,
-    intra_doc_link_resolution_failure
+    rustdoc::broken_intra_doc_links
 )]
 #![allow(clippy::type_complexity)]
 
 use one_way_slot_map::{SlotMap, SlotMapKey as Key, SlotMapKeyData};
-use std::sync::{atomic, Arc, Mutex};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::mem::ManuallyDrop;
+use std::sync::{atomic, Arc, Mutex, MutexGuard, PoisonError};
+mod index;
 mod inner;
+mod slot_table;
+use crate::index::IndexDef;
 use crate::inner::Inner;
-use evmap::ShallowCopy;
+use crate::secondary::{SecondaryOp, SecondaryValue};
+use crate::slot_table::SlotTable;
+pub use crate::slot_table::{InsertAtError, KeyStatus};
 use slab::Slab;
 pub(crate) type Epochs = Arc<Mutex<Slab<Arc<atomic::AtomicUsize>>>>;
 
+/// Lock the given epochs. A writer that panics part way through a write
+/// poisons this lock, but it never changes the epochs themselves, so the
+/// poison says nothing about them and is ignored.
+pub(crate) fn lock_epochs(
+    epochs: &Epochs,
+) -> MutexGuard<'_, Slab<Arc<atomic::AtomicUsize>>> {
+    epochs.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 /// A pending map operation.
 #[non_exhaustive]
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Debug)]
 pub(crate) enum Operation<V> {
     /// Just do a refresh without altering the data
     NoOp,
@@ -51,10 +70,23 @@ pub(crate) enum Operation<V> {
     Replace(SlotMapKeyData, V),
     /// Add this value to the map.
     Add(V),
+    /// Add this value to the map under this key.
+    AddAt(SlotMapKeyData, V),
     /// Remove the value with this key from the map.
     Remove(SlotMapKeyData),
     /// Clear the map.
     Clear,
+    /// Build a secondary index over the values in the map.
+    AddIndex(IndexDef<V>),
+    /// Change a secondary map that shares keys with this map.
+    Secondary(SecondaryOp),
+    /// Add this value to the map, and the given value to a secondary map
+    /// under the new key.
+    AddJoined(V, SecondaryValue),
+    /// Make room for this many more values.
+    Reserve(usize),
+    /// Release unused memory.
+    ShrinkToFit,
 }
 
 mod write;
@@ -63,23 +95,320 @@ pub use crate::write::WriteHandle;
 mod read;
 pub use crate::read::{MapReadRef, ReadGuard, ReadHandle, ReadHandleFactory};
 
+mod shallow_copy;
+#[cfg(feature = "evmap")]
+pub use crate::shallow_copy::EvmapValue;
+pub use crate::shallow_copy::ShallowCopy;
+pub use ev_slotmap_derive::ShallowCopy;
+
+mod secondary;
+pub use crate::secondary::{EvSecondaryMap, ForeignSecondaryMap};
+
+mod multi;
+pub use crate::multi::{EvSlotMultiMap, MultiReadHandle};
+
+mod cloned;
+pub use crate::cloned::{ClonedReadHandle, ClonedWriteHandle};
+
+mod rcu;
+pub use crate::rcu::{RcuReadHandle, RcuWriteHandle};
+
+#[cfg(feature = "serde")]
+mod serialize;
+#[cfg(feature = "serde")]
+pub use crate::serialize::{serde_key, SerializedMap};
+
+mod dump;
+pub use crate::dump::DumpFormat;
+
+mod snapshot;
+pub use crate::snapshot::{Decode, Encode, SnapshotError};
+
+mod wal;
+pub use crate::wal::{CheckpointPolicy, SyncPolicy, WriteAheadLog};
+
+mod replicate;
+pub use crate::replicate::Follower;
+
+mod frozen;
+pub use crate::frozen::FrozenMap;
+
+mod reclaim;
+pub use crate::reclaim::DropPolicy;
+
+mod stats;
+pub use crate::stats::{HeapSize, MemoryStats};
+
+mod changes;
+pub use crate::changes::{ChangeSet, TooOld};
+
+mod signal;
+pub use crate::signal::WaitForGeneration;
+
+mod subscribe;
+pub use crate::subscribe::{
+    ChangeEvent, RecvError, Subscription, TryRecvError,
+};
+
 /// Create an empty ev slotmap.
 pub fn new<K, P, V>() -> (ReadHandle<K, P, V>, WriteHandle<K, P, V>)
 where
     K: Key<P>,
     V: ShallowCopy,
+{
+    with_capacity(0)
+}
+
+/// Create an empty ev slotmap with room for at least `capacity` values in
+/// each copy before either has to reallocate.
+pub fn with_capacity<K, P, V>(
+    capacity: usize,
+) -> (ReadHandle<K, P, V>, WriteHandle<K, P, V>)
+where
+    K: Key<P>,
+    V: ShallowCopy,
+{
+    construct(capacity, ())
+}
+
+/// Create an empty ev slotmap that also publishes the given metadata.
+///
+/// The metadata is a small value readers see from the same version as the
+/// data, through [`MapReadRef::meta`] or [`ReadHandle::meta`]. It is changed
+/// with [`WriteHandle::set_meta`].
+pub fn with_meta<K, P, V, M>(
+    meta: M,
+) -> (ReadHandle<K, P, V, M>, WriteHandle<K, P, V, M>)
+where
+    K: Key<P>,
+    V: ShallowCopy,
+    M: ShallowCopy,
+{
+    construct(0, meta)
+}
+
+fn construct<K, P, V, M>(
+    capacity: usize,
+    meta: M,
+) -> (ReadHandle<K, P, V, M>, WriteHandle<K, P, V, M>)
+where
+    K: Key<P>,
+    V: ShallowCopy,
+    M: ShallowCopy,
 {
     let epochs = Default::default();
-    let inner = Inner::new();
+    let (inner, mut w_handle) = Inner::with_capacity(capacity, meta);
 
-    let mut w_handle = Inner::new();
     w_handle.mark_ready();
     let r = read::new(inner, Arc::clone(&epochs));
     let w = write::new(w_handle, epochs, r.clone());
     (r, w)
 }
 
+/// Create an empty multi-value ev slotmap, where every key owns a bag of
+/// values instead of a single one.
+pub fn new_multi<K, P, V>(
+) -> (MultiReadHandle<K, P, V>, EvSlotMultiMap<K, P, V>)
+where
+    K: Key<P>,
+    V: ShallowCopy + Send + Sync + 'static,
+{
+    multi::new()
+}
+
+/// Create an empty ev slotmap for values that can't be shallow copied.
+///
+/// Every value is kept in a single heap allocation shared by both copies of
+/// the map, which costs an extra indirection on reads but works for any value
+/// type. The write handle frees each value once neither copy can see it.
+pub fn new_cloned<K, P, V>(
+) -> (ClonedReadHandle<K, P, V>, ClonedWriteHandle<K, P, V>)
+where
+    K: Key<P>,
+{
+    cloned::new()
+}
+
+/// Create an empty ev slotmap in RCU style, for values too large to keep
+/// twice.
+///
+/// Both copies of the map point at a single heap allocation per value, so
+/// the slot tables only hold pointers. A replaced or removed value is freed
+/// once every reader that could still see it has finished its read, using the
+/// same reader epochs that keep the two copies apart.
+pub fn new_rcu<K, P, V>() -> (RcuReadHandle<K, P, V>, RcuWriteHandle<K, P, V>)
+where
+    K: Key<P>,
+{
+    rcu::new()
+}
+
+/// Create an RCU style ev slotmap with the given data. The values are moved
+/// into the map, never copied. Keys are carried over as described on
+/// [`new_with_data`].
+pub fn new_rcu_with_data<K, P, V>(
+    data: SlotMap<K, P, V>,
+) -> (RcuReadHandle<K, P, V>, RcuWriteHandle<K, P, V>)
+where
+    K: Key<P>,
+{
+    let epochs = Default::default();
+    let (inner_r, inner_w) =
+        Inner::new_with_wrapped_data(data, rcu::Shared::new, ());
+
+    let r = read::new(inner_r, Arc::clone(&epochs));
+    let w = write::new(inner_w, epochs, r.clone());
+    rcu::from_handles((r, w))
+}
+
+/// Create an ev slotmap from a deserialized [`MapReadRef`]. Every key the
+/// serialized map handed out resolves to the same value in the new one, and
+/// new keys are handed out in the same order.
+#[cfg(feature = "serde")]
+pub fn new_from_serialized<K, P, V, M>(
+    map: SerializedMap<V, M>,
+) -> (ReadHandle<K, P, V, M>, WriteHandle<K, P, V, M>)
+where
+    K: Key<P>,
+    V: ShallowCopy,
+    M: ShallowCopy,
+{
+    serialize::new(map)
+}
+
+/// Load a map from a snapshot written by [`ReadHandle::write_snapshot`].
+///
+/// The snapshot's header and checksum are verified, and its slot table is
+/// loaded directly into both copies of the new map, so every key the
+/// snapshotted map handed out resolves to the same value here.
+pub fn load_snapshot<K, P, V, M>(
+    reader: impl std::io::Read,
+) -> Result<(ReadHandle<K, P, V, M>, WriteHandle<K, P, V, M>), SnapshotError>
+where
+    K: Key<P>,
+    V: Decode + ShallowCopy,
+    M: Decode + ShallowCopy,
+{
+    snapshot::load(reader)
+}
+
+/// Rebuild a map from a snapshot written by [`ReadHandle::write_snapshot`]
+/// and the [`WriteAheadLog`] of the writes made after it.
+///
+/// Every logged write is replayed in order, and each one is checked to hand
+/// out or touch the same key it did originally, so the recovered map's keys
+/// match the ones given out before the crash. Writes the snapshot already
+/// holds are skipped by their generation, as is a record cut off at the end
+/// of the log, as a crash part way through a write leaves behind.
+pub fn recover<K, P, V, M>(
+    snapshot: impl std::io::Read,
+    log: impl std::io::Read,
+) -> Result<(ReadHandle<K, P, V, M>, WriteHandle<K, P, V, M>), SnapshotError>
+where
+    K: Key<P>,
+    V: Decode + ShallowCopy,
+    M: Decode + ShallowCopy,
+{
+    wal::recover(snapshot, log)
+}
+
+/// Load a map from a dump written by [`MapReadRef::dump`], or written by hand
+/// in the same format, so tests can start from a known map state.
+///
+/// Every slot has to be listed, in order, and each filled slot's value is
+/// parsed from its text with the given function. The empty slots are
+/// refilled in the order the dump lists them in, so the loaded map hands out
+/// the same keys the dumped one would have.
+pub fn load_dump<K, P, V, E>(
+    reader: impl std::io::Read,
+    format: DumpFormat,
+    parse: impl FnMut(&str) -> Result<V, E>,
+) -> std::io::Result<(ReadHandle<K, P, V>, WriteHandle<K, P, V>)>
+where
+    K: Key<P>,
+    V: ShallowCopy,
+    E: std::fmt::Display,
+{
+    dump::load(reader, format, parse)
+}
+
+/// Create an ev slotmap holding the given values, and return the key for
+/// each of them in the same order.
+///
+/// The pointer value for each key is created from its value, just like with
+/// [`MapReadRef::iter`]. The values are given the same keys that inserting
+/// them one at a time into an empty map would hand out, but both copies of
+/// the map are built directly, with nothing published along the way.
+pub fn from_iter_with_keys<K, P, V>(
+    values: impl IntoIterator<Item = V>,
+    mut pointer_finder: impl FnMut(&V) -> P,
+) -> (ReadHandle<K, P, V>, WriteHandle<K, P, V>, Vec<K>)
+where
+    K: Key<P>,
+    V: ShallowCopy,
+{
+    let values = values.into_iter();
+    let mut data = SlotTable::with_capacity(values.size_hint().0);
+    let keys = values
+        .map(|value| {
+            let p = pointer_finder(&value);
+            K::from((p, data.insert(ManuallyDrop::new(value))))
+        })
+        .collect();
+
+    let (r, w) = from_table(data);
+    (r, w, keys)
+}
+
+/// Create an ev slotmap holding the values of the given hash map, and return
+/// the key each of its ids now maps to.
+///
+/// This works like [`from_iter_with_keys`], with the values taking slots in
+/// the order the hash map iterates over them.
+pub fn from_hash_map<K, P, V, Id, S>(
+    map: HashMap<Id, V, S>,
+    mut pointer_finder: impl FnMut(&V) -> P,
+) -> (ReadHandle<K, P, V>, WriteHandle<K, P, V>, HashMap<Id, K>)
+where
+    K: Key<P>,
+    V: ShallowCopy,
+    Id: Eq + Hash,
+{
+    let mut data = SlotTable::with_capacity(map.len());
+    let keys = map
+        .into_iter()
+        .map(|(id, value)| {
+            let p = pointer_finder(&value);
+            (id, K::from((p, data.insert(ManuallyDrop::new(value)))))
+        })
+        .collect();
+
+    let (r, w) = from_table(data);
+    (r, w, keys)
+}
+
+fn from_table<K, P, V>(
+    data: SlotTable<ManuallyDrop<V>>,
+) -> (ReadHandle<K, P, V>, WriteHandle<K, P, V>)
+where
+    K: Key<P>,
+    V: ShallowCopy,
+{
+    let epochs = Default::default();
+    let (inner_r, inner_w) = Inner::from_table(data, ());
+
+    let r = read::new(inner_r, Arc::clone(&epochs));
+    let w = write::new(inner_w, epochs, r.clone());
+    (r, w)
+}
+
 /// Create a new evmap with the given data
+///
+/// Every key that is live in the given map stays live with the same value,
+/// and new values fill the empty slots in the order the given map would have
+/// filled them. Values the given map was still holding on to for removed
+/// keys are dropped, unless it holds no live values at all, in which case
+/// they are leaked.
 pub fn new_with_data<K, P, V>(
     data: SlotMap<K, P, V>,
 ) -> (ReadHandle<K, P, V>, WriteHandle<K, P, V>)
@@ -88,7 +417,7 @@ where
     V: ShallowCopy,
 {
     let epochs = Default::default();
-    let (inner_r, inner_w) = Inner::new_with_data(data);
+    let (inner_r, inner_w) = Inner::new_with_data(data, ());
 
     let r = read::new(inner_r, Arc::clone(&epochs));
     let w = write::new(inner_w, epochs, r.clone());
@@ -0,0 +1,333 @@
+use crate::read::{ReadGuard, ReadHandle};
+use crate::secondary::{
+    next_secondary_id, SecondaryOp, SecondaryStore, SecondaryValue,
+};
+use crate::write::WriteHandle;
+use crate::Operation;
+use crate::ShallowCopy;
+use one_way_slot_map::{SlotMapKey as Key, SlotMapKeyData};
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+
+/// A change to the bag in one slot
+enum BagEdit<V> {
+    Push(V),
+    RemoveAt(usize),
+    Clear,
+}
+
+/// The bags of values for every slot, as stored in one copy of the map.
+///
+/// Each copy has its own vector for each bag, and only the values in them are
+/// shallow copies of each other. This means a bag can be edited in place in
+/// one copy while readers are still using the other.
+struct BagData<V> {
+    bags: HashMap<SlotMapKeyData, Vec<ManuallyDrop<V>>>,
+}
+
+impl<V> BagData<V>
+where
+    V: ShallowCopy + Send + Sync + 'static,
+{
+    fn create() -> Box<dyn SecondaryStore> {
+        Box::new(BagData::<V> {
+            bags: HashMap::new(),
+        })
+    }
+
+    fn drop_all(bag: Vec<ManuallyDrop<V>>) {
+        for mut value in bag {
+            unsafe { ManuallyDrop::drop(&mut value) };
+        }
+    }
+}
+
+impl<V> SecondaryStore for BagData<V>
+where
+    V: ShallowCopy + Send + Sync + 'static,
+{
+    fn update_first(&mut self, key: SlotMapKeyData, update: &SecondaryValue) {
+        let edit = update
+            .value
+            .downcast_ref::<BagEdit<V>>()
+            .expect("Bag edit of the wrong type");
+
+        match edit {
+            BagEdit::Push(value) => self
+                .bags
+                .entry(key)
+                .or_default()
+                .push(unsafe { value.shallow_copy() }),
+            BagEdit::RemoveAt(i) => {
+                if let Some(bag) = self.bags.get_mut(&key) {
+                    let _ = bag.remove(*i);
+                }
+            }
+            BagEdit::Clear => {
+                let _ = self.bags.remove(&key);
+            }
+        }
+    }
+
+    fn update_second(&mut self, key: SlotMapKeyData, update: SecondaryValue) {
+        let edit = *update
+            .value
+            .downcast::<BagEdit<V>>()
+            .expect("Bag edit of the wrong type");
+
+        match edit {
+            BagEdit::Push(value) => self
+                .bags
+                .entry(key)
+                .or_default()
+                .push(ManuallyDrop::new(value)),
+            BagEdit::RemoveAt(i) => {
+                if let Some(bag) = self.bags.get_mut(&key) {
+                    let mut old = bag.remove(i);
+                    unsafe { ManuallyDrop::drop(&mut old) };
+                }
+            }
+            BagEdit::Clear => self.remove(&key, true),
+        }
+    }
+
+    fn remove(&mut self, key: &SlotMapKeyData, second: bool) {
+        if let Some(bag) = self.bags.remove(key) {
+            if second {
+                Self::drop_all(bag);
+            }
+        }
+    }
+
+    fn clear(&mut self, second: bool) {
+        for (_, bag) in self.bags.drain() {
+            if second {
+                Self::drop_all(bag);
+            }
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub(crate) fn new<K, P, V>(
+) -> (MultiReadHandle<K, P, V>, EvSlotMultiMap<K, P, V>)
+where
+    K: Key<P>,
+    V: ShallowCopy + Send + Sync + 'static,
+{
+    let (r, mut w) = crate::new();
+    let id = next_secondary_id();
+
+    let _ = w.refresh_with_operation(Operation::Secondary(
+        SecondaryOp::Create(id, BagData::<V>::create),
+    ));
+
+    let r = MultiReadHandle {
+        handle: r,
+        id,
+        _phantom: PhantomData,
+    };
+    let w = EvSlotMultiMap {
+        reader: r.clone(),
+        handle: w,
+    };
+    (r, w)
+}
+
+/// A handle that may be used to read from a multi-value slot map.
+pub struct MultiReadHandle<K, P, V>
+where
+    K: Key<P>,
+{
+    handle: ReadHandle<K, P, ()>,
+    id: u64,
+    _phantom: PhantomData<fn() -> V>,
+}
+
+impl<K, P, V> fmt::Debug for MultiReadHandle<K, P, V>
+where
+    K: fmt::Debug + Key<P>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MultiReadHandle")
+            .field("handle", &self.handle)
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl<K, P, V> Clone for MultiReadHandle<K, P, V>
+where
+    K: Key<P>,
+{
+    fn clone(&self) -> Self {
+        MultiReadHandle {
+            handle: self.handle.clone(),
+            id: self.id,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<K, P, V> MultiReadHandle<K, P, V>
+where
+    K: Key<P>,
+    V: 'static,
+{
+    /// Returns a guarded reference to the values in the bag for the given key.
+    /// A key with an empty bag gives an empty slice.
+    ///
+    /// While the guard lives, the map cannot be refreshed.
+    ///
+    /// If no writes have happened, the key is not present, or the write
+    /// handle has been dropped, then None is returned here
+    pub fn get<'rh>(&'rh self, key: &K) -> Option<ReadGuard<'rh, [V]>> {
+        let inner = self.handle.read_inner()?;
+        inner.map_opt(|inner| {
+            let key = key.borrow();
+            inner.data.get(key)?;
+
+            let bags = &inner
+                .secondaries
+                .get(&self.id)?
+                .as_any()
+                .downcast_ref::<BagData<V>>()?
+                .bags;
+
+            let bag = bags.get(key).map_or(&[][..], Vec::as_slice);
+
+            // ManuallyDrop is repr(transparent) to the wrapped type
+            Some(unsafe { &*(bag as *const [ManuallyDrop<V>] as *const [V]) })
+        })
+    }
+
+    /// Returns true if the map contains the given key, even if its bag is
+    /// empty.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.handle
+            .read()
+            .map(|r| r.contains_key(key))
+            .unwrap_or(false)
+    }
+
+    /// Returns the number of keys present in the map.
+    pub fn len(&self) -> usize {
+        self.handle.len()
+    }
+
+    /// Returns true if the map contains no keys.
+    pub fn is_empty(&self) -> bool {
+        self.handle.is_empty()
+    }
+
+    /// Returns true if the writer has destroyed this map.
+    pub fn is_destroyed(&self) -> bool {
+        self.handle.is_destroyed()
+    }
+}
+
+/// A handle that may be used to modify a multi-value slot map.
+///
+/// Every key owns a bag of values, much like the value bags in evmap. Edits
+/// to a bag go through the same two-copy replay as writes to a regular map,
+/// so each one is visible to readers as soon as the call returns. Like
+/// [`WriteHandle`], this derefs to a read handle for the same map.
+pub struct EvSlotMultiMap<K, P, V>
+where
+    K: Key<P>,
+{
+    handle: WriteHandle<K, P, ()>,
+    reader: MultiReadHandle<K, P, V>,
+}
+
+impl<K, P, V> fmt::Debug for EvSlotMultiMap<K, P, V>
+where
+    K: fmt::Debug + Key<P>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EvSlotMultiMap")
+            .field("handle", &self.handle)
+            .field("reader", &self.reader)
+            .finish()
+    }
+}
+
+impl<K, P, V> EvSlotMultiMap<K, P, V>
+where
+    K: Key<P>,
+    V: ShallowCopy + Send + Sync + 'static,
+{
+    fn edit(&mut self, key: &K, edit: BagEdit<V>) {
+        let _ = self.handle.refresh_with_operation(Operation::Secondary(
+            SecondaryOp::Update(
+                *key.borrow(),
+                SecondaryValue::new(self.reader.id, edit),
+            ),
+        ));
+    }
+
+    /// Add a new key with an empty bag and return it
+    pub fn insert(&mut self, p: P) -> K {
+        self.handle.insert(p, ())
+    }
+
+    /// Add the given value to the bag for the given key. If the key is not
+    /// present, nothing happens.
+    pub fn push(&mut self, key: &K, value: V) {
+        self.edit(key, BagEdit::Push(value));
+    }
+
+    /// Remove one value equal to the given one from the bag for the given
+    /// key. Returns true if a value was removed.
+    pub fn remove_value(&mut self, key: &K, value: &V) -> bool
+    where
+        V: PartialEq,
+    {
+        // both copies hold the bag in the same order, so the position in the
+        // published copy is good for both
+        let position = self
+            .reader
+            .get(key)
+            .and_then(|bag| bag.iter().position(|v| v == value));
+
+        match position {
+            Some(i) => {
+                self.edit(key, BagEdit::RemoveAt(i));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove every value from the bag for the given key, keeping the key
+    pub fn clear_key(&mut self, key: &K) {
+        self.edit(key, BagEdit::Clear);
+    }
+
+    /// Remove the given key and its whole bag from the map
+    pub fn remove(&mut self, key: &K) {
+        self.handle.remove(key);
+    }
+
+    /// Remove every key from the map
+    pub fn clear(&mut self) {
+        self.handle.clear();
+    }
+}
+
+impl<K, P, V> Deref for EvSlotMultiMap<K, P, V>
+where
+    K: Key<P>,
+{
+    type Target = MultiReadHandle<K, P, V>;
+    fn deref(&self) -> &Self::Target {
+        &self.reader
+    }
+}
@@ -0,0 +1,347 @@
+use crate::read::{ReadGuard, ReadHandle};
+use crate::write::WriteHandle;
+use crate::{lock_epochs, Epochs, ShallowCopy};
+use one_way_slot_map::SlotMapKey as Key;
+use std::fmt;
+use std::mem::{self, ManuallyDrop};
+use std::ops::Deref;
+use std::ptr::NonNull;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+
+pub(crate) fn new<K, P, V>() -> (RcuReadHandle<K, P, V>, RcuWriteHandle<K, P, V>)
+where
+    K: Key<P>,
+{
+    from_handles(crate::new())
+}
+
+pub(crate) fn from_handles<K, P, V>(
+    (r, mut w): (ReadHandle<K, P, Shared<V>>, WriteHandle<K, P, Shared<V>>),
+) -> (RcuReadHandle<K, P, V>, RcuWriteHandle<K, P, V>)
+where
+    K: Key<P>,
+{
+    // this handle frees the values itself once the map is gone, which it
+    // couldn't do if the map outlived a panic
+    w.destroy_on_panic();
+
+    let r = RcuReadHandle { handle: r };
+    let w = RcuWriteHandle {
+        reader: r.clone(),
+        handle: Some(w),
+        retired: Vec::new(),
+    };
+    (r, w)
+}
+
+/// A pointer to a value that both copies of the map share. Neither copy owns
+/// the value, so dropping either copy's pointer does nothing; the write handle
+/// frees it once every reader that could still see it has moved on.
+pub(crate) struct Shared<V>(NonNull<V>);
+
+unsafe impl<V: Send + Sync> Send for Shared<V> {}
+unsafe impl<V: Send + Sync> Sync for Shared<V> {}
+
+impl<V> Shared<V> {
+    pub(crate) fn new(value: V) -> Self {
+        Shared(NonNull::from(Box::leak(Box::new(value))))
+    }
+
+    /// Free the value. Nothing may use any copy of this pointer afterwards.
+    unsafe fn free(self) {
+        drop(Box::from_raw(self.0.as_ptr()));
+    }
+}
+
+impl<V> ShallowCopy for Shared<V> {
+    unsafe fn shallow_copy(&self) -> ManuallyDrop<Self> {
+        ManuallyDrop::new(Shared(self.0))
+    }
+}
+
+impl<V> fmt::Debug for Shared<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the write copy may still point at values that have been freed, so
+        // only the pointer is safe to show here
+        f.debug_tuple("Shared").field(&self.0).finish()
+    }
+}
+
+/// The readers that were in the middle of a read when some values were
+/// retired, along with the epochs they were reading under.
+struct GracePeriod {
+    active: Vec<(Arc<AtomicUsize>, usize)>,
+}
+
+impl GracePeriod {
+    /// Start a grace period for values that have just been unpublished
+    fn start(epochs: &Epochs) -> Self {
+        let high_bit = 1usize << (mem::size_of::<usize>() * 8 - 1);
+
+        // ensure that the epoch reads aren't re-ordered to before the swap
+        // that unpublished the values
+        atomic::fence(atomic::Ordering::SeqCst);
+
+        let active = lock_epochs(epochs)
+            .iter()
+            .filter_map(|(_, epoch)| {
+                let now = epoch.load(atomic::Ordering::Acquire);
+                if now & high_bit == 0 && now != 0 {
+                    Some((Arc::clone(epoch), now))
+                } else {
+                    // readers that aren't active can only see the new pointer
+                    // once they start reading again
+                    None
+                }
+            })
+            .collect();
+
+        GracePeriod { active }
+    }
+
+    /// Returns true once every reader that was active at the start of the
+    /// grace period has finished that read.
+    fn has_passed(&self) -> bool {
+        let high_bit = 1usize << (mem::size_of::<usize>() * 8 - 1);
+        self.active.iter().all(|(epoch, then)| {
+            let now = epoch.load(atomic::Ordering::Acquire);
+            now != *then || now & high_bit != 0
+        })
+    }
+}
+
+/// A handle that may be used to read from a slot map created with
+/// [`new_rcu`](crate::new_rcu).
+pub struct RcuReadHandle<K, P, V>
+where
+    K: Key<P>,
+{
+    handle: ReadHandle<K, P, Shared<V>>,
+}
+
+impl<K, P, V> fmt::Debug for RcuReadHandle<K, P, V>
+where
+    K: fmt::Debug + Key<P>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RcuReadHandle")
+            .field("handle", &self.handle)
+            .finish()
+    }
+}
+
+impl<K, P, V> Clone for RcuReadHandle<K, P, V>
+where
+    K: Key<P>,
+{
+    fn clone(&self) -> Self {
+        RcuReadHandle {
+            handle: self.handle.clone(),
+        }
+    }
+}
+
+impl<K, P, V> RcuReadHandle<K, P, V>
+where
+    K: Key<P>,
+{
+    /// Returns a guarded reference to the value corresponding to the key.
+    ///
+    /// While the guard lives, the value it points at will not be freed, even
+    /// if it is replaced or removed in the meantime.
+    ///
+    /// If no writes have happened or if the write handle has been dropped, then
+    /// None is returned here
+    pub fn get<'rh>(&'rh self, key: &K) -> Option<ReadGuard<'rh, V>> {
+        // values are only freed after every reader that could see them has
+        // finished reading, and this guard keeps our read going
+        Some(
+            self.handle
+                .get(key)?
+                .map_ref(|value| unsafe { value.0.as_ref() }),
+        )
+    }
+
+    /// Returns true if the map contains a value for the specified key.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.handle.contains_key(key)
+    }
+
+    /// Returns the number of values present in the map.
+    pub fn len(&self) -> usize {
+        self.handle.len()
+    }
+
+    /// Returns true if the map contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.handle.is_empty()
+    }
+
+    /// Returns the generation of the currently published version of the map.
+    pub fn generation(&self) -> u64 {
+        self.handle.generation()
+    }
+
+    /// Returns true if the writer has destroyed this map.
+    pub fn is_destroyed(&self) -> bool {
+        self.handle.is_destroyed()
+    }
+
+    /// Get the pointer to the value currently published for the given key
+    fn published(&self, key: &K) -> Option<Shared<V>> {
+        self.handle.get(key).map(|value| Shared(value.0))
+    }
+}
+
+/// A handle that may be used to modify a slot map created with
+/// [`new_rcu`](crate::new_rcu).
+///
+/// Each value lives in a single heap allocation that both copies of the map
+/// point at, so the slot tables only hold pointers and values are never
+/// copied. Replaced and removed values are retired rather than dropped, and
+/// freed once every reader that was reading when they were unpublished has
+/// finished that read. Retired values are checked on every write, or on
+/// demand with [`reclaim`](RcuWriteHandle::reclaim). Like [`WriteHandle`],
+/// this derefs to a read handle for the same map.
+///
+/// Unlike a [`WriteHandle`], dropping this during a panic destroys the map
+/// rather than freezing it, since the values are freed along with it.
+pub struct RcuWriteHandle<K, P, V>
+where
+    K: Key<P>,
+{
+    handle: Option<WriteHandle<K, P, Shared<V>>>,
+    reader: RcuReadHandle<K, P, V>,
+    retired: Vec<(GracePeriod, Vec<Shared<V>>)>,
+}
+
+impl<K, P, V> fmt::Debug for RcuWriteHandle<K, P, V>
+where
+    K: fmt::Debug + Key<P>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RcuWriteHandle")
+            .field("handle", &self.handle)
+            .field("reader", &self.reader)
+            .field("pending_reclamation", &self.pending_reclamation())
+            .finish()
+    }
+}
+
+impl<K, P, V> Drop for RcuWriteHandle<K, P, V>
+where
+    K: Key<P>,
+{
+    fn drop(&mut self) {
+        let live: Vec<_> = match self.reader.handle.read() {
+            Some(map) => map.values().map(|value| Shared(value.0)).collect(),
+            None => Vec::new(),
+        };
+
+        // destroying the map waits for all readers to depart, after which
+        // nothing can see any of the values
+        drop(self.handle.take());
+        if !self.reader.is_destroyed() {
+            // the map was left readable after a write panicked, so its values
+            // have to stay around for good
+            self.retired.clear();
+            return;
+        }
+
+        for value in live
+            .into_iter()
+            .chain(self.retired.drain(..).flat_map(|(_, values)| values))
+        {
+            unsafe { value.free() };
+        }
+    }
+}
+
+impl<K, P, V> RcuWriteHandle<K, P, V>
+where
+    K: Key<P>,
+{
+    fn handle(&mut self) -> &mut WriteHandle<K, P, Shared<V>> {
+        self.handle.as_mut().unwrap()
+    }
+
+    /// Insert the given value into the slot map and return the associated key
+    pub fn insert(&mut self, p: P, v: V) -> K {
+        let key = self.handle().insert(p, Shared::new(v));
+        self.reclaim();
+        key
+    }
+
+    /// Replace the value of the given key with the given value. The old value
+    /// is freed once no reader can see it anymore.
+    pub fn update(&mut self, k: K, v: V) {
+        match self.reader.published(&k) {
+            Some(old) => {
+                self.handle().update(k, Shared::new(v));
+                self.retire(vec![old]);
+            }
+            // there's nothing to replace, so the new value is never published
+            None => drop(v),
+        }
+    }
+
+    /// Remove the value from the map for the given key. The value is freed
+    /// once no reader can see it anymore.
+    pub fn remove(&mut self, k: &K) {
+        let old = self.reader.published(k);
+        self.handle().remove(k);
+        self.retire(old.into_iter().collect());
+    }
+
+    /// Clear the slot map. The values are freed once no reader can see them
+    /// anymore.
+    pub fn clear(&mut self) {
+        let old = match self.reader.handle.read() {
+            Some(map) => map.values().map(|value| Shared(value.0)).collect(),
+            None => Vec::new(),
+        };
+        self.handle().clear();
+        self.retire(old);
+    }
+
+    /// Free every retired value whose grace period has passed.
+    pub fn reclaim(&mut self) {
+        let mut i = 0;
+        while i < self.retired.len() {
+            if !self.retired[i].0.has_passed() {
+                i += 1;
+                continue;
+            }
+            let (_, values) = self.retired.remove(i);
+            for value in values {
+                unsafe { value.free() };
+            }
+        }
+    }
+
+    /// Returns the number of values that have been replaced or removed but
+    /// not yet freed, because a reader may still be looking at them.
+    pub fn pending_reclamation(&self) -> usize {
+        self.retired.iter().map(|(_, values)| values.len()).sum()
+    }
+
+    /// Start the grace period for values that were just unpublished
+    fn retire(&mut self, values: Vec<Shared<V>>) {
+        if !values.is_empty() {
+            let grace = GracePeriod::start(&self.reader.handle.epochs);
+            self.retired.push((grace, values));
+        }
+        self.reclaim();
+    }
+}
+
+impl<K, P, V> Deref for RcuWriteHandle<K, P, V>
+where
+    K: Key<P>,
+{
+    type Target = RcuReadHandle<K, P, V>;
+    fn deref(&self) -> &Self::Target {
+        &self.reader
+    }
+}
@@ -1,9 +1,11 @@
+use super::published::Published;
 use super::ReadHandle;
-use crate::inner::Inner;
+use crate::changes::ChangeLog;
+use crate::signal::PublishSignal;
+use crate::{ShallowCopy, WriteHandle};
 use one_way_slot_map::SlotMapKey as Key;
 use std::marker::PhantomData;
-use std::mem::ManuallyDrop;
-use std::sync::atomic::AtomicPtr;
+use std::sync::Mutex;
 use std::{fmt, sync};
 
 /// A type that is both `Sync` and `Send` and lets you produce new [`ReadHandle`] instances.
@@ -12,18 +14,20 @@ use std::{fmt, sync};
 /// additional external locking to synchronize access to the non-`Sync` `ReadHandle` type. Note
 /// that this _internally_ takes a lock whenever you call [`ReadHandleFactory::handle`], so
 /// you should not expect producing new handles rapidly to scale well.
-pub struct ReadHandleFactory<K, P, V>
+pub struct ReadHandleFactory<K, P, V, M = ()>
 where
     K: Key<P>,
 {
-    pub(super) inner: sync::Arc<AtomicPtr<Inner<ManuallyDrop<V>>>>,
+    pub(super) inner: sync::Arc<Published<V, M>>,
     pub(super) epochs: crate::Epochs,
+    pub(super) signal: sync::Arc<PublishSignal>,
+    pub(super) changes: sync::Arc<Mutex<ChangeLog>>,
 
     pub(super) _phantom_p: PhantomData<P>,
     pub(super) _phantom_k: PhantomData<K>,
 }
 
-impl<K, P, V> fmt::Debug for ReadHandleFactory<K, P, V>
+impl<K, P, V, M> fmt::Debug for ReadHandleFactory<K, P, V, M>
 where
     K: Key<P>,
 {
@@ -34,7 +38,7 @@ where
     }
 }
 
-impl<K, P, V> Clone for ReadHandleFactory<K, P, V>
+impl<K, P, V, M> Clone for ReadHandleFactory<K, P, V, M>
 where
     K: Key<P>,
 {
@@ -42,6 +46,8 @@ where
         Self {
             inner: sync::Arc::clone(&self.inner),
             epochs: sync::Arc::clone(&self.epochs),
+            signal: sync::Arc::clone(&self.signal),
+            changes: sync::Arc::clone(&self.changes),
 
             _phantom_p: Default::default(),
             _phantom_k: Default::default(),
@@ -49,15 +55,43 @@ where
     }
 }
 
-impl<K, P, V> ReadHandleFactory<K, P, V>
+impl<K, P, V, M> ReadHandleFactory<K, P, V, M>
 where
     K: Key<P>,
 {
     /// Produce a new [`ReadHandle`] to the same map as this factory was originally produced from.
-    pub fn handle(&self) -> ReadHandle<K, P, V> {
+    pub fn handle(&self) -> ReadHandle<K, P, V, M> {
         ReadHandle::new(
             sync::Arc::clone(&self.inner),
             sync::Arc::clone(&self.epochs),
+            sync::Arc::clone(&self.signal),
+            sync::Arc::clone(&self.changes),
         )
     }
 }
+
+impl<K, P, V, M> ReadHandleFactory<K, P, V, M>
+where
+    K: Key<P>,
+    V: ShallowCopy,
+    M: ShallowCopy,
+{
+    /// Take over writing to this map, if its writer has gone away but left
+    /// the map frozen, either through [`WriteHandle::freeze`] or by being
+    /// dropped during a panic.
+    ///
+    /// The new writer starts from the last published version of the map, so
+    /// existing readers carry on and simply see its writes once they are
+    /// published. Returns `None` if a writer still exists, if another caller
+    /// has already claimed the map, if the map was destroyed, or if its
+    /// writer panicked part way through a write.
+    pub fn try_claim_writer(&self) -> Option<WriteHandle<K, P, V, M>> {
+        let w_handle = self.inner.thaw()?;
+        self.signal.reopen();
+        Some(crate::write::new(
+            *w_handle,
+            sync::Arc::clone(&self.epochs),
+            self.handle(),
+        ))
+    }
+}
@@ -1,10 +1,15 @@
+use crate::changes::{ChangeLog, ChangeSet, TooOld};
 use crate::inner::Inner;
+use crate::lock_epochs;
+use crate::signal::{PublishSignal, WaitForGeneration};
+use crate::slot_table::KeyStatus;
+use crate::stats::{HeapSize, MemoryStats};
 use one_way_slot_map::SlotMapKey as Key;
 use std::marker::PhantomData;
 use std::mem::ManuallyDrop;
 use std::sync::atomic;
-use std::sync::atomic::AtomicPtr;
-use std::sync::{self, Arc};
+use std::sync::{self, Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{cell, fmt, mem};
 
 mod guard;
@@ -13,21 +18,26 @@ pub use guard::ReadGuard;
 mod factory;
 pub use factory::ReadHandleFactory;
 
+mod published;
+pub(crate) use published::Published;
+
 mod read_ref;
 pub use read_ref::MapReadRef;
 
 /// Turn an manually drop into something useable
-pub(crate) fn user_friendly<'a, T>(to_fix: &'a ManuallyDrop<T>) -> &'a T {
+pub(crate) fn user_friendly<T>(to_fix: &ManuallyDrop<T>) -> &T {
     unsafe { &*(to_fix as *const ManuallyDrop<T> as *const T) }
 }
 
 /// A handle that may be used to read from the concurrent slot map.
-pub struct ReadHandle<K, P, V>
+pub struct ReadHandle<K, P, V, M = ()>
 where
     K: Key<P>,
 {
-    pub(crate) inner: sync::Arc<AtomicPtr<Inner<ManuallyDrop<V>>>>,
+    pub(crate) inner: sync::Arc<Published<V, M>>,
     pub(crate) epochs: crate::Epochs,
+    pub(crate) signal: sync::Arc<PublishSignal>,
+    pub(crate) changes: sync::Arc<Mutex<ChangeLog>>,
     epoch: sync::Arc<sync::atomic::AtomicUsize>,
     epoch_i: usize,
     my_epoch: sync::atomic::AtomicUsize,
@@ -44,18 +54,18 @@ where
     _phantom_k: PhantomData<K>,
 }
 
-impl<K, P, V> Drop for ReadHandle<K, P, V>
+impl<K, P, V, M> Drop for ReadHandle<K, P, V, M>
 where
     K: Key<P>,
 {
     fn drop(&mut self) {
         // parity must be restored, so okay to lock since we're not holding up the epoch
-        let e = self.epochs.lock().unwrap().remove(self.epoch_i);
+        let e = lock_epochs(&self.epochs).remove(self.epoch_i);
         assert!(Arc::ptr_eq(&e, &self.epoch));
     }
 }
 
-impl<K, P, V> fmt::Debug for ReadHandle<K, P, V>
+impl<K, P, V, M> fmt::Debug for ReadHandle<K, P, V, M>
 where
     K: fmt::Debug + Key<P>,
 {
@@ -68,7 +78,7 @@ where
     }
 }
 
-impl<K, P, V> Clone for ReadHandle<K, P, V>
+impl<K, P, V, M> Clone for ReadHandle<K, P, V, M>
 where
     K: Key<P>,
 {
@@ -76,36 +86,48 @@ where
         ReadHandle::new(
             sync::Arc::clone(&self.inner),
             sync::Arc::clone(&self.epochs),
+            sync::Arc::clone(&self.signal),
+            sync::Arc::clone(&self.changes),
         )
     }
 }
 
-pub(crate) fn new<K, P, V>(
-    inner: Inner<ManuallyDrop<V>>,
+pub(crate) fn new<K, P, V, M>(
+    inner: Inner<ManuallyDrop<V>, ManuallyDrop<M>>,
     epochs: crate::Epochs,
-) -> ReadHandle<K, P, V>
+) -> ReadHandle<K, P, V, M>
 where
     K: Key<P>,
 {
-    let store = Box::into_raw(Box::new(inner));
-    ReadHandle::new(sync::Arc::new(AtomicPtr::new(store)), epochs)
+    let signal = sync::Arc::new(PublishSignal::new(inner.generation));
+    let changes = sync::Arc::new(Mutex::new(ChangeLog::new(inner.generation)));
+    ReadHandle::new(
+        sync::Arc::new(Published::new(inner)),
+        epochs,
+        signal,
+        changes,
+    )
 }
 
-impl<K, P, V> ReadHandle<K, P, V>
+impl<K, P, V, M> ReadHandle<K, P, V, M>
 where
     K: Key<P>,
 {
-    fn new(
-        inner: sync::Arc<AtomicPtr<Inner<ManuallyDrop<V>>>>,
+    pub(crate) fn new(
+        inner: sync::Arc<Published<V, M>>,
         epochs: crate::Epochs,
+        signal: sync::Arc<PublishSignal>,
+        changes: sync::Arc<Mutex<ChangeLog>>,
     ) -> Self {
         // tell writer about our epoch tracker
         let epoch = sync::Arc::new(atomic::AtomicUsize::new(0));
         // okay to lock, since we're not holding up the epoch
-        let epoch_i = epochs.lock().unwrap().insert(Arc::clone(&epoch));
+        let epoch_i = lock_epochs(&epochs).insert(Arc::clone(&epoch));
 
         Self {
             epochs,
+            signal,
+            changes,
             epoch,
             epoch_i,
             my_epoch: atomic::AtomicUsize::new(0),
@@ -118,21 +140,25 @@ where
 
     /// Create a new `Sync` type that can produce additional `ReadHandle`s for use in other
     /// threads.
-    pub fn factory(&self) -> ReadHandleFactory<K, P, V> {
+    pub fn factory(&self) -> ReadHandleFactory<K, P, V, M> {
         ReadHandleFactory {
             inner: sync::Arc::clone(&self.inner),
             epochs: sync::Arc::clone(&self.epochs),
+            signal: sync::Arc::clone(&self.signal),
+            changes: sync::Arc::clone(&self.changes),
             _phantom_p: Default::default(),
             _phantom_k: Default::default(),
         }
     }
 }
 
-impl<K, P, V> ReadHandle<K, P, V>
+impl<K, P, V, M> ReadHandle<K, P, V, M>
 where
     K: Key<P>,
 {
-    fn handle(&self) -> Option<ReadGuard<'_, Inner<ManuallyDrop<V>>>> {
+    pub(crate) fn handle(
+        &self,
+    ) -> Option<ReadGuard<'_, Inner<ManuallyDrop<V>, ManuallyDrop<M>>>> {
         // once we update our epoch, the writer can no longer do a swap until we set the MSB to
         // indicate that we've finished our read. however, we still need to deal with the case of a
         // race between when the writer reads our epoch and when they decide to make the swap.
@@ -199,11 +225,8 @@ where
     /// If no refresh has happened, or the map has been destroyed, this function returns `None`.
     ///
     /// See [`MapReadRef`].
-    pub fn read(&self) -> Option<MapReadRef<'_, K, P, V>> {
-        let guard = self.handle()?;
-        if !guard.is_ready() {
-            return None;
-        }
+    pub fn read(&self) -> Option<MapReadRef<'_, K, P, V, M>> {
+        let guard = self.read_inner()?;
         Some(MapReadRef {
             guard,
             _phantom_k: Default::default(),
@@ -211,14 +234,142 @@ where
         })
     }
 
+    /// Get a guarded reference to the published copy of the map, as long as
+    /// anything has been published
+    pub(crate) fn read_inner(
+        &self,
+    ) -> Option<ReadGuard<'_, Inner<ManuallyDrop<V>, ManuallyDrop<M>>>> {
+        let guard = self.handle()?;
+        if !guard.is_ready() {
+            return None;
+        }
+        Some(guard)
+    }
+
     /// Returns the number of non-empty keys present in the map.
     pub fn len(&self) -> usize {
         self.read().map_or(0, |x| x.len())
     }
 
+    /// Returns the number of values the published copy of the map can hold
+    /// without reallocating. Both copies are grown and shrunk together through
+    /// [`WriteHandle::reserve`] and [`WriteHandle::shrink_to_fit`].
+    ///
+    /// [`WriteHandle::reserve`]: crate::WriteHandle::reserve
+    /// [`WriteHandle::shrink_to_fit`]: crate::WriteHandle::shrink_to_fit
+    pub fn capacity(&self) -> usize {
+        self.read().map_or(0, |x| x.capacity())
+    }
+
+    /// Returns how much memory the published copy of the map is using. See
+    /// [`MemoryStats`].
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.measure(None)
+    }
+
+    /// Same as [`ReadHandle::memory_stats`], but also adds up the heap memory
+    /// owned by every value.
+    pub fn memory_stats_deep(&self) -> MemoryStats
+    where
+        V: HeapSize,
+    {
+        self.measure(Some(V::heap_size))
+    }
+
+    fn measure(&self, value_heap_size: Option<fn(&V) -> usize>) -> MemoryStats {
+        // the writer holds this lock while it waits for readers to depart, so
+        // it must not be taken while we're reading
+        let readers = lock_epochs(&self.epochs).len();
+
+        let mut stats = MemoryStats {
+            readers,
+            ..Default::default()
+        };
+        if let Some(inner) = self.read_inner() {
+            stats.slot_capacity = inner.data.capacity();
+            stats.occupied = inner.data.len();
+            stats.free_slots = inner.data.vacant();
+            stats.published_bytes = inner.bytes();
+            stats.value_heap_bytes = value_heap_size.map(|heap_size| {
+                inner.data.values().map(|v| heap_size(v)).sum()
+            });
+        }
+        stats
+    }
+
+    /// Returns a guarded reference to the metadata published with the current
+    /// version of the map. To see it together with the data from the same
+    /// version, use [`MapReadRef::meta`] instead.
+    ///
+    /// If no writes have happened or if the write handle has been dropped,
+    /// then None is returned here
+    pub fn meta(&self) -> Option<ReadGuard<'_, M>> {
+        Some(
+            self.read_inner()?
+                .map_ref(|inner| user_friendly(&inner.meta)),
+        )
+    }
+
     /// Returns true if the map contains no non-empty keys.
     pub fn is_empty(&self) -> bool {
-        self.read().map_or(true, |x| x.is_empty())
+        self.read().map(|x| x.is_empty()).unwrap_or(true)
+    }
+
+    /// Returns the generation of the currently published version of the map.
+    ///
+    /// This can be used to cheaply check whether the map has changed since it
+    /// was last read. If no writes have happened or the map has been
+    /// destroyed, this returns 0.
+    pub fn generation(&self) -> u64 {
+        self.read().map_or(0, |x| x.generation())
+    }
+
+    /// Block the current thread until the map has published at least the
+    /// given generation.
+    ///
+    /// The wait is woken by the writer as soon as it makes a new version
+    /// visible, so no polling is involved. Returns `false` if the writer is
+    /// dropped before the generation is reached.
+    pub fn wait_for_generation(&self, generation: u64) -> bool {
+        self.signal.wait(generation, None)
+    }
+
+    /// Same as [`ReadHandle::wait_for_generation`], but gives up and returns
+    /// `false` if the generation has not been reached within the timeout.
+    pub fn wait_for_generation_timeout(
+        &self,
+        generation: u64,
+        timeout: Duration,
+    ) -> bool {
+        self.signal
+            .wait(generation, Instant::now().checked_add(timeout))
+    }
+
+    /// Returns a future that resolves once the map has published at least the
+    /// given generation.
+    ///
+    /// The future does not borrow this handle, so it can be sent to and
+    /// awaited on any executor.
+    pub fn wait_for_generation_async(
+        &self,
+        generation: u64,
+    ) -> WaitForGeneration {
+        WaitForGeneration {
+            signal: sync::Arc::clone(&self.signal),
+            generation,
+        }
+    }
+
+    /// Returns the set of slots touched by every write published after the
+    /// given generation.
+    ///
+    /// This lets a consumer that has mirrored the map up to some generation
+    /// catch up by re-reading only the slots that changed. Only a bounded
+    /// number of recent operations are remembered, so if the requested
+    /// generation is too far in the past, [`TooOld`] is returned and the
+    /// consumer has to fall back to a full scan.
+    pub fn changes_since(&self, generation: u64) -> Result<ChangeSet, TooOld> {
+        self.changes.lock().unwrap().changes_since(generation)
     }
 
     /// Internal version of `get_and`
@@ -227,7 +378,7 @@ where
         if !inner.is_ready() {
             return None;
         }
-        inner.map_opt(|inner| inner.data.get_unbounded(key))
+        inner.map_opt(|inner| inner.data.get(key.borrow()))
     }
 
     /// Returns a guarded reference to the value corresponding to the key.
@@ -242,14 +393,34 @@ where
         Some(self.get_raw(key)?.map_ref(user_friendly))
     }
 
+    /// Find out why a lookup for the given key does or doesn't find a value.
+    ///
+    /// [`ReadHandle::get`] returns `None` for a key whose value was removed,
+    /// for a key that was never handed out by this map, and for a map that
+    /// has not been written to or has been destroyed. This tells those cases
+    /// apart, which helps when tracking down where a dangling key came from.
+    pub fn lookup_status(&self, key: &K) -> KeyStatus {
+        match self.handle() {
+            None => KeyStatus::MapDestroyed,
+            Some(inner) if !inner.is_ready() => KeyStatus::MapUninitialized,
+            Some(inner) => inner.data.status(key.borrow()),
+        }
+    }
+
     /// Returns true if the writer has destroyed this map (This happens when the
-    /// writer is dropped).
+    /// writer is dropped without freezing the map first).
     pub fn is_destroyed(&self) -> bool {
         self.handle().is_none()
     }
 
+    /// Returns true if the writer has frozen this map, so it will never
+    /// change again. See [`WriteHandle::freeze`](crate::WriteHandle::freeze).
+    pub fn is_frozen(&self) -> bool {
+        self.inner.is_frozen()
+    }
+
     /// Returns true if the map contains a value for the specified key.
     pub fn contains_key(&self, key: &K) -> bool {
-        self.read().map_or(false, |x| x.contains_key(key))
+        self.read().map(|x| x.contains_key(key)).unwrap_or(false)
     }
 }
@@ -0,0 +1,73 @@
+use crate::inner::Inner;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::Mutex;
+
+type Copy<V, M> = Box<Inner<ManuallyDrop<V>, ManuallyDrop<M>>>;
+
+/// The pointer through which readers find the published copy of the map.
+///
+/// While the writer is around it owns whatever this points at. When the
+/// writer freezes the map, it leaves its own copy here too, so a new writer
+/// can pick up both copies where it left off. If nobody does, the last read
+/// handle or factory to let go of this frees them. A writer that can't vouch
+/// for its own copy strands the map instead, which leaves it readable but
+/// never frees it.
+pub(crate) struct Published<V, M> {
+    ptr: AtomicPtr<Inner<ManuallyDrop<V>, ManuallyDrop<M>>>,
+    frozen: Mutex<Option<Copy<V, M>>>,
+    stranded: AtomicBool,
+}
+
+impl<V, M> Published<V, M> {
+    pub(crate) fn new(inner: Inner<ManuallyDrop<V>, ManuallyDrop<M>>) -> Self {
+        Published {
+            ptr: AtomicPtr::new(Box::into_raw(Box::new(inner))),
+            frozen: Mutex::new(None),
+            stranded: AtomicBool::new(false),
+        }
+    }
+
+    /// Hand ownership of the published copy over to the readers, keeping the
+    /// writer's up to date copy for whoever writes next
+    pub(crate) fn freeze(&self, w_handle: Copy<V, M>) {
+        *self.frozen.lock().unwrap() = Some(w_handle);
+    }
+
+    /// Hand the published copy over to the readers for good, without a copy
+    /// for anyone to write to next
+    pub(crate) fn strand(&self) {
+        self.stranded.store(true, Ordering::Release);
+    }
+
+    pub(crate) fn is_frozen(&self) -> bool {
+        self.stranded.load(Ordering::Acquire)
+            || self.frozen.lock().unwrap().is_some()
+    }
+
+    /// Take back the writer's copy of a frozen map. Only one caller will ever
+    /// get it, and the map is no longer frozen once it has been taken.
+    pub(crate) fn thaw(&self) -> Option<Copy<V, M>> {
+        self.frozen.lock().unwrap().take()
+    }
+}
+
+impl<V, M> Deref for Published<V, M> {
+    type Target = AtomicPtr<Inner<ManuallyDrop<V>, ManuallyDrop<M>>>;
+    fn deref(&self) -> &Self::Target {
+        &self.ptr
+    }
+}
+
+impl<V, M> Drop for Published<V, M> {
+    fn drop(&mut self) {
+        if let Some(mut w_handle) = self.frozen.get_mut().unwrap().take() {
+            // the writer is gone, and so is every reader. the writer's copy
+            // only holds shallow copies, and the published copy owns them.
+            w_handle.clear_values(false);
+            let mut r_handle = unsafe { Box::from_raw(*self.ptr.get_mut()) };
+            unsafe { r_handle.drop_values() };
+        }
+    }
+}
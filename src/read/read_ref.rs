@@ -1,8 +1,11 @@
 use super::ReadGuard;
+use crate::index::OrderedIndex;
 use crate::inner::Inner;
+use crate::secondary::{downcast_store, EvSecondaryMap};
 use one_way_slot_map::{SlotMapKey as Key, SlotMapKeyData};
 use std::marker::PhantomData;
 use std::mem::ManuallyDrop;
+use std::ops::RangeBounds;
 
 use super::user_friendly;
 
@@ -15,16 +18,16 @@ use super::user_friendly;
 /// Since the map remains immutable while this lives, the methods on this type all give you
 /// unguarded references to types contained in the map.
 #[derive(Debug)]
-pub struct MapReadRef<'rh, K, P, V>
+pub struct MapReadRef<'rh, K, P, V, M = ()>
 where
     K: Key<P>,
 {
-    pub(super) guard: ReadGuard<'rh, Inner<ManuallyDrop<V>>>,
+    pub(super) guard: ReadGuard<'rh, Inner<ManuallyDrop<V>, ManuallyDrop<M>>>,
     pub(super) _phantom_k: PhantomData<K>,
     pub(super) _phantom_p: PhantomData<P>,
 }
 
-impl<'rh, K, P, V> MapReadRef<'rh, K, P, V>
+impl<'rh, K, P, V, M> MapReadRef<'rh, K, P, V, M>
 where
     K: Key<P>,
 {
@@ -33,11 +36,34 @@ where
         self.guard.data.len()
     }
 
+    /// Returns the metadata published with this version of the map.
+    pub fn meta(&self) -> &M {
+        user_friendly(&self.guard.meta)
+    }
+
+    /// Returns the number of values this version of the map can hold without
+    /// reallocating.
+    pub fn capacity(&self) -> usize {
+        self.guard.data.capacity()
+    }
+
     /// Returns true if the map contains no elements.
     pub fn is_empty(&self) -> bool {
         self.guard.data.is_empty()
     }
 
+    /// Returns the generation of the version of the map being read. The
+    /// generation is incremented by the writer every time it publishes a
+    /// change, so two reads with the same generation observe the same data.
+    pub fn generation(&self) -> u64 {
+        self.guard.generation
+    }
+
+    /// Get the copy of the map this reference is reading
+    pub(crate) fn inner(&self) -> &Inner<ManuallyDrop<V>, ManuallyDrop<M>> {
+        &self.guard
+    }
+
     /// Get an iterator over all the items in the slot map
     pub fn values(&self) -> impl Iterator<Item = &V> {
         self.guard.data.values().map(user_friendly)
@@ -53,7 +79,7 @@ where
         F: FnMut(&V) -> P,
     {
         self.iter_raw().map(move |(key_data, v)| {
-            (K::from(((&mut pointer_finder)(v), key_data)), v)
+            (K::from((pointer_finder(v), key_data)), v)
         })
     }
 
@@ -74,7 +100,7 @@ where
     /// refreshed by the writer. If no refresh has happened, or the map has been destroyed, this
     /// function returns `None`.
     pub fn get<'a>(&'a self, key: &'_ K) -> Option<&'a V> {
-        self.guard.data.get_unbounded(key).map(user_friendly)
+        self.guard.data.get(key.borrow()).map(user_friendly)
     }
 
     /// Returns true if the map contains any values for the specified key.
@@ -82,6 +108,112 @@ where
     /// The key may be any borrowed form of the map's key type, but `Hash` and `Eq` on the borrowed
     /// form *must* match those for the key type.
     pub fn contains_key(&self, key: &K) -> bool {
-        self.guard.data.contains_key_unbounded(key)
+        self.guard.data.contains_key(key.borrow())
+    }
+
+    /// Returns a reference to the value a secondary map holds for the key.
+    pub fn get_secondary<'a, W>(
+        &'a self,
+        secondary: &EvSecondaryMap<K, W>,
+        key: &'_ K,
+    ) -> Option<&'a W>
+    where
+        W: 'static,
+    {
+        let store = self.guard.secondaries.get(&secondary.id)?;
+        downcast_store::<W>(store.as_ref())?
+            .get(key.borrow())
+            .map(user_friendly)
+    }
+
+    /// Returns references to both the value for the key and the value the
+    /// given secondary map holds for it. Both come from the same published
+    /// version of the map.
+    pub fn get_joined<'a, W>(
+        &'a self,
+        secondary: &EvSecondaryMap<K, W>,
+        key: &'_ K,
+    ) -> (Option<&'a V>, Option<&'a W>)
+    where
+        W: 'static,
+    {
+        (self.get(key), self.get_secondary(secondary, key))
+    }
+
+    /// Get the raw key data and values for every entry whose indexed key in
+    /// the named index is equal to `ik`.
+    ///
+    /// Returns `None` if there is no index with the given name, or if it was
+    /// built with a different key type. See [`WriteHandle::add_index`].
+    ///
+    /// [`WriteHandle::add_index`]: crate::WriteHandle::add_index
+    pub fn lookup_index_raw<IK>(
+        &self,
+        name: &str,
+        ik: &IK,
+    ) -> Option<impl Iterator<Item = (SlotMapKeyData, &V)>>
+    where
+        IK: 'static,
+    {
+        let slots = self.guard.indexes.get(name)?.lookup(ik)?;
+
+        Some(
+            slots
+                .map(move |key_data| (*key_data, self.get_raw_value(key_data))),
+        )
+    }
+
+    /// Get the values for every entry whose indexed key in the named index is
+    /// equal to `ik`, or `None` if no such index exists.
+    pub fn lookup_index<IK>(
+        &self,
+        name: &str,
+        ik: &IK,
+    ) -> Option<impl Iterator<Item = &V>>
+    where
+        IK: 'static,
+    {
+        Some(self.lookup_index_raw(name, ik)?.map(|(_, v)| v))
+    }
+
+    /// Get the values for every entry whose indexed key in the named ordered
+    /// index falls in the given range, in key order.
+    ///
+    /// Returns `None` if there is no ordered index with the given name and key
+    /// type. See [`WriteHandle::add_ordered_index`].
+    ///
+    /// [`WriteHandle::add_ordered_index`]: crate::WriteHandle::add_ordered_index
+    pub fn lookup_index_range<IK, R>(
+        &self,
+        name: &str,
+        range: R,
+    ) -> Option<impl Iterator<Item = &V>>
+    where
+        IK: Ord + 'static,
+        R: RangeBounds<IK>,
+        V: 'static,
+    {
+        let index = self
+            .guard
+            .indexes
+            .get(name)?
+            .as_any()
+            .downcast_ref::<OrderedIndex<V, IK>>()?;
+
+        Some(
+            index
+                .range(range)
+                .map(move |key_data| self.get_raw_value(key_data)),
+        )
+    }
+
+    /// Get the value in a slot an index says is occupied
+    fn get_raw_value(&self, key_data: &SlotMapKeyData) -> &V {
+        user_friendly(
+            self.guard
+                .data
+                .get(key_data)
+                .expect("Index out of sync with map"),
+        )
     }
 }
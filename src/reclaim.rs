@@ -0,0 +1,101 @@
+use std::sync::mpsc;
+use std::thread;
+
+/// When the write handle drops values that have been replaced or removed.
+///
+/// A value can only be dropped once neither copy of the map holds it, which
+/// is while the writer applies the operation that removed it for the second
+/// time, at the start of the *next* write. With [`DropPolicy::Inline`] the
+/// drop happens right there, which holds up that write. The other policies
+/// move the drop somewhere else.
+///
+/// Only the map's own values follow the policy. Metadata and the values of
+/// secondary maps are always dropped inline.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DropPolicy {
+    /// Drop values as soon as neither copy holds them. This is the default.
+    #[default]
+    Inline,
+    /// Keep values on a garbage list until
+    /// [`WriteHandle::collect_garbage`](crate::WriteHandle::collect_garbage)
+    /// is called.
+    Deferred,
+    /// Send values to a dedicated thread that drops them.
+    Background,
+}
+
+/// Where values go once neither copy of the map holds them
+pub(crate) enum Reclaimer<V> {
+    Inline,
+    Deferred(Vec<V>),
+    Background(mpsc::Sender<V>, thread::JoinHandle<()>),
+}
+
+impl<V> Reclaimer<V>
+where
+    V: Send + 'static,
+{
+    pub(crate) fn new(policy: DropPolicy) -> Self {
+        match policy {
+            DropPolicy::Inline => Reclaimer::Inline,
+            DropPolicy::Deferred => Reclaimer::Deferred(Vec::new()),
+            DropPolicy::Background => {
+                let (sender, receiver) = mpsc::channel();
+                let thread = thread::Builder::new()
+                    .name("ev_slotmap-reclaim".to_string())
+                    .spawn(move || receiver.into_iter().for_each(drop))
+                    .expect("Failed to spawn the reclamation thread");
+                Reclaimer::Background(sender, thread)
+            }
+        }
+    }
+}
+
+impl<V> Reclaimer<V> {
+    pub(crate) fn policy(&self) -> DropPolicy {
+        match self {
+            Reclaimer::Inline => DropPolicy::Inline,
+            Reclaimer::Deferred(_) => DropPolicy::Deferred,
+            Reclaimer::Background(..) => DropPolicy::Background,
+        }
+    }
+
+    /// Get rid of a value neither copy of the map holds anymore
+    pub(crate) fn dispose(&mut self, value: V) {
+        match self {
+            Reclaimer::Inline => drop(value),
+            Reclaimer::Deferred(garbage) => garbage.push(value),
+            Reclaimer::Background(sender, _) => {
+                // if the thread has died, there's nobody else to drop it
+                if let Err(mpsc::SendError(value)) = sender.send(value) {
+                    drop(value);
+                }
+            }
+        }
+    }
+
+    /// Drop everything on the garbage list, returning how many values that was
+    pub(crate) fn collect(&mut self) -> usize {
+        match self {
+            Reclaimer::Deferred(garbage) => {
+                let count = garbage.len();
+                garbage.clear();
+                count
+            }
+            _ => 0,
+        }
+    }
+
+    /// Stop reclaiming, making sure every value handed over so far has been
+    /// dropped by the time this returns
+    pub(crate) fn finish(self) {
+        match self {
+            Reclaimer::Inline | Reclaimer::Deferred(_) => (),
+            Reclaimer::Background(sender, thread) => {
+                drop(sender);
+                let _ = thread.join();
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use super::Operation;
+use crate::inner::Inner;
+use crate::read::ReadHandle;
+use crate::snapshot::{self, read_bytes, Decode, Encode, SnapshotError};
+use crate::wal::{self, apply_record, encode_record, frame, read_record};
+use crate::write::WriteHandle;
+use crate::ShallowCopy;
+use one_way_slot_map::SlotMapKey as Key;
+use std::fmt;
+use std::io::{self, BufReader, Read, Write};
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::sync::mpsc;
+use std::thread;
+
+/// The number of writes buffered for a follower if no capacity is given
+pub(crate) const DEFAULT_REPLICATION_CAPACITY: usize = 1024;
+
+/// The streams a leader's write handle sends its writes to, along with how
+/// to encode the handle's values
+pub(crate) struct Followers<V> {
+    streams: Vec<mpsc::SyncSender<Vec<u8>>>,
+    encode: fn(&V, &mut dyn Write) -> io::Result<()>,
+}
+
+impl<V> fmt::Debug for Followers<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Followers")
+            .field("streams", &self.streams.len())
+            .finish()
+    }
+}
+
+impl<V> Followers<V> {
+    pub(crate) fn new() -> Self
+    where
+        V: Encode,
+    {
+        Followers {
+            streams: Vec::new(),
+            encode: V::encode,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Send a new follower the given copy of the map, then keep it up to
+    /// date from there. Everything is written to the stream by a thread of
+    /// its own, which buffers up to `capacity` writes.
+    pub(crate) fn add<M>(
+        &mut self,
+        target: &Inner<ManuallyDrop<V>, ManuallyDrop<M>>,
+        mut stream: impl Write + Send + 'static,
+        capacity: usize,
+    ) -> Result<(), SnapshotError>
+    where
+        V: Encode,
+        M: Encode,
+    {
+        assert!(capacity > 0, "A follower needs room for at least one write");
+
+        // the snapshot is length prefixed, since loading it reads ahead
+        let mut snapshot = Vec::new();
+        snapshot::write(target, &mut snapshot)?;
+        let mut start = Vec::new();
+        (snapshot.len() as u64).encode(&mut start)?;
+        start.append(&mut snapshot);
+        wal::write_header(&mut start)?;
+
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(capacity);
+        thread::Builder::new()
+            .name("ev_slotmap-replicate".to_string())
+            .spawn(move || {
+                // once the stream fails, the receiver is dropped, which the
+                // writer sees on its next send
+                let mut bytes = Some(start).into_iter().chain(receiver);
+                let _ = bytes.try_for_each(|bytes| {
+                    stream.write_all(&bytes).and_then(|()| stream.flush())
+                });
+            })?;
+
+        self.streams.push(sender);
+        Ok(())
+    }
+
+    /// Send every follower the given operation, which is about to be applied
+    /// to a map that currently looks like `target` and published as
+    /// `generation`. A follower whose stream has failed, or that is a full
+    /// buffer of writes behind, is dropped.
+    pub(crate) fn send<M>(
+        &mut self,
+        op: &Operation<V>,
+        target: &Inner<ManuallyDrop<V>, M>,
+        generation: u64,
+    ) {
+        let record = match encode_record(op, target, generation, self.encode) {
+            Ok(Some(body)) => frame(&body),
+            Ok(None) => return,
+            Err(_) => {
+                // the followers can't follow a write they never get
+                self.streams.clear();
+                return;
+            }
+        };
+
+        self.streams
+            .retain(|stream| stream.try_send(record.clone()).is_ok());
+    }
+}
+
+/// A copy of a map that is kept identical to a leader's, usually in another
+/// process, by applying the writes the leader streams to it.
+///
+/// The leader starts streaming with
+/// [`WriteHandle::replicate_to`](crate::WriteHandle::replicate_to), which
+/// sends a snapshot of its map followed by every write it publishes. Each
+/// write is applied with the key and generation it had on the leader, so
+/// keys handed out by the leader resolve to the same values here.
+///
+/// This derefs to a [`ReadHandle`] for the follower's copy of the map.
+pub struct Follower<R, K, P, V, M = ()>
+where
+    K: Key<P>,
+    V: ShallowCopy,
+    M: ShallowCopy,
+{
+    stream: BufReader<R>,
+    handle: WriteHandle<K, P, V, M>,
+    applied: usize,
+}
+
+impl<R, K, P, V, M> fmt::Debug for Follower<R, K, P, V, M>
+where
+    K: fmt::Debug + Key<P>,
+    V: fmt::Debug + ShallowCopy,
+    M: fmt::Debug + ShallowCopy,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Follower")
+            .field("handle", &self.handle)
+            .field("applied", &self.applied)
+            .finish()
+    }
+}
+
+impl<R, K, P, V, M> Follower<R, K, P, V, M>
+where
+    R: Read,
+    K: Key<P>,
+    V: Decode + ShallowCopy,
+    M: Decode + ShallowCopy,
+{
+    /// Start following the leader at the other end of the given stream, by
+    /// loading the snapshot it sends first.
+    pub fn new(stream: R) -> Result<Self, SnapshotError> {
+        let mut stream = BufReader::new(stream);
+        let len = u64::decode(&mut stream)?;
+        let bytes = read_bytes(&mut stream, len as usize)?;
+        let (_, handle) = snapshot::load(&bytes[..])?;
+        wal::read_header(&mut stream)?;
+
+        Ok(Follower {
+            stream,
+            handle,
+            applied: 0,
+        })
+    }
+
+    /// Wait for the leader's next write and apply it. Returns `false`
+    /// instead once the leader has closed the stream.
+    pub fn apply_next(&mut self) -> Result<bool, SnapshotError> {
+        match read_record(&mut self.stream)? {
+            Some(body) => {
+                apply_record(&mut self.handle, &body, self.applied)?;
+                self.applied += 1;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Apply the leader's writes until it closes the stream.
+    pub fn run(&mut self) -> Result<(), SnapshotError> {
+        while self.apply_next()? {}
+        Ok(())
+    }
+}
+
+impl<R, K, P, V, M> Follower<R, K, P, V, M>
+where
+    K: Key<P>,
+    V: ShallowCopy,
+    M: ShallowCopy,
+{
+    /// Produce a new [`ReadHandle`] to the follower's copy of the map.
+    pub fn handle(&self) -> ReadHandle<K, P, V, M> {
+        (*self.handle).clone()
+    }
+
+    /// Stop following, and take over writing to the follower's copy of the
+    /// map, say once the leader is gone.
+    pub fn into_writer(self) -> WriteHandle<K, P, V, M> {
+        self.handle
+    }
+}
+
+impl<R, K, P, V, M> Deref for Follower<R, K, P, V, M>
+where
+    K: Key<P>,
+    V: ShallowCopy,
+    M: ShallowCopy,
+{
+    type Target = ReadHandle<K, P, V, M>;
+    fn deref(&self) -> &Self::Target {
+        &self.handle
+    }
+}
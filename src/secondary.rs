@@ -0,0 +1,313 @@
+use crate::write::WriteHandle;
+use crate::Operation;
+use crate::ShallowCopy;
+use one_way_slot_map::{SlotMapKey as Key, SlotMapKeyData};
+use std::any::Any;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{error, fmt};
+
+/// Source of ids for secondary maps. These are unique across all maps so that
+/// a secondary map can't be confused with one belonging to another map
+static NEXT_SECONDARY_ID: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn next_secondary_id() -> u64 {
+    NEXT_SECONDARY_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The values of one secondary map as stored in one copy of the primary map.
+///
+/// Like the primary values, the values in the two copies are shallow copies of
+/// each other, and a value is only dropped when it is removed from the second
+/// copy. What an update means depends on the store; for a plain secondary map
+/// it is the new value for the slot.
+pub(crate) trait SecondaryStore: Send + Sync {
+    /// Apply the given update to the given slot, only shallow copying values
+    /// in and never dropping anything
+    fn update_first(&mut self, key: SlotMapKeyData, update: &SecondaryValue);
+
+    /// Apply the given update to the given slot, taking ownership of its
+    /// values and dropping any values it replaces
+    fn update_second(&mut self, key: SlotMapKeyData, update: SecondaryValue);
+
+    /// Remove the value in the given slot, dropping it if `second` is set
+    fn remove(&mut self, key: &SlotMapKeyData, second: bool);
+
+    /// Remove all values, dropping them if `second` is set
+    fn clear(&mut self, second: bool);
+
+    fn as_any(&self) -> &dyn Any;
+}
+
+struct SecondaryData<W> {
+    values: HashMap<SlotMapKeyData, ManuallyDrop<W>>,
+}
+
+impl<W> SecondaryData<W>
+where
+    W: ShallowCopy + Send + Sync + 'static,
+{
+    fn create() -> Box<dyn SecondaryStore> {
+        Box::new(SecondaryData::<W> {
+            values: HashMap::new(),
+        })
+    }
+
+    fn unwrap_value(value: &SecondaryValue) -> &W {
+        value
+            .value
+            .downcast_ref()
+            .expect("Secondary value of the wrong type")
+    }
+}
+
+impl<W> SecondaryStore for SecondaryData<W>
+where
+    W: ShallowCopy + Send + Sync + 'static,
+{
+    fn update_first(&mut self, key: SlotMapKeyData, update: &SecondaryValue) {
+        let value = Self::unwrap_value(update);
+        let _ = self.values.insert(key, unsafe { value.shallow_copy() });
+    }
+
+    fn update_second(&mut self, key: SlotMapKeyData, update: SecondaryValue) {
+        let value = *update
+            .value
+            .downcast::<W>()
+            .expect("Secondary value of the wrong type");
+
+        if let Some(mut old) = self.values.insert(key, ManuallyDrop::new(value))
+        {
+            unsafe { ManuallyDrop::drop(&mut old) };
+        }
+    }
+
+    fn remove(&mut self, key: &SlotMapKeyData, second: bool) {
+        if let Some(mut old) = self.values.remove(key) {
+            if second {
+                unsafe { ManuallyDrop::drop(&mut old) };
+            }
+        }
+    }
+
+    fn clear(&mut self, second: bool) {
+        for (_, mut old) in self.values.drain() {
+            if second {
+                unsafe { ManuallyDrop::drop(&mut old) };
+            }
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// A value or update headed for a secondary map
+pub(crate) struct SecondaryValue {
+    pub(crate) id: u64,
+    pub(crate) value: Box<dyn Any + Send>,
+}
+
+impl SecondaryValue {
+    pub(crate) fn new<T>(id: u64, value: T) -> Self
+    where
+        T: Any + Send,
+    {
+        SecondaryValue {
+            id,
+            value: Box::new(value),
+        }
+    }
+}
+
+impl fmt::Debug for SecondaryValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecondaryValue")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+/// A pending change to a secondary map
+#[derive(Debug)]
+pub(crate) enum SecondaryOp {
+    /// Create the secondary map with this id
+    Create(u64, fn() -> Box<dyn SecondaryStore>),
+    /// Update the value for this key
+    Update(SlotMapKeyData, SecondaryValue),
+    /// Remove the value for this key
+    Remove(u64, SlotMapKeyData),
+}
+
+/// Look up the values of a secondary map in one copy of the primary map
+pub(crate) fn downcast_store<W>(
+    store: &dyn SecondaryStore,
+) -> Option<&HashMap<SlotMapKeyData, ManuallyDrop<W>>>
+where
+    W: 'static,
+{
+    store
+        .as_any()
+        .downcast_ref::<SecondaryData<W>>()
+        .map(|data| &data.values)
+}
+
+/// Error returned when an [`EvSecondaryMap`] is written through a
+/// [`WriteHandle`] for a map other than the one it was created for. Maps
+/// restored from a snapshot or log, or claimed after a crash, don't carry
+/// their secondary maps with them, so this also covers those.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ForeignSecondaryMap;
+
+impl fmt::Display for ForeignSecondaryMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "secondary map belongs to a different primary map")
+    }
+}
+
+impl error::Error for ForeignSecondaryMap {}
+
+/// A map from the keys of a primary map to values of another type, published
+/// together with the primary map.
+///
+/// Different subsystems can attach their own per-entry data to a map without
+/// owning the primary values. The secondary values live alongside the primary
+/// values in both copies of the map, so every write to either one is published
+/// by the same swap, and readers see both sides of
+/// [`MapReadRef::get_joined`] from the same version. When a key is removed
+/// from the primary map (or the primary map is cleared), the secondary value
+/// for that key is dropped too.
+///
+/// This is only a handle; all writes go through the [`WriteHandle`] that
+/// created it and reads through a [`MapReadRef`] for the same map.
+///
+/// [`MapReadRef`]: crate::MapReadRef
+/// [`MapReadRef::get_joined`]: crate::MapReadRef::get_joined
+pub struct EvSecondaryMap<K, W> {
+    pub(crate) id: u64,
+    _phantom: PhantomData<fn() -> (K, W)>,
+}
+
+impl<K, W> fmt::Debug for EvSecondaryMap<K, W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EvSecondaryMap")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl<K, W> Clone for EvSecondaryMap<K, W> {
+    fn clone(&self) -> Self {
+        EvSecondaryMap {
+            id: self.id,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<K, W> EvSecondaryMap<K, W>
+where
+    W: ShallowCopy + Send + Sync + 'static,
+{
+    /// Create a new, empty secondary map bound to the map written by `primary`.
+    pub fn new<P, V, M>(primary: &mut WriteHandle<K, P, V, M>) -> Self
+    where
+        K: Key<P>,
+        V: ShallowCopy,
+        M: ShallowCopy,
+    {
+        let id = next_secondary_id();
+
+        let _ = primary.refresh_with_operation(Operation::Secondary(
+            SecondaryOp::Create(id, SecondaryData::<W>::create),
+        ));
+
+        EvSecondaryMap {
+            id,
+            _phantom: PhantomData,
+        }
+    }
+
+    fn wrap(&self, value: W) -> SecondaryValue {
+        SecondaryValue::new(self.id, value)
+    }
+
+    /// Make sure this secondary map belongs to the map written by `primary`,
+    /// before anything is published
+    fn check<P, V, M>(
+        &self,
+        primary: &WriteHandle<K, P, V, M>,
+    ) -> Result<(), ForeignSecondaryMap>
+    where
+        K: Key<P>,
+        V: ShallowCopy,
+        M: ShallowCopy,
+    {
+        if primary.has_secondary(self.id) {
+            Ok(())
+        } else {
+            Err(ForeignSecondaryMap)
+        }
+    }
+
+    /// Set the secondary value for the given key. If the key is not present
+    /// in the primary map, nothing is stored.
+    pub fn insert<P, V, M>(
+        &self,
+        primary: &mut WriteHandle<K, P, V, M>,
+        key: &K,
+        value: W,
+    ) -> Result<(), ForeignSecondaryMap>
+    where
+        K: Key<P>,
+        V: ShallowCopy,
+        M: ShallowCopy,
+    {
+        self.check(primary)?;
+        let _ = primary.refresh_with_operation(Operation::Secondary(
+            SecondaryOp::Update(*key.borrow(), self.wrap(value)),
+        ));
+        Ok(())
+    }
+
+    /// Remove the secondary value for the given key, leaving the primary
+    /// value in place.
+    pub fn remove<P, V, M>(
+        &self,
+        primary: &mut WriteHandle<K, P, V, M>,
+        key: &K,
+    ) -> Result<(), ForeignSecondaryMap>
+    where
+        K: Key<P>,
+        V: ShallowCopy,
+        M: ShallowCopy,
+    {
+        self.check(primary)?;
+        let _ = primary.refresh_with_operation(Operation::Secondary(
+            SecondaryOp::Remove(self.id, *key.borrow()),
+        ));
+        Ok(())
+    }
+
+    /// Insert a value into the primary map together with its secondary value,
+    /// so both become visible in the same publish. Returns the new key.
+    pub fn insert_with<P, V, M>(
+        &self,
+        primary: &mut WriteHandle<K, P, V, M>,
+        p: P,
+        v: V,
+        value: W,
+    ) -> Result<K, ForeignSecondaryMap>
+    where
+        K: Key<P>,
+        V: ShallowCopy,
+        M: ShallowCopy,
+    {
+        self.check(primary)?;
+        Ok(primary
+            .insert_operation(p, Operation::AddJoined(v, self.wrap(value))))
+    }
+}
@@ -0,0 +1,202 @@
+use crate::inner::Inner;
+use crate::read::{MapReadRef, ReadHandle};
+use crate::slot_table::{Entry, Slot, SlotTable};
+use crate::write::WriteHandle;
+use crate::ShallowCopy;
+use one_way_slot_map::{SlotMapKey as Key, SlotMapKeyData};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Borrow;
+use std::fmt;
+use std::mem::ManuallyDrop;
+use std::sync::Arc;
+
+/// One slot as it's written out. Slots are written in order, so a slot's
+/// position in the list is its index.
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Slot")]
+enum SlotRepr<V> {
+    Occupied {
+        generation: u32,
+        value: V,
+    },
+    Vacant {
+        generation: u32,
+        next: Option<usize>,
+    },
+    Unused {
+        generation: u32,
+        next: Option<usize>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "EvSlotMap")]
+struct MapRepr<V, M> {
+    meta: M,
+    free_head: Option<usize>,
+    slots: Vec<SlotRepr<V>>,
+}
+
+/// Writes the map's slots with their generations and the layout of its free
+/// list, so that deserializing it into a [`SerializedMap`] and building a new
+/// map from that hands out and resolves keys exactly like this one does.
+impl<'rh, K, P, V, M> Serialize for MapReadRef<'rh, K, P, V, M>
+where
+    K: Key<P>,
+    V: Serialize,
+    M: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let inner = self.inner();
+        let (slots, free_head) = inner.data.layout();
+        MapRepr {
+            meta: &*inner.meta,
+            free_head,
+            slots: slots
+                .iter()
+                .map(|slot| {
+                    let generation = slot.generation;
+                    match &slot.entry {
+                        Entry::Occupied(value) => SlotRepr::Occupied {
+                            generation,
+                            value: &**value,
+                        },
+                        Entry::Vacant(next) => SlotRepr::Vacant {
+                            generation,
+                            next: *next,
+                        },
+                        Entry::Unused(next) => SlotRepr::Unused {
+                            generation,
+                            next: *next,
+                        },
+                    }
+                })
+                .collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// The contents of a serialized [`MapReadRef`], ready to become a new map
+/// with [`new_from_serialized`](crate::new_from_serialized).
+pub struct SerializedMap<V, M = ()> {
+    data: SlotTable<V>,
+    meta: M,
+}
+
+impl<V, M> fmt::Debug for SerializedMap<V, M>
+where
+    V: fmt::Debug,
+    M: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SerializedMap")
+            .field("data", &self.data)
+            .field("meta", &self.meta)
+            .finish()
+    }
+}
+
+impl<V, M> SerializedMap<V, M> {
+    /// Returns the number of values in the map.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns true if the map holds no values.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Returns the map's metadata.
+    pub fn meta(&self) -> &M {
+        &self.meta
+    }
+}
+
+impl<'de, V, M> Deserialize<'de> for SerializedMap<V, M>
+where
+    V: Deserialize<'de>,
+    M: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let repr = MapRepr::<V, M>::deserialize(deserializer)?;
+        let slots = repr
+            .slots
+            .into_iter()
+            .map(|slot| match slot {
+                SlotRepr::Occupied { generation, value } => Slot {
+                    generation,
+                    entry: Entry::Occupied(value),
+                },
+                SlotRepr::Vacant { generation, next } => Slot {
+                    generation,
+                    entry: Entry::Vacant(next),
+                },
+                SlotRepr::Unused { generation, next } => Slot {
+                    generation,
+                    entry: Entry::Unused(next),
+                },
+            })
+            .collect();
+
+        Ok(SerializedMap {
+            data: SlotTable::from_layout(slots, repr.free_head)
+                .map_err(D::Error::custom)?,
+            meta: repr.meta,
+        })
+    }
+}
+
+pub(crate) fn new<K, P, V, M>(
+    map: SerializedMap<V, M>,
+) -> (ReadHandle<K, P, V, M>, WriteHandle<K, P, V, M>)
+where
+    K: Key<P>,
+    V: ShallowCopy,
+    M: ShallowCopy,
+{
+    let epochs = Default::default();
+    let (inner_r, inner_w) =
+        Inner::from_table(map.data.into_map(ManuallyDrop::new), map.meta);
+
+    let r = crate::read::new(inner_r, Arc::clone(&epochs));
+    let w = crate::write::new(inner_w, epochs, r.clone());
+    (r, w)
+}
+
+/// Serialize and deserialize keys as their raw `u64` key data, for use with
+/// `#[serde(with = "ev_slotmap::serde_key")]`.
+///
+/// Only keys without a pointer type (`define_key_type!(Key<()>)`) can be
+/// deserialized this way. Keys with a pointer can be rebuilt with
+/// `K::from((pointer, SlotMapKeyData::from(raw)))`.
+pub mod serde_key {
+    use super::*;
+
+    /// Serialize the key as its raw key data
+    pub fn serialize<K, S>(key: &K, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Borrow<SlotMapKeyData>,
+        S: Serializer,
+    {
+        serializer.serialize_u64(u64::from(*key.borrow()))
+    }
+
+    /// Deserialize a key from its raw key data
+    pub fn deserialize<'de, K, D>(deserializer: D) -> Result<K, D::Error>
+    where
+        K: From<((), SlotMapKeyData)>,
+        D: Deserializer<'de>,
+    {
+        let raw = u64::deserialize(deserializer)?;
+        Ok(K::from(((), SlotMapKeyData::from(raw))))
+    }
+}
@@ -0,0 +1,244 @@
+// This is evmap's `ShallowCopy` trait and its impls, carried over so that
+// users of this crate don't need to depend on evmap to store their own types.
+
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+#[cfg(feature = "evmap")]
+use std::ops::Deref;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Types that implement this trait can be cheaply copied by (potentially)
+/// aliasing the data they contain. Only the _last_ shallow copy will be
+/// dropped -- all others will be silently leaked (with `mem::forget`).
+///
+/// Every value stored in an ev slotmap is kept in both copies of the map, so
+/// it has to be shallow copyable.
+///
+/// For structs and enums made up of types that all implement `ShallowCopy`,
+/// the trait can be derived:
+///
+/// ```rust
+/// use ev_slotmap::ShallowCopy;
+/// use std::sync::Arc;
+///
+/// #[derive(ShallowCopy)]
+/// struct Topic {
+///     name: String,
+///     subscribers: Vec<u64>,
+///     config: Arc<Vec<u8>>,
+/// }
+/// ```
+///
+/// To implement this trait for your own `Copy` type, write:
+///
+/// ```rust
+/// use ev_slotmap::ShallowCopy;
+/// use std::mem::ManuallyDrop;
+///
+/// #[derive(Copy, Clone)]
+/// struct T;
+///
+/// impl ShallowCopy for T {
+///     unsafe fn shallow_copy(&self) -> ManuallyDrop<Self> {
+///         ManuallyDrop::new(*self)
+///     }
+/// }
+/// ```
+///
+/// If you have a non-`Copy` type, the value returned by `shallow_copy` should
+/// point to the same data as the `&self`, and it should be safe to
+/// `mem::forget` either of the copies as long as the other is dropped normally
+/// afterwards.
+///
+/// For complex, non-`Copy` types, you can place the type behind a wrapper that
+/// implements `ShallowCopy` such as `Box` or `Arc`.
+///
+/// This is a different trait from `evmap::ShallowCopy`, which earlier
+/// versions of this crate used, so types that only implement evmap's trait
+/// can't be stored as they are. Derive this trait for them instead, or, with
+/// the `evmap` feature, wrap them in `EvmapValue`.
+pub trait ShallowCopy {
+    /// Perform an aliasing copy of this value.
+    ///
+    /// # Safety
+    ///
+    /// The use of this method is *only* safe if the values involved are never
+    /// mutated, and only one of the copies is dropped; the remaining copies
+    /// must be forgotten with `mem::forget`.
+    unsafe fn shallow_copy(&self) -> ManuallyDrop<Self>;
+}
+
+impl<T> ShallowCopy for Arc<T>
+where
+    T: ?Sized,
+{
+    unsafe fn shallow_copy(&self) -> ManuallyDrop<Self> {
+        ManuallyDrop::new(Arc::from_raw(&**self as *const _))
+    }
+}
+
+impl<T> ShallowCopy for Rc<T>
+where
+    T: ?Sized,
+{
+    unsafe fn shallow_copy(&self) -> ManuallyDrop<Self> {
+        ManuallyDrop::new(Rc::from_raw(&**self as *const _))
+    }
+}
+
+impl<T> ShallowCopy for Box<T>
+where
+    T: ?Sized,
+{
+    unsafe fn shallow_copy(&self) -> ManuallyDrop<Self> {
+        ManuallyDrop::new(Box::from_raw(&**self as *const _ as *mut _))
+    }
+}
+
+impl<T> ShallowCopy for Option<T>
+where
+    T: ShallowCopy,
+{
+    unsafe fn shallow_copy(&self) -> ManuallyDrop<Self> {
+        ManuallyDrop::new(
+            self.as_ref()
+                .map(|value| ManuallyDrop::into_inner(value.shallow_copy())),
+        )
+    }
+}
+
+impl ShallowCopy for String {
+    unsafe fn shallow_copy(&self) -> ManuallyDrop<Self> {
+        let buf = self.as_bytes().as_ptr();
+        let len = self.len();
+        let cap = self.capacity();
+        ManuallyDrop::new(String::from_raw_parts(buf as *mut _, len, cap))
+    }
+}
+
+impl<T> ShallowCopy for Vec<T> {
+    unsafe fn shallow_copy(&self) -> ManuallyDrop<Self> {
+        let ptr = self.as_ptr() as *mut _;
+        let len = self.len();
+        let cap = self.capacity();
+        ManuallyDrop::new(Vec::from_raw_parts(ptr, len, cap))
+    }
+}
+
+impl<T> ShallowCopy for &T
+where
+    T: ?Sized,
+{
+    unsafe fn shallow_copy(&self) -> ManuallyDrop<Self> {
+        ManuallyDrop::new(*self)
+    }
+}
+
+impl<T> ShallowCopy for PhantomData<T>
+where
+    T: ?Sized,
+{
+    unsafe fn shallow_copy(&self) -> ManuallyDrop<Self> {
+        ManuallyDrop::new(PhantomData)
+    }
+}
+
+/// A value that implements evmap's `ShallowCopy` trait, stored through that
+/// trait's implementation. Only available with the `evmap` feature.
+///
+/// This is for types moving over from evmap, or from earlier versions of this
+/// crate, that implement `evmap::ShallowCopy` by hand. There is no blanket
+/// implementation of this crate's trait for every `evmap::ShallowCopy` type,
+/// since it would overlap with the implementations above for std types.
+///
+/// ```rust
+/// use ev_slotmap::EvmapValue;
+///
+/// #[derive(Clone, Copy)]
+/// struct Legacy {
+///     id: u32,
+/// }
+///
+/// impl evmap::ShallowCopy for Legacy {
+///     unsafe fn shallow_copy(&self) -> std::mem::ManuallyDrop<Self> {
+///         std::mem::ManuallyDrop::new(*self)
+///     }
+/// }
+///
+/// one_way_slot_map::define_key_type!(Key<()>);
+///
+/// let (r, mut w) = ev_slotmap::new::<Key, (), EvmapValue<Legacy>>();
+/// let key = w.insert((), EvmapValue(Legacy { id: 7 }));
+/// assert_eq!(r.get(&key).map(|v| v.id), Some(7));
+/// ```
+#[cfg(feature = "evmap")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EvmapValue<T>(pub T);
+
+#[cfg(feature = "evmap")]
+impl<T> ShallowCopy for EvmapValue<T>
+where
+    T: evmap::ShallowCopy,
+{
+    unsafe fn shallow_copy(&self) -> ManuallyDrop<Self> {
+        let copy = evmap::ShallowCopy::shallow_copy(&self.0);
+        ManuallyDrop::new(EvmapValue(ManuallyDrop::into_inner(copy)))
+    }
+}
+
+#[cfg(feature = "evmap")]
+impl<T> Deref for EvmapValue<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[cfg(feature = "evmap")]
+impl<T> From<T> for EvmapValue<T> {
+    fn from(value: T) -> Self {
+        EvmapValue(value)
+    }
+}
+
+macro_rules! impl_shallow_copy_for_copy_primitives {
+    ($($t:ty)*) => ($(
+        impl ShallowCopy for $t {
+            unsafe fn shallow_copy(&self) -> ManuallyDrop<Self> {
+                ManuallyDrop::new(*self)
+            }
+        }
+    )*)
+}
+
+impl_shallow_copy_for_copy_primitives!(() bool char usize u8 u16 u32 u64 u128 isize i8 i16 i32 i64 i128 f32 f64);
+
+macro_rules! impl_shallow_copy_for_tuples {
+    ($(($($idx:tt $T:ident)+))+) => {
+        $(
+            impl<$($T: ShallowCopy),+> ShallowCopy for ($($T,)+) {
+                unsafe fn shallow_copy(&self) -> ManuallyDrop<Self> {
+                    ManuallyDrop::new((
+                        $(ManuallyDrop::into_inner(self.$idx.shallow_copy()),)+
+                    ))
+                }
+            }
+        )+
+    }
+}
+
+impl_shallow_copy_for_tuples! {
+    (0 A)
+    (0 A 1 B)
+    (0 A 1 B 2 C)
+    (0 A 1 B 2 C 3 D)
+    (0 A 1 B 2 C 3 D 4 E)
+    (0 A 1 B 2 C 3 D 4 E 5 F)
+    (0 A 1 B 2 C 3 D 4 E 5 F 6 G)
+    (0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H)
+    (0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H 8 I)
+    (0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H 8 I 9 J)
+    (0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H 8 I 9 J 10 K)
+    (0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H 8 I 9 J 10 K 11 L)
+}
@@ -0,0 +1,160 @@
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
+
+/// Per-map record of the last published generation that readers can block on.
+///
+/// The writer bumps this after every swap, and wakes anyone waiting for the
+/// map to reach a particular generation. Once the writer is dropped the signal
+/// is closed and all waiters are released.
+#[derive(Default)]
+pub(crate) struct PublishSignal {
+    generation: AtomicU64,
+    state: Mutex<SignalState>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct SignalState {
+    closed: bool,
+    wakers: Vec<Waker>,
+}
+
+impl fmt::Debug for PublishSignal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PublishSignal")
+            .field("generation", &self.generation)
+            .finish()
+    }
+}
+
+impl PublishSignal {
+    pub(crate) fn new(generation: u64) -> Self {
+        PublishSignal {
+            generation: AtomicU64::new(generation),
+            ..Default::default()
+        }
+    }
+
+    /// Record that the given generation is now visible to readers, and wake
+    /// everyone waiting on it
+    pub(crate) fn publish(&self, generation: u64) {
+        self.generation.store(generation, Ordering::Release);
+        self.wake_all(false);
+    }
+
+    /// Mark the map as destroyed, which releases all current and future
+    /// waiters
+    pub(crate) fn close(&self) {
+        self.wake_all(true);
+    }
+
+    /// A new writer has taken over the map, so waiters block again until it
+    /// publishes
+    pub(crate) fn reopen(&self) {
+        self.state.lock().unwrap().closed = false;
+    }
+
+    fn wake_all(&self, close: bool) {
+        // take the lock even when there are no waiters, so a waiter that has
+        // just checked the generation can't miss this wake up
+        let mut state = self.state.lock().unwrap();
+        state.closed |= close;
+        let wakers = std::mem::take(&mut state.wakers);
+        drop(state);
+
+        self.cond.notify_all();
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    fn reached(&self, generation: u64) -> bool {
+        self.generation.load(Ordering::Acquire) >= generation
+    }
+
+    /// Block until the given generation is reached, the map is destroyed, or
+    /// the optional deadline passes
+    pub(crate) fn wait(
+        &self,
+        generation: u64,
+        deadline: Option<Instant>,
+    ) -> bool {
+        if self.reached(generation) {
+            return true;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if self.reached(generation) {
+                return true;
+            }
+            if state.closed {
+                return false;
+            }
+
+            state = match deadline {
+                None => self.cond.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.cond.wait_timeout(state, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    fn poll_wait(&self, generation: u64, cx: &mut Context<'_>) -> Poll<bool> {
+        if self.reached(generation) {
+            return Poll::Ready(true);
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        if self.reached(generation) {
+            return Poll::Ready(true);
+        }
+        if state.closed {
+            return Poll::Ready(false);
+        }
+
+        if !state.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            state.wakers.push(cx.waker().clone());
+        }
+
+        Poll::Pending
+    }
+}
+
+/// Future returned by [`ReadHandle::wait_for_generation_async`].
+///
+/// Resolves to `true` once the map has published at least the requested
+/// generation, or `false` if the writer is dropped before that happens.
+///
+/// [`ReadHandle::wait_for_generation_async`]: crate::ReadHandle::wait_for_generation_async
+#[must_use = "futures do nothing unless polled"]
+pub struct WaitForGeneration {
+    pub(crate) signal: Arc<PublishSignal>,
+    pub(crate) generation: u64,
+}
+
+impl fmt::Debug for WaitForGeneration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WaitForGeneration")
+            .field("generation", &self.generation)
+            .finish()
+    }
+}
+
+impl Future for WaitForGeneration {
+    type Output = bool;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<bool> {
+        self.signal.poll_wait(self.generation, cx)
+    }
+}
@@ -0,0 +1,605 @@
+use one_way_slot_map::SlotMapKeyData;
+use std::{error, fmt, mem};
+
+/// Number of low bits in raw key data that locate the slot. This matches the
+/// layout of one_way_slot_map's key data, an 8 bit index into a 256 slot
+/// chunk followed by a 32 bit chunk index, so a slot's position in the flat
+/// table is exactly the low bits of its key.
+const INDEX_BITS: u32 = 40;
+const INDEX_MASK: u64 = (1 << INDEX_BITS) - 1;
+const MAX_GENERATION: u32 = (1 << (64 - INDEX_BITS)) - 1;
+
+/// How many slots past the end of the table `insert_at` may skip over
+pub(crate) const MAX_INSERT_GAP: usize = 1 << 16;
+
+/// Get the position in the table of the slot the given key points to
+pub(crate) fn index_of(key: &SlotMapKeyData) -> usize {
+    (u64::from(*key) & INDEX_MASK) as usize
+}
+
+/// Get the generation the given key expects its slot to be at
+pub(crate) fn generation_of(key: &SlotMapKeyData) -> u32 {
+    (u64::from(*key) >> INDEX_BITS) as u32
+}
+
+/// Build the key for the given slot at the given generation
+pub(crate) fn key_at(index: usize, generation: u32) -> SlotMapKeyData {
+    SlotMapKeyData::from(
+        (index as u64 & INDEX_MASK) | (u64::from(generation) << INDEX_BITS),
+    )
+}
+
+/// Generations are even while a slot is filled and odd while it is empty, and
+/// wrap around at the same point as one_way_slot_map's
+fn next_generation(generation: u32) -> u32 {
+    if generation == MAX_GENERATION {
+        0
+    } else {
+        generation + 1
+    }
+}
+
+/// Get the generation a slot was at before it moved to the given one
+pub(crate) fn previous_generation(generation: u32) -> u32 {
+    if generation == 0 {
+        MAX_GENERATION
+    } else {
+        generation - 1
+    }
+}
+
+/// What a lookup found for a key, as returned by
+/// [`ReadHandle::lookup_status`].
+///
+/// [`ReadHandle::lookup_status`]: crate::ReadHandle::lookup_status
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyStatus {
+    /// The key points to a value in the map.
+    Live,
+    /// The key's slot exists, but has been emptied or reused since the key
+    /// was handed out.
+    Stale {
+        /// The generation the slot is at now. This is even while the slot
+        /// holds a value and odd while it is empty.
+        current_generation: u32,
+    },
+    /// The key points past the end of the map, or to a slot that has never
+    /// held a value, so it was never handed out by this map.
+    OutOfRange,
+    /// Nothing has been published to the map yet.
+    MapUninitialized,
+    /// The map's write handle has been dropped.
+    MapDestroyed,
+}
+
+/// Error returned by [`WriteHandle::insert_at`] when the map can't take a
+/// value under the given key.
+///
+/// [`WriteHandle::insert_at`]: crate::WriteHandle::insert_at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InsertAtError {
+    /// The slot the key points to is filled, or has already been used at or
+    /// past the key's generation.
+    Occupied {
+        /// The generation the slot is at now. This is even while the slot
+        /// holds a value and odd while it is empty.
+        current_generation: u32,
+    },
+    /// The key points more than 65536 slots past the end of the map.
+    TooFarPastEnd {
+        /// The number of slots the map has, empty ones included.
+        slot_count: usize,
+    },
+}
+
+impl fmt::Display for InsertAtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InsertAtError::Occupied { current_generation } => write!(
+                f,
+                "slot is already at generation {}",
+                current_generation
+            ),
+            InsertAtError::TooFarPastEnd { slot_count } => write!(
+                f,
+                "slot is too far past the end of the {} slots in the map",
+                slot_count
+            ),
+        }
+    }
+}
+
+impl error::Error for InsertAtError {}
+
+pub(crate) enum Entry<T> {
+    Occupied(T),
+    /// An empty slot, pointing at the next empty slot to fill after this one
+    Vacant(Option<usize>),
+    /// An empty slot that has never held a value, because `insert_at` skipped
+    /// over it. It's on the free list like a vacant slot, but any key into it
+    /// can be filled, since none was ever handed out. Its generation is
+    /// always the last one, so filling it from the free list hands out
+    /// generation 0
+    Unused(Option<usize>),
+}
+
+pub(crate) struct Slot<T> {
+    pub(crate) generation: u32,
+    pub(crate) entry: Entry<T>,
+}
+
+/// Flat slot storage behind each copy of the map.
+///
+/// All slots are kept in a single vector so that the copies can be grown and
+/// shrunk on demand. Empty slots are reused last-in-first-out, and new slots
+/// are only added once there are no empty ones left, which is the order a
+/// `one_way_slot_map::SlotMap` hands keys out in. The order keys are handed
+/// out in only depends on the operations applied, and is documented on
+/// `WriteHandle::insert`, so changes to it are breaking.
+pub(crate) struct SlotTable<T> {
+    slots: Vec<Slot<T>>,
+    free_head: Option<usize>,
+    len: usize,
+}
+
+impl<T> Default for SlotTable<T> {
+    fn default() -> Self {
+        SlotTable::new()
+    }
+}
+
+impl<T> fmt::Debug for SlotTable<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.values()).finish()
+    }
+}
+
+impl<T> SlotTable<T> {
+    pub(crate) fn new() -> Self {
+        SlotTable::with_capacity(0)
+    }
+
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        SlotTable {
+            slots: Vec::with_capacity(capacity),
+            free_head: None,
+            len: 0,
+        }
+    }
+
+    /// Build a table holding the given values at the given keys, whose other
+    /// slots are the given empty ones, in the order they are to be filled.
+    /// Each empty slot's key has the generation the slot was left at. Any
+    /// slot below the last one that isn't given is left unused, and filled
+    /// after the empty ones, lowest first.
+    pub(crate) fn from_parts(
+        occupied: impl IntoIterator<Item = (SlotMapKeyData, T)>,
+        vacant: &[SlotMapKeyData],
+    ) -> Self {
+        let mut slots: Vec<Option<Slot<T>>> = Vec::new();
+        let mut place = |key: &SlotMapKeyData, slot| {
+            let index = index_of(key);
+            if index >= slots.len() {
+                slots.resize_with(index + 1, || None);
+            }
+            slots[index] = Some(slot);
+        };
+
+        let mut len = 0;
+        for (key, value) in occupied {
+            let slot = Slot {
+                generation: generation_of(&key),
+                entry: Entry::Occupied(value),
+            };
+            place(&key, slot);
+            len += 1;
+        }
+        for key in vacant {
+            let slot = Slot {
+                generation: generation_of(key),
+                entry: Entry::Vacant(None),
+            };
+            place(key, slot);
+        }
+
+        let unused: Vec<usize> = (0..slots.len())
+            .filter(|index| slots[*index].is_none())
+            .collect();
+        let free: Vec<usize> = vacant
+            .iter()
+            .map(index_of)
+            .chain(unused.iter().copied())
+            .collect();
+        for (position, index) in free.iter().enumerate() {
+            let next = free.get(position + 1).copied();
+            let slot = slots[*index].get_or_insert(Slot {
+                generation: MAX_GENERATION,
+                entry: Entry::Unused(None),
+            });
+            slot.entry = match slot.entry {
+                Entry::Unused(_) => Entry::Unused(next),
+                _ => Entry::Vacant(next),
+            };
+        }
+
+        SlotTable {
+            slots: slots.into_iter().flatten().collect(),
+            free_head: free.first().copied(),
+            len,
+        }
+    }
+
+    /// Rebuild a table from the slots and free list head of another one, as
+    /// handed out by `layout`. The layout is checked, so keys into the old
+    /// table resolve exactly the same way in the new one.
+    pub(crate) fn from_layout(
+        slots: Vec<Slot<T>>,
+        free_head: Option<usize>,
+    ) -> Result<Self, String> {
+        let mut len = 0;
+        for (index, slot) in slots.iter().enumerate() {
+            let filled = matches!(slot.entry, Entry::Occupied(_));
+            let unused = matches!(slot.entry, Entry::Unused(_));
+            if slot.generation > MAX_GENERATION
+                || (slot.generation % 2 == 0) != filled
+                || (unused && slot.generation != MAX_GENERATION)
+            {
+                return Err(format!(
+                    "Slot {} has invalid generation {}",
+                    index, slot.generation
+                ));
+            }
+            if filled {
+                len += 1;
+            }
+        }
+
+        // the free list has to visit every vacant slot exactly once
+        let vacant = slots.len() - len;
+        let mut visited = 0;
+        let mut next = free_head;
+        while let Some(index) = next {
+            next = match slots.get(index).map(|slot| &slot.entry) {
+                Some(Entry::Vacant(after) | Entry::Unused(after))
+                    if visited < vacant =>
+                {
+                    *after
+                }
+                _ => {
+                    return Err(format!(
+                        "Free list is broken at slot {}",
+                        index
+                    ))
+                }
+            };
+            visited += 1;
+        }
+        if visited != vacant {
+            return Err(format!(
+                "Free list reaches {} of {} empty slots",
+                visited, vacant
+            ));
+        }
+
+        Ok(SlotTable {
+            slots,
+            free_head,
+            len,
+        })
+    }
+
+    /// Get the slots of this table and the head of its free list
+    pub(crate) fn layout(&self) -> (&[Slot<T>], Option<usize>) {
+        (&self.slots, self.free_head)
+    }
+
+    /// Returns the number of filled slots
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of slots that can exist without reallocating
+    pub(crate) fn capacity(&self) -> usize {
+        self.slots.capacity()
+    }
+
+    /// Returns the number of empty slots waiting to be reused
+    pub(crate) fn vacant(&self) -> usize {
+        self.slots.len() - self.len
+    }
+
+    /// Returns the number of bytes allocated for slots
+    pub(crate) fn heap_bytes(&self) -> usize {
+        self.slots.capacity() * mem::size_of::<Slot<T>>()
+    }
+
+    /// Make sure `additional` more values can be inserted without
+    /// reallocating
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        self.slots.reserve(additional.saturating_sub(self.vacant()));
+    }
+
+    /// Release any memory not needed for the existing slots. Empty slots are
+    /// kept, since their generations are what keep old keys from resolving to
+    /// new values.
+    pub(crate) fn shrink_to_fit(&mut self) {
+        self.slots.shrink_to_fit();
+    }
+
+    /// Returns the key the next insert will hand out
+    pub(crate) fn next_key(&self) -> SlotMapKeyData {
+        match self.free_head {
+            Some(index) => {
+                key_at(index, next_generation(self.slots[index].generation))
+            }
+            None => key_at(self.slots.len(), 0),
+        }
+    }
+
+    /// Store the given value in the next empty slot and return its key
+    pub(crate) fn insert(&mut self, value: T) -> SlotMapKeyData {
+        self.len += 1;
+
+        if let Some(index) = self.free_head {
+            let slot = &mut self.slots[index];
+            match mem::replace(&mut slot.entry, Entry::Occupied(value)) {
+                Entry::Vacant(next) | Entry::Unused(next) => {
+                    self.free_head = next
+                }
+                _ => unreachable!("Free list points at a non-empty slot"),
+            }
+            slot.generation = next_generation(slot.generation);
+            key_at(index, slot.generation)
+        } else {
+            self.slots.push(Slot {
+                generation: 0,
+                entry: Entry::Occupied(value),
+            });
+            key_at(self.slots.len() - 1, 0)
+        }
+    }
+
+    /// Check that the slot the given key points to can take a value under
+    /// that key
+    pub(crate) fn check_vacant(
+        &self,
+        key: &SlotMapKeyData,
+    ) -> Result<(), InsertAtError> {
+        match self.slots.get(index_of(key)) {
+            Some(Slot {
+                entry: Entry::Unused(_),
+                ..
+            }) => Ok(()),
+            Some(Slot {
+                generation,
+                entry: Entry::Vacant(_),
+            }) if generation_of(key) > *generation => Ok(()),
+            Some(slot) => Err(InsertAtError::Occupied {
+                current_generation: slot.generation,
+            }),
+            None if index_of(key) - self.slots.len() > MAX_INSERT_GAP => {
+                Err(InsertAtError::TooFarPastEnd {
+                    slot_count: self.slots.len(),
+                })
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Store the given value under the given key, whose generation has to be
+    /// even. Gives the value back if the slot can't take it.
+    pub(crate) fn insert_at(
+        &mut self,
+        key: &SlotMapKeyData,
+        value: T,
+    ) -> Result<(), T> {
+        if self.check_vacant(key).is_err() {
+            return Err(value);
+        }
+        let index = index_of(key);
+        let slot = Slot {
+            generation: generation_of(key),
+            entry: Entry::Occupied(value),
+        };
+
+        if index < self.slots.len() {
+            // take the slot out of the free list
+            let after = match self.slots[index].entry {
+                Entry::Vacant(after) | Entry::Unused(after) => after,
+                _ => unreachable!("Inserting into a non-empty slot"),
+            };
+            let mut next = &mut self.free_head;
+            while let Some(at) = *next {
+                if at == index {
+                    *next = after;
+                    break;
+                }
+                next = match &mut self.slots[at].entry {
+                    Entry::Vacant(next) | Entry::Unused(next) => next,
+                    _ => unreachable!("Free list points at a non-empty slot"),
+                };
+            }
+            self.slots[index] = slot;
+        } else {
+            // the slots skipped over are left unused and go on the free list
+            // lowest first
+            let first = self.slots.len();
+            for gap in first..index {
+                self.slots.push(Slot {
+                    generation: MAX_GENERATION,
+                    entry: Entry::Unused(Some(gap + 1)),
+                });
+            }
+            if first < index {
+                self.slots[index - 1].entry = Entry::Unused(self.free_head);
+                self.free_head = Some(first);
+            }
+            self.slots.push(slot);
+        }
+
+        self.len += 1;
+        Ok(())
+    }
+
+    fn slot(&self, key: &SlotMapKeyData) -> Option<&Slot<T>> {
+        self.slots
+            .get(index_of(key))
+            .filter(|slot| slot.generation == generation_of(key))
+    }
+
+    pub(crate) fn get(&self, key: &SlotMapKeyData) -> Option<&T> {
+        match &self.slot(key)?.entry {
+            Entry::Occupied(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, key: &SlotMapKeyData) -> Option<&mut T> {
+        let slot = self
+            .slots
+            .get_mut(index_of(key))
+            .filter(|slot| slot.generation == generation_of(key))?;
+
+        match &mut slot.entry {
+            Entry::Occupied(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn contains_key(&self, key: &SlotMapKeyData) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Describe what the slot the given key points to holds
+    pub(crate) fn status(&self, key: &SlotMapKeyData) -> KeyStatus {
+        match self.slots.get(index_of(key)) {
+            None => KeyStatus::OutOfRange,
+            Some(slot) => match slot.entry {
+                Entry::Occupied(_) if slot.generation == generation_of(key) => {
+                    KeyStatus::Live
+                }
+                // the generation of an unused slot is made up, so it says
+                // nothing about the key
+                Entry::Unused(_) => KeyStatus::OutOfRange,
+                _ => KeyStatus::Stale {
+                    current_generation: slot.generation,
+                },
+            },
+        }
+    }
+
+    /// Empty the slot for the given key, and return the value it held
+    pub(crate) fn remove(&mut self, key: &SlotMapKeyData) -> Option<T> {
+        self.get(key)?;
+
+        let index = index_of(key);
+        let slot = &mut self.slots[index];
+        let old = mem::replace(&mut slot.entry, Entry::Vacant(self.free_head));
+        slot.generation = next_generation(slot.generation);
+        self.free_head = Some(index);
+        self.len -= 1;
+
+        match old {
+            Entry::Occupied(value) => Some(value),
+            _ => unreachable!("Removed a slot that wasn't filled"),
+        }
+    }
+
+    /// Empty every slot, handing each removed value to the given function.
+    /// The filled slots are emptied from the lowest index up, as if each one
+    /// was removed in turn, so the highest of them is refilled first and the
+    /// slots that were already empty keep their place after them.
+    pub(crate) fn clear_with(&mut self, mut on_removed: impl FnMut(T)) {
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if !matches!(slot.entry, Entry::Occupied(_)) {
+                continue;
+            }
+            match mem::replace(&mut slot.entry, Entry::Vacant(self.free_head)) {
+                Entry::Occupied(value) => on_removed(value),
+                _ => unreachable!("Cleared a slot that wasn't filled"),
+            }
+            slot.generation = next_generation(slot.generation);
+            self.free_head = Some(index);
+        }
+
+        self.len = 0;
+    }
+
+    /// Get an iterator over the keys and values of all filled slots
+    pub(crate) fn iter_raw(
+        &self,
+    ) -> impl Iterator<Item = (SlotMapKeyData, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            match &slot.entry {
+                Entry::Occupied(value) => {
+                    Some((key_at(index, slot.generation), value))
+                }
+                _ => None,
+            }
+        })
+    }
+
+    /// Get an iterator over the values of all filled slots
+    pub(crate) fn values(&self) -> impl Iterator<Item = &T> {
+        self.iter_raw().map(|(_, value)| value)
+    }
+
+    /// Create a table with exactly the same layout as this one, with each
+    /// value mapped through the given function
+    pub(crate) fn map<R>(
+        &self,
+        mut mapper: impl FnMut(&T) -> R,
+    ) -> SlotTable<R> {
+        SlotTable {
+            slots: self
+                .slots
+                .iter()
+                .map(|slot| Slot {
+                    generation: slot.generation,
+                    entry: match &slot.entry {
+                        Entry::Occupied(value) => {
+                            Entry::Occupied(mapper(value))
+                        }
+                        Entry::Vacant(next) => Entry::Vacant(*next),
+                        Entry::Unused(next) => Entry::Unused(*next),
+                    },
+                })
+                .collect(),
+            free_head: self.free_head,
+            len: self.len,
+        }
+    }
+
+    /// Like `map`, but moves each value out of this table
+    pub(crate) fn into_map<R>(
+        self,
+        mut mapper: impl FnMut(T) -> R,
+    ) -> SlotTable<R> {
+        SlotTable {
+            slots: self
+                .slots
+                .into_iter()
+                .map(|slot| Slot {
+                    generation: slot.generation,
+                    entry: match slot.entry {
+                        Entry::Occupied(value) => {
+                            Entry::Occupied(mapper(value))
+                        }
+                        Entry::Vacant(next) => Entry::Vacant(next),
+                        Entry::Unused(next) => Entry::Unused(next),
+                    },
+                })
+                .collect(),
+            free_head: self.free_head,
+            len: self.len,
+        }
+    }
+}
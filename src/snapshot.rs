@@ -0,0 +1,592 @@
+use crate::inner::Inner;
+use crate::read::ReadHandle;
+use crate::slot_table::{Entry, Slot, SlotTable};
+use crate::write::WriteHandle;
+use crate::ShallowCopy;
+use one_way_slot_map::SlotMapKey as Key;
+use std::convert::TryFrom;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem::ManuallyDrop;
+use std::sync::Arc;
+use std::{error, fmt};
+
+/// Every snapshot starts with these bytes
+const MAGIC: [u8; 8] = *b"EVSLOTMP";
+const VERSION: u32 = 3;
+
+/// Stands in for a missing slot index, since real ones never get this big
+const NO_SLOT: u64 = u64::MAX;
+
+const OCCUPIED: u8 = 0;
+const VACANT: u8 = 1;
+const UNUSED: u8 = 3;
+
+/// Values that can be written into a snapshot with
+/// [`ReadHandle::write_snapshot`].
+///
+/// Each value is written into its own length prefixed payload, so an
+/// encoding only needs to be readable by the matching [`Decode`] impl.
+///
+/// ```rust
+/// use ev_slotmap::{Decode, Encode};
+/// use std::io::{self, Read, Write};
+///
+/// struct Point {
+///     x: i32,
+///     y: i32,
+/// }
+///
+/// impl Encode for Point {
+///     fn encode(&self, writer: &mut dyn Write) -> io::Result<()> {
+///         self.x.encode(writer)?;
+///         self.y.encode(writer)
+///     }
+/// }
+///
+/// impl Decode for Point {
+///     fn decode(reader: &mut dyn Read) -> io::Result<Self> {
+///         Ok(Point {
+///             x: i32::decode(reader)?,
+///             y: i32::decode(reader)?,
+///         })
+///     }
+/// }
+/// ```
+pub trait Encode {
+    /// Write this value to the given writer
+    fn encode(&self, writer: &mut dyn Write) -> io::Result<()>;
+}
+
+/// Values that can be read back out of a snapshot with
+/// [`load_snapshot`](crate::load_snapshot). See [`Encode`].
+pub trait Decode: Sized {
+    /// Read a value written by the matching [`Encode`] impl
+    fn decode(reader: &mut dyn Read) -> io::Result<Self>;
+}
+
+macro_rules! impl_codec_for_numbers {
+    ($($t:ty)*) => ($(
+        impl Encode for $t {
+            fn encode(&self, writer: &mut dyn Write) -> io::Result<()> {
+                writer.write_all(&self.to_le_bytes())
+            }
+        }
+
+        impl Decode for $t {
+            fn decode(reader: &mut dyn Read) -> io::Result<Self> {
+                let mut bytes = [0; std::mem::size_of::<$t>()];
+                reader.read_exact(&mut bytes)?;
+                Ok(<$t>::from_le_bytes(bytes))
+            }
+        }
+    )*)
+}
+
+impl_codec_for_numbers!(u8 u16 u32 u64 u128 i8 i16 i32 i64 i128 f32 f64);
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// sizes are always written as 64 bits, so snapshots can move between
+// platforms
+
+impl Encode for usize {
+    fn encode(&self, writer: &mut dyn Write) -> io::Result<()> {
+        (*self as u64).encode(writer)
+    }
+}
+
+impl Decode for usize {
+    fn decode(reader: &mut dyn Read) -> io::Result<Self> {
+        usize::try_from(u64::decode(reader)?)
+            .map_err(|_| invalid_data("usize out of range"))
+    }
+}
+
+impl Encode for isize {
+    fn encode(&self, writer: &mut dyn Write) -> io::Result<()> {
+        (*self as i64).encode(writer)
+    }
+}
+
+impl Decode for isize {
+    fn decode(reader: &mut dyn Read) -> io::Result<Self> {
+        isize::try_from(i64::decode(reader)?)
+            .map_err(|_| invalid_data("isize out of range"))
+    }
+}
+
+impl Encode for () {
+    fn encode(&self, _: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Decode for () {
+    fn decode(_: &mut dyn Read) -> io::Result<Self> {
+        Ok(())
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, writer: &mut dyn Write) -> io::Result<()> {
+        (*self as u8).encode(writer)
+    }
+}
+
+impl Decode for bool {
+    fn decode(reader: &mut dyn Read) -> io::Result<Self> {
+        match u8::decode(reader)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid_data("invalid bool")),
+        }
+    }
+}
+
+impl Encode for char {
+    fn encode(&self, writer: &mut dyn Write) -> io::Result<()> {
+        u32::from(*self).encode(writer)
+    }
+}
+
+impl Decode for char {
+    fn decode(reader: &mut dyn Read) -> io::Result<Self> {
+        char::from_u32(u32::decode(reader)?)
+            .ok_or_else(|| invalid_data("invalid char"))
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, writer: &mut dyn Write) -> io::Result<()> {
+        self.len().encode(writer)?;
+        writer.write_all(self.as_bytes())
+    }
+}
+
+impl Decode for String {
+    fn decode(reader: &mut dyn Read) -> io::Result<Self> {
+        let len = usize::decode(reader)?;
+        let bytes = read_bytes(reader, len)?;
+        String::from_utf8(bytes).map_err(|_| invalid_data("invalid UTF-8"))
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, writer: &mut dyn Write) -> io::Result<()> {
+        self.len().encode(writer)?;
+        self.iter().try_for_each(|item| item.encode(writer))
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(reader: &mut dyn Read) -> io::Result<Self> {
+        // the length isn't trusted for allocating up front, since a corrupt
+        // one could be huge
+        (0..usize::decode(reader)?)
+            .map(|_| T::decode(reader))
+            .collect()
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, writer: &mut dyn Write) -> io::Result<()> {
+        match self {
+            None => false.encode(writer),
+            Some(value) => {
+                true.encode(writer)?;
+                value.encode(writer)
+            }
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(reader: &mut dyn Read) -> io::Result<Self> {
+        if bool::decode(reader)? {
+            Ok(Some(T::decode(reader)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for Box<T> {
+    fn encode(&self, writer: &mut dyn Write) -> io::Result<()> {
+        (**self).encode(writer)
+    }
+}
+
+impl<T: Decode> Decode for Box<T> {
+    fn decode(reader: &mut dyn Read) -> io::Result<Self> {
+        Ok(Box::new(T::decode(reader)?))
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for Arc<T> {
+    fn encode(&self, writer: &mut dyn Write) -> io::Result<()> {
+        (**self).encode(writer)
+    }
+}
+
+impl<T: Decode> Decode for Arc<T> {
+    fn decode(reader: &mut dyn Read) -> io::Result<Self> {
+        Ok(Arc::new(T::decode(reader)?))
+    }
+}
+
+macro_rules! impl_codec_for_tuples {
+    ($(($($idx:tt $T:ident)+))+) => {
+        $(
+            impl<$($T: Encode),+> Encode for ($($T,)+) {
+                fn encode(&self, writer: &mut dyn Write) -> io::Result<()> {
+                    $(self.$idx.encode(writer)?;)+
+                    Ok(())
+                }
+            }
+
+            impl<$($T: Decode),+> Decode for ($($T,)+) {
+                fn decode(reader: &mut dyn Read) -> io::Result<Self> {
+                    Ok(($($T::decode(reader)?,)+))
+                }
+            }
+        )+
+    }
+}
+
+impl_codec_for_tuples! {
+    (0 A)
+    (0 A 1 B)
+    (0 A 1 B 2 C)
+    (0 A 1 B 2 C 3 D)
+}
+
+/// Read exactly `len` bytes, without trusting `len` enough to allocate it
+/// all up front
+pub(crate) fn read_bytes(
+    reader: &mut dyn Read,
+    len: usize,
+) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    (&mut *reader).take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+/// Error returned when a snapshot or write-ahead log can't be written or
+/// loaded.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SnapshotError {
+    /// Reading or writing the snapshot failed.
+    Io(io::Error),
+    /// Nothing has been published to the map yet, or it has been destroyed,
+    /// so there's nothing to write.
+    MapUnavailable,
+    /// The data doesn't start like a snapshot.
+    NotASnapshot,
+    /// The data doesn't start like a write-ahead log.
+    NotALog,
+    /// The snapshot or log was written in a format version this crate can't
+    /// read.
+    UnsupportedVersion(u32),
+    /// The snapshot's contents don't match its checksum.
+    ChecksumMismatch,
+    /// The snapshot or log passed its other checks but doesn't describe a
+    /// valid map, a value in it couldn't be decoded, or a logged write didn't
+    /// replay the way it was recorded.
+    Corrupt(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "snapshot I/O failed: {}", e),
+            SnapshotError::MapUnavailable => {
+                write!(f, "map has no published version to snapshot")
+            }
+            SnapshotError::NotASnapshot => write!(f, "not a snapshot"),
+            SnapshotError::NotALog => write!(f, "not a write-ahead log"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::ChecksumMismatch => {
+                write!(f, "snapshot checksum mismatch")
+            }
+            SnapshotError::Corrupt(reason) => {
+                write!(f, "corrupt snapshot: {}", reason)
+            }
+        }
+    }
+}
+
+impl error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            SnapshotError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+/// Lookup table for the standard (IEEE) CRC-32
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Running CRC-32 of everything written or read through it
+struct Checksummed<T> {
+    inner: T,
+    crc: u32,
+}
+
+impl<T> Checksummed<T> {
+    fn new(inner: T) -> Self {
+        Checksummed { inner, crc: !0 }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.crc = CRC_TABLE
+                [((self.crc ^ u32::from(*byte)) & 0xFF) as usize]
+                ^ (self.crc >> 8);
+        }
+    }
+
+    fn checksum(&self) -> u32 {
+        !self.crc
+    }
+}
+
+/// Returns the CRC-32 of the given bytes
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut checksummed = Checksummed::new(());
+    checksummed.update(bytes);
+    checksummed.checksum()
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Write a value as a length prefixed payload
+fn write_payload(
+    writer: &mut dyn Write,
+    value: &impl Encode,
+) -> io::Result<()> {
+    let mut payload = Vec::new();
+    value.encode(&mut payload)?;
+    payload.len().encode(writer)?;
+    writer.write_all(&payload)
+}
+
+/// Read a length prefixed payload and decode the value in it
+fn read_payload<T: Decode>(
+    reader: &mut dyn Read,
+    what: &dyn fmt::Display,
+) -> Result<T, SnapshotError> {
+    let len = usize::decode(reader)?;
+    let payload = read_bytes(reader, len)?;
+    let mut remaining = &payload[..];
+    let corrupt = |reason: &dyn fmt::Display| {
+        SnapshotError::Corrupt(format!("{}: {}", what, reason))
+    };
+    let value = T::decode(&mut remaining).map_err(|e| corrupt(&e))?;
+    if !remaining.is_empty() {
+        return Err(corrupt(&"value didn't use its whole payload"));
+    }
+    Ok(value)
+}
+
+fn encode_slot(slot: Option<usize>) -> u64 {
+    slot.map_or(NO_SLOT, |index| index as u64)
+}
+
+fn decode_slot(raw: u64) -> Result<Option<usize>, SnapshotError> {
+    if raw == NO_SLOT {
+        Ok(None)
+    } else {
+        usize::try_from(raw).map(Some).map_err(|_| {
+            SnapshotError::Corrupt(format!("slot {} out of range", raw))
+        })
+    }
+}
+
+impl<K, P, V, M> ReadHandle<K, P, V, M>
+where
+    K: Key<P>,
+    V: Encode,
+    M: Encode,
+{
+    /// Write the published version of the map to the given writer, in a
+    /// format that [`load_snapshot`](crate::load_snapshot) reads back into a
+    /// map where every key resolves just like it does in this one.
+    ///
+    /// The snapshot holds a versioned header, the generation the map was
+    /// published at, the slot table with every slot's generation and the
+    /// free list, each value and the metadata in
+    /// its own payload, and a CRC-32 of all of it. The map is read under a
+    /// single guard for the whole snapshot, so the writer is held up until
+    /// the snapshot has been written.
+    pub fn write_snapshot(
+        &self,
+        writer: impl Write,
+    ) -> Result<(), SnapshotError> {
+        let inner = self.read_inner().ok_or(SnapshotError::MapUnavailable)?;
+        write(&inner, writer)
+    }
+}
+
+/// Write a snapshot of the given copy of a map
+pub(crate) fn write<V, M>(
+    inner: &Inner<ManuallyDrop<V>, ManuallyDrop<M>>,
+    writer: impl Write,
+) -> Result<(), SnapshotError>
+where
+    V: Encode,
+    M: Encode,
+{
+    let (slots, free_head) = inner.data.layout();
+
+    let mut writer = Checksummed::new(BufWriter::new(writer));
+    writer.write_all(&MAGIC)?;
+    VERSION.encode(&mut writer)?;
+    inner.generation.encode(&mut writer)?;
+    slots.len().encode(&mut writer)?;
+    encode_slot(free_head).encode(&mut writer)?;
+    write_payload(&mut writer, &*inner.meta)?;
+
+    for slot in slots {
+        match &slot.entry {
+            Entry::Occupied(value) => {
+                OCCUPIED.encode(&mut writer)?;
+                slot.generation.encode(&mut writer)?;
+                write_payload(&mut writer, &**value)?;
+            }
+            Entry::Vacant(next) => {
+                VACANT.encode(&mut writer)?;
+                slot.generation.encode(&mut writer)?;
+                encode_slot(*next).encode(&mut writer)?;
+            }
+            Entry::Unused(next) => {
+                UNUSED.encode(&mut writer)?;
+                slot.generation.encode(&mut writer)?;
+                encode_slot(*next).encode(&mut writer)?;
+            }
+        }
+    }
+
+    let checksum = writer.checksum();
+    let mut writer = writer.inner;
+    checksum.encode(&mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub(crate) fn load<K, P, V, M>(
+    reader: impl Read,
+) -> Result<(ReadHandle<K, P, V, M>, WriteHandle<K, P, V, M>), SnapshotError>
+where
+    K: Key<P>,
+    V: Decode + ShallowCopy,
+    M: Decode + ShallowCopy,
+{
+    let mut reader = Checksummed::new(BufReader::new(reader));
+
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(SnapshotError::NotASnapshot);
+    }
+    let version = u32::decode(&mut reader)?;
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    let published = u64::decode(&mut reader)?;
+    let slot_count = usize::decode(&mut reader)?;
+    let free_head = decode_slot(u64::decode(&mut reader)?)?;
+    let meta: M = read_payload(&mut reader, &"metadata")?;
+
+    let mut slots = Vec::new();
+    for index in 0..slot_count {
+        let tag = u8::decode(&mut reader)?;
+        let generation = u32::decode(&mut reader)?;
+        let entry = match tag {
+            OCCUPIED => Entry::Occupied(read_payload::<V>(
+                &mut reader,
+                &format_args!("slot {}", index),
+            )?),
+            VACANT => Entry::Vacant(decode_slot(u64::decode(&mut reader)?)?),
+            UNUSED => Entry::Unused(decode_slot(u64::decode(&mut reader)?)?),
+            _ => {
+                return Err(SnapshotError::Corrupt(format!(
+                    "slot {} has unknown kind {}",
+                    index, tag
+                )))
+            }
+        };
+        slots.push(Slot { generation, entry });
+    }
+
+    let checksum = reader.checksum();
+    if u32::decode(&mut reader.inner)? != checksum {
+        return Err(SnapshotError::ChecksumMismatch);
+    }
+
+    let data = SlotTable::from_layout(slots, free_head)
+        .map_err(SnapshotError::Corrupt)?;
+
+    // the values go straight into both copies, without being inserted one
+    // at a time
+    let epochs = Default::default();
+    let (mut inner_r, mut inner_w) =
+        Inner::from_table(data.into_map(ManuallyDrop::new), meta);
+    inner_r.generation = published;
+    inner_w.generation = published;
+
+    let r = crate::read::new(inner_r, Arc::clone(&epochs));
+    let w = crate::write::new(inner_w, epochs, r.clone());
+    Ok((r, w))
+}
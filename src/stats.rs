@@ -0,0 +1,118 @@
+use std::mem;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// A snapshot of how much memory an ev slotmap is using.
+///
+/// Taken with [`ReadHandle::memory_stats`] or [`WriteHandle::memory_stats`].
+/// Byte counts cover each copy's slot table and the copy itself; indexes and
+/// secondary maps aren't counted.
+///
+/// [`ReadHandle::memory_stats`]: crate::ReadHandle::memory_stats
+/// [`WriteHandle::memory_stats`]: crate::WriteHandle::memory_stats
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MemoryStats {
+    /// The number of slots the published copy can hold without reallocating
+    pub slot_capacity: usize,
+    /// The number of slots holding a value
+    pub occupied: usize,
+    /// The number of empty slots waiting to be reused
+    pub free_slots: usize,
+    /// Bytes used by the copy of the map readers currently see
+    pub published_bytes: usize,
+    /// Bytes used by the copy of the map the writer is working on. Only the
+    /// write handle can see this copy, so it's `None` when the stats were
+    /// taken from a read handle.
+    pub write_bytes: Option<usize>,
+    /// The number of read handles registered with the map
+    pub readers: usize,
+    /// Bytes the values own on the heap, as reported by [`HeapSize`]. Values
+    /// are shared between the copies, so they are only counted once. This is
+    /// only filled in by the `memory_stats_deep` methods.
+    pub value_heap_bytes: Option<usize>,
+}
+
+impl MemoryStats {
+    /// Returns the total bytes known to be in use: both copies of the map,
+    /// plus the values' heap memory if it was measured.
+    pub fn total_bytes(&self) -> usize {
+        self.published_bytes
+            + self.write_bytes.unwrap_or(self.published_bytes)
+            + self.value_heap_bytes.unwrap_or(0)
+    }
+}
+
+/// Types that can report how many bytes they own on the heap, not counting
+/// their own inline size. Used by the `memory_stats_deep` methods.
+///
+/// ```rust
+/// use ev_slotmap::HeapSize;
+///
+/// struct Document {
+///     title: String,
+///     pages: Vec<Vec<u8>>,
+/// }
+///
+/// impl HeapSize for Document {
+///     fn heap_size(&self) -> usize {
+///         self.title.heap_size() + self.pages.heap_size()
+///     }
+/// }
+/// ```
+pub trait HeapSize {
+    /// Returns the number of bytes this value owns on the heap
+    fn heap_size(&self) -> usize;
+}
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * mem::size_of::<T>()
+            + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Box<T> {
+    fn heap_size(&self) -> usize {
+        mem::size_of::<T>() + (**self).heap_size()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Option<T> {
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, HeapSize::heap_size)
+    }
+}
+
+// Shared pointers report everything they point at, even though other owners
+// may be holding on to the same allocation.
+
+impl<T: HeapSize> HeapSize for Arc<T> {
+    fn heap_size(&self) -> usize {
+        mem::size_of::<T>() + (**self).heap_size()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Rc<T> {
+    fn heap_size(&self) -> usize {
+        mem::size_of::<T>() + (**self).heap_size()
+    }
+}
+
+macro_rules! impl_heap_size_for_primitives {
+    ($($t:ty)*) => ($(
+        impl HeapSize for $t {
+            fn heap_size(&self) -> usize {
+                0
+            }
+        }
+    )*)
+}
+
+impl_heap_size_for_primitives!(() bool char usize u8 u16 u32 u64 u128 isize i8 i16 i32 i64 i128 f32 f64);
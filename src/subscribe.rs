@@ -0,0 +1,253 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::{error, fmt};
+
+/// The number of events a subscription buffers if no capacity is given
+pub(crate) const DEFAULT_SUBSCRIPTION_CAPACITY: usize = 1024;
+
+/// A change to the map, delivered to a [`Subscription`] once it is visible to
+/// readers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChangeEvent<K> {
+    /// A value was inserted under this key.
+    Inserted(K),
+    /// The value for this key was replaced.
+    Updated(K),
+    /// The value for this key was removed.
+    Removed(K),
+    /// The map was cleared.
+    Cleared,
+}
+
+impl<K> ChangeEvent<&K> {
+    fn cloned_with(self, clone_key: fn(&K) -> K) -> ChangeEvent<K> {
+        match self {
+            ChangeEvent::Inserted(k) => ChangeEvent::Inserted(clone_key(k)),
+            ChangeEvent::Updated(k) => ChangeEvent::Updated(clone_key(k)),
+            ChangeEvent::Removed(k) => ChangeEvent::Removed(clone_key(k)),
+            ChangeEvent::Cleared => ChangeEvent::Cleared,
+        }
+    }
+}
+
+/// Error returned by [`Subscription::recv`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// The subscriber fell behind and this many events were dropped. Events
+    /// received after this error are again complete, but anything mirrored
+    /// from the map should be rebuilt.
+    Lagged(u64),
+    /// The write handle was dropped and all buffered events have been
+    /// received.
+    Closed,
+}
+
+/// Error returned by [`Subscription::try_recv`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// No events are currently buffered.
+    Empty,
+    /// See [`RecvError::Lagged`].
+    Lagged(u64),
+    /// See [`RecvError::Closed`].
+    Closed,
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvError::Lagged(n) => write!(f, "subscription lagged by {}", n),
+            RecvError::Closed => write!(f, "subscription closed"),
+        }
+    }
+}
+
+impl error::Error for RecvError {}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "no events available"),
+            TryRecvError::Lagged(n) => {
+                write!(f, "subscription lagged by {}", n)
+            }
+            TryRecvError::Closed => write!(f, "subscription closed"),
+        }
+    }
+}
+
+impl error::Error for TryRecvError {}
+
+struct ChannelState<K> {
+    buffer: VecDeque<ChangeEvent<K>>,
+    capacity: usize,
+    lagged: u64,
+    writer_dropped: bool,
+    subscriber_dropped: bool,
+}
+
+struct Channel<K> {
+    state: Mutex<ChannelState<K>>,
+    cond: Condvar,
+}
+
+impl<K> Channel<K> {
+    /// Push an event onto this channel, returning false if the subscriber has
+    /// gone away
+    fn send(&self, event: ChangeEvent<K>) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        if state.subscriber_dropped {
+            return false;
+        }
+
+        // once a subscriber has lagged, keep dropping events until it has
+        // observed the lag so the gap shows up at the right place in the
+        // stream
+        if state.lagged > 0 || state.buffer.len() >= state.capacity {
+            state.lagged += 1;
+        } else {
+            state.buffer.push_back(event);
+        }
+
+        drop(state);
+        self.cond.notify_one();
+        true
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().writer_dropped = true;
+        self.cond.notify_one();
+    }
+}
+
+/// The write handle's side of all the subscriptions to a map
+pub(crate) struct Subscribers<K> {
+    channels: Vec<Arc<Channel<K>>>,
+    clone_key: fn(&K) -> K,
+}
+
+impl<K> fmt::Debug for Subscribers<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscribers")
+            .field("count", &self.channels.len())
+            .finish()
+    }
+}
+
+impl<K> Subscribers<K>
+where
+    K: Clone,
+{
+    pub(crate) fn new() -> Self {
+        Subscribers {
+            channels: Vec::new(),
+            clone_key: K::clone,
+        }
+    }
+}
+
+impl<K> Subscribers<K> {
+    pub(crate) fn subscribe(&mut self, capacity: usize) -> Subscription<K> {
+        assert!(capacity > 0, "Subscription capacity must be non-zero");
+
+        let channel = Arc::new(Channel {
+            state: Mutex::new(ChannelState {
+                buffer: VecDeque::new(),
+                capacity,
+                lagged: 0,
+                writer_dropped: false,
+                subscriber_dropped: false,
+            }),
+            cond: Condvar::new(),
+        });
+
+        self.channels.push(Arc::clone(&channel));
+
+        Subscription { channel }
+    }
+
+    /// Deliver the given event to all live subscriptions, and forget any
+    /// subscriptions that have been dropped
+    pub(crate) fn send(&mut self, event: ChangeEvent<&K>) {
+        let clone_key = self.clone_key;
+        self.channels
+            .retain(|channel| channel.send(event.cloned_with(clone_key)));
+    }
+}
+
+impl<K> Drop for Subscribers<K> {
+    fn drop(&mut self) {
+        self.channels.iter().for_each(|channel| channel.close());
+    }
+}
+
+/// A stream of [`ChangeEvent`]s for a map, created by
+/// [`WriteHandle::subscribe`].
+///
+/// Events are buffered up to a fixed capacity. If the subscriber falls behind
+/// far enough to fill the buffer, further events are dropped and the next
+/// receive reports how many were lost with [`RecvError::Lagged`].
+///
+/// [`WriteHandle::subscribe`]: crate::WriteHandle::subscribe
+pub struct Subscription<K> {
+    channel: Arc<Channel<K>>,
+}
+
+impl<K> fmt::Debug for Subscription<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.channel.state.lock().unwrap();
+        f.debug_struct("Subscription")
+            .field("buffered", &state.buffer.len())
+            .field("capacity", &state.capacity)
+            .field("lagged", &state.lagged)
+            .finish()
+    }
+}
+
+impl<K> Subscription<K> {
+    fn take(
+        state: &mut ChannelState<K>,
+    ) -> Result<ChangeEvent<K>, TryRecvError> {
+        if let Some(event) = state.buffer.pop_front() {
+            Ok(event)
+        } else if state.lagged > 0 {
+            Err(TryRecvError::Lagged(std::mem::take(&mut state.lagged)))
+        } else if state.writer_dropped {
+            Err(TryRecvError::Closed)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    /// Receive the next event without blocking.
+    pub fn try_recv(&self) -> Result<ChangeEvent<K>, TryRecvError> {
+        Self::take(&mut self.channel.state.lock().unwrap())
+    }
+
+    /// Block until the next event is available.
+    pub fn recv(&self) -> Result<ChangeEvent<K>, RecvError> {
+        let mut state = self.channel.state.lock().unwrap();
+
+        loop {
+            match Self::take(&mut state) {
+                Ok(event) => return Ok(event),
+                Err(TryRecvError::Lagged(n)) => {
+                    return Err(RecvError::Lagged(n))
+                }
+                Err(TryRecvError::Closed) => return Err(RecvError::Closed),
+                Err(TryRecvError::Empty) => {
+                    state = self.channel.cond.wait(state).unwrap();
+                }
+            }
+        }
+    }
+}
+
+impl<K> Drop for Subscription<K> {
+    fn drop(&mut self) {
+        let mut state = self.channel.state.lock().unwrap();
+        state.subscriber_dropped = true;
+        state.buffer.clear();
+    }
+}
@@ -0,0 +1,624 @@
+use super::Operation;
+use crate::inner::Inner;
+use crate::read::ReadHandle;
+use crate::snapshot::{self, crc32, read_bytes, Decode, Encode, SnapshotError};
+use crate::write::WriteHandle;
+use crate::ShallowCopy;
+use one_way_slot_map::{SlotMapKey as Key, SlotMapKeyData};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::mem::ManuallyDrop;
+use std::path::{Path, PathBuf};
+
+/// Every log starts with these bytes
+const MAGIC: [u8; 8] = *b"EVSLOWAL";
+const VERSION: u32 = 2;
+
+/// Bytes taken up by the magic and version
+const HEADER_LEN: u64 = MAGIC.len() as u64 + 4;
+
+const ADD: u8 = 0;
+const REPLACE: u8 = 1;
+const REMOVE: u8 = 2;
+const CLEAR: u8 = 3;
+const ADD_AT: u8 = 4;
+
+/// How often a [`WriteAheadLog`] forces what has been appended to disk.
+///
+/// Every write reaches the operating system before it is published, so it
+/// survives the process crashing either way. Syncing is what makes it survive
+/// the machine going down.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum SyncPolicy {
+    /// Sync after every write. This is the default.
+    #[default]
+    Always,
+    /// Sync after every `n` writes.
+    Every(u32),
+    /// Never sync, except through
+    /// [`WriteHandle::sync_log`](crate::WriteHandle::sync_log).
+    Never,
+}
+
+/// When a write handle checkpoints its map on its own. See
+/// [`WriteHandle::set_checkpoint_policy`](crate::WriteHandle::set_checkpoint_policy).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CheckpointPolicy {
+    /// Checkpoint once this many writes have been logged since the last
+    /// checkpoint.
+    EveryOps(u64),
+    /// Checkpoint once this many bytes have been logged since the last
+    /// checkpoint.
+    EveryBytes(u64),
+}
+
+impl CheckpointPolicy {
+    fn is_due(self, log: &WriteAheadLog) -> bool {
+        match self {
+            CheckpointPolicy::EveryOps(ops) => log.records >= ops,
+            CheckpointPolicy::EveryBytes(bytes) => log.bytes >= bytes,
+        }
+    }
+}
+
+/// An append-only file of the writes made to a map, so the ones made after
+/// its last snapshot can be recovered with [`recover`](crate::recover).
+///
+/// Attach one to a write handle with
+/// [`WriteHandle::log_to`](crate::WriteHandle::log_to).
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    file: File,
+    path: PathBuf,
+    policy: SyncPolicy,
+    unsynced: u32,
+    /// Records and bytes held after the header
+    records: u64,
+    bytes: u64,
+    /// Set once a failed append couldn't be undone, after which nothing more
+    /// can be appended
+    broken: bool,
+}
+
+impl WriteAheadLog {
+    /// Start a new, empty log at the given path, replacing any file that's
+    /// already there.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut file = File::create(path)?;
+        write_header(&mut file)?;
+        file.sync_data()?;
+        Ok(WriteAheadLog::new(file, path))
+    }
+
+    /// Open an existing log to keep appending to it.
+    ///
+    /// A record cut off at the end of the log by a crash is removed, so the
+    /// records appended after it can be read back.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().read(true).append(true).open(path)?;
+        let mut log = WriteAheadLog::new(file, path);
+
+        let mut reader = BufReader::new(&log.file);
+        read_header(&mut reader).map_err(into_io)?;
+        while let Some(body) = read_record(&mut reader).map_err(into_io)? {
+            log.records += 1;
+            log.bytes += 8 + body.len() as u64;
+        }
+        log.file.set_len(HEADER_LEN + log.bytes)?;
+        Ok(log)
+    }
+
+    fn new(file: File, path: &Path) -> Self {
+        WriteAheadLog {
+            file,
+            path: path.to_path_buf(),
+            policy: SyncPolicy::default(),
+            unsynced: 0,
+            records: 0,
+            bytes: 0,
+            broken: false,
+        }
+    }
+
+    /// Choose how often the log is forced to disk. See [`SyncPolicy`].
+    pub fn with_sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Force everything appended so far to disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Append a record, prefixed with its length and checksum. If that
+    /// fails, whatever part of the record made it into the file is taken
+    /// back out, so it isn't replayed and later records still follow on from
+    /// the last whole one.
+    // io::Error::other needs a newer compiler than this crate supports
+    #[allow(clippy::io_other_error)]
+    fn append(&mut self, body: &[u8]) -> io::Result<()> {
+        if self.broken {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "a failed append couldn't be taken back out of the log",
+            ));
+        }
+
+        let record = frame(body);
+        if let Err(e) = self.write_record(&record) {
+            let end = HEADER_LEN + self.bytes;
+            let undone = self
+                .file
+                .set_len(end)
+                .and_then(|()| self.file.seek(SeekFrom::Start(end)));
+            self.broken = undone.is_err();
+            return Err(e);
+        }
+        self.records += 1;
+        self.bytes += record.len() as u64;
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        self.file.write_all(record)?;
+        self.unsynced += 1;
+        match self.policy {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Every(n) if self.unsynced >= n => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drop every record up to and including the given generation, by
+    /// writing the rest into a new log that then replaces this one
+    fn truncate_through(&mut self, generation: u64) -> io::Result<()> {
+        let mut kept = Vec::new();
+        let mut records = 0;
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        read_header(&mut reader).map_err(into_io)?;
+        while let Some(body) = read_record(&mut reader).map_err(into_io)? {
+            if record_generation(&body).map_err(into_io)? > generation {
+                kept.extend_from_slice(&frame(&body));
+                records += 1;
+            }
+        }
+
+        // appends only move over to the new log once it has replaced the old
+        // one, so nothing is lost if that fails
+        self.file = replace_file(&self.path, |file| {
+            write_header(file)?;
+            file.write_all(&kept)
+        })?;
+        self.records = records;
+        self.bytes = kept.len() as u64;
+        self.unsynced = 0;
+        self.broken = false;
+        Ok(())
+    }
+}
+
+fn into_io(error: SnapshotError) -> io::Error {
+    match error {
+        SnapshotError::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    }
+}
+
+/// Write a file next to the given path and rename it over the top, so the
+/// path always holds either the old contents or all of the new ones. The new
+/// file is returned open for writing.
+fn replace_file<E: From<io::Error>>(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> Result<(), E>,
+) -> Result<File, E> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+
+    let mut file = File::create(&temp)?;
+    let written = write(&mut file)
+        .and_then(|()| Ok(file.sync_all()?))
+        .and_then(|()| Ok(std::fs::rename(&temp, path)?));
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e);
+    }
+
+    // make the rename itself durable
+    #[cfg(unix)]
+    {
+        let parent = path.parent().filter(|p| !p.as_os_str().is_empty());
+        File::open(parent.unwrap_or_else(|| Path::new(".")))?.sync_all()?;
+    }
+    Ok(file)
+}
+
+/// A write-ahead log attached to a write handle, along with how to encode the
+/// handle's values
+pub(crate) struct OperationLog<V> {
+    log: WriteAheadLog,
+    encode: fn(&V, &mut dyn Write) -> io::Result<()>,
+}
+
+impl<V> fmt::Debug for OperationLog<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OperationLog")
+            .field("log", &self.log)
+            .finish()
+    }
+}
+
+impl<V> OperationLog<V> {
+    pub(crate) fn new(log: WriteAheadLog) -> Self
+    where
+        V: Encode,
+    {
+        OperationLog {
+            log,
+            encode: V::encode,
+        }
+    }
+
+    pub(crate) fn into_log(self) -> WriteAheadLog {
+        self.log
+    }
+
+    pub(crate) fn sync(&mut self) -> io::Result<()> {
+        self.log.sync()
+    }
+
+    /// Record the given operation, which is about to be applied to a map
+    /// that currently looks like `target` and published as `generation`.
+    pub(crate) fn append<M>(
+        &mut self,
+        op: &Operation<V>,
+        target: &Inner<ManuallyDrop<V>, M>,
+        generation: u64,
+    ) -> io::Result<()> {
+        match encode_record(op, target, generation, self.encode)? {
+            Some(body) => self.log.append(&body),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Encode the record of the given operation, which is about to be applied to
+/// a map that currently looks like `target` and published as `generation`.
+/// Operations that don't change the map's values have no record.
+pub(crate) fn encode_record<V, M>(
+    op: &Operation<V>,
+    target: &Inner<ManuallyDrop<V>, M>,
+    generation: u64,
+    encode: fn(&V, &mut dyn Write) -> io::Result<()>,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut body = Vec::new();
+    let mut record = |tag: u8| {
+        tag.encode(&mut body)?;
+        generation.encode(&mut body)?;
+        Ok::<_, io::Error>(())
+    };
+    match op {
+        // a joined value is recorded without its secondary value, which
+        // still keeps the keys handed out on replay the same
+        Operation::Add(value) | Operation::AddJoined(value, _) => {
+            record(ADD)?;
+            u64::from(target.data.next_key()).encode(&mut body)?;
+            encode(value, &mut body)?;
+        }
+        Operation::AddAt(key, value) => {
+            if target.data.check_vacant(key).is_err() {
+                // this is about to fail, so there's nothing to replay
+                return Ok(None);
+            }
+            record(ADD_AT)?;
+            u64::from(*key).encode(&mut body)?;
+            encode(value, &mut body)?;
+        }
+        Operation::Replace(key, value) => {
+            if !target.data.contains_key(key) {
+                // this is about to fail, so there's nothing to replay
+                return Ok(None);
+            }
+            record(REPLACE)?;
+            u64::from(*key).encode(&mut body)?;
+            encode(value, &mut body)?;
+        }
+        Operation::Remove(key) => {
+            record(REMOVE)?;
+            u64::from(*key).encode(&mut body)?;
+        }
+        Operation::Clear => record(CLEAR)?,
+        _ => return Ok(None),
+    }
+    Ok(Some(body))
+}
+
+/// Prefix a record's body with its length and checksum
+pub(crate) fn frame(body: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(body.len() + 8);
+    record.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32(body).to_le_bytes());
+    record.extend_from_slice(body);
+    record
+}
+
+/// Where and when a write handle checkpoints its map, along with how to
+/// snapshot it
+pub(crate) struct Checkpoints<K, P, V, M>
+where
+    K: Key<P>,
+{
+    snapshot: PathBuf,
+    policy: CheckpointPolicy,
+    write: fn(
+        &ReadHandle<K, P, V, M>,
+        &mut dyn Write,
+    ) -> Result<(), SnapshotError>,
+}
+
+impl<K, P, V, M> fmt::Debug for Checkpoints<K, P, V, M>
+where
+    K: Key<P>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Checkpoints")
+            .field("snapshot", &self.snapshot)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+impl<K, P, V, M> Checkpoints<K, P, V, M>
+where
+    K: Key<P>,
+{
+    pub(crate) fn new(snapshot: PathBuf, policy: CheckpointPolicy) -> Self
+    where
+        V: Encode,
+        M: Encode,
+    {
+        Checkpoints {
+            snapshot,
+            policy,
+            write: |r, writer| r.write_snapshot(writer),
+        }
+    }
+
+    /// Checkpoint if the policy says the log has grown enough since the last
+    /// one. A failed checkpoint leaves the log as it was, so it's tried again
+    /// after the next write.
+    pub(crate) fn run_if_due(
+        &self,
+        r: &ReadHandle<K, P, V, M>,
+        generation: u64,
+        log: Option<&mut OperationLog<V>>,
+    ) -> Result<(), SnapshotError> {
+        match log.filter(|log| self.policy.is_due(&log.log)) {
+            Some(log) => {
+                checkpoint(r, generation, &self.snapshot, self.write, Some(log))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Atomically replace the snapshot at the given path with one of the map as
+/// of the given generation, which has to be the one it's published at, and
+/// then drop everything up to that generation from the log
+pub(crate) fn checkpoint<K, P, V, M>(
+    r: &ReadHandle<K, P, V, M>,
+    generation: u64,
+    snapshot: &Path,
+    write: fn(
+        &ReadHandle<K, P, V, M>,
+        &mut dyn Write,
+    ) -> Result<(), SnapshotError>,
+    log: Option<&mut OperationLog<V>>,
+) -> Result<(), SnapshotError>
+where
+    K: Key<P>,
+{
+    replace_file(snapshot, |file| write(r, file))?;
+
+    // a crash before the log is truncated leaves records the snapshot
+    // already holds, which recovery skips over by their generation
+    if let Some(log) = log {
+        log.log.truncate_through(generation)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn write_header(writer: &mut dyn Write) -> io::Result<()> {
+    writer.write_all(&MAGIC)?;
+    VERSION.encode(writer)
+}
+
+pub(crate) fn read_header(reader: &mut dyn Read) -> Result<(), SnapshotError> {
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(SnapshotError::NotALog);
+    }
+    let version = u32::decode(reader)?;
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
+
+/// Read the next record's body. Returns `None` at the end of the log, which
+/// includes a record that was cut off by a crash while it was being written.
+pub(crate) fn read_record(
+    reader: &mut BufReader<impl Read>,
+) -> Result<Option<Vec<u8>>, SnapshotError> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+
+    let mut header = [0; 8];
+    let mut body = Vec::new();
+    let complete = reader.read_exact(&mut header).is_ok() && {
+        let len =
+            u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        body = read_bytes(reader, len as usize).unwrap_or_default();
+        body.len() == len as usize
+    };
+    if !complete {
+        return Ok(None);
+    }
+
+    let checksum =
+        u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if crc32(&body) != checksum {
+        // a torn write can leave garbage in the last record, but not before
+        // records that come after it
+        return if reader.fill_buf()?.is_empty() {
+            Ok(None)
+        } else {
+            Err(SnapshotError::Corrupt(
+                "write-ahead log record checksum mismatch".to_string(),
+            ))
+        };
+    }
+    Ok(Some(body))
+}
+
+/// Returns the generation a record's write was published as
+fn record_generation(body: &[u8]) -> Result<u64, SnapshotError> {
+    let mut body = body.get(1..).unwrap_or_default();
+    Ok(u64::decode(&mut body)?)
+}
+
+/// Apply every write recorded in the log that the given map doesn't already
+/// hold
+fn replay<K, P, V, M>(
+    w: &mut WriteHandle<K, P, V, M>,
+    log: impl Read,
+) -> Result<(), SnapshotError>
+where
+    K: Key<P>,
+    V: Decode + ShallowCopy,
+    M: ShallowCopy,
+{
+    let mut reader = BufReader::new(log);
+    read_header(&mut reader)?;
+
+    let mut index = 0;
+    while let Some(body) = read_record(&mut reader)? {
+        apply_record(w, &body, index)?;
+        index += 1;
+    }
+    Ok(())
+}
+
+/// Apply a recorded write to the given map, unless the map already holds
+/// it, checking that it hands out or touches the same key, and is published
+/// as the same generation, as it was originally
+pub(crate) fn apply_record<K, P, V, M>(
+    w: &mut WriteHandle<K, P, V, M>,
+    body: &[u8],
+    index: usize,
+) -> Result<(), SnapshotError>
+where
+    K: Key<P>,
+    V: Decode + ShallowCopy,
+    M: ShallowCopy,
+{
+    let corrupt = |reason: &dyn fmt::Display| {
+        SnapshotError::Corrupt(format!("record {}: {}", index, reason))
+    };
+    let read_key = |body: &mut &[u8]| {
+        u64::decode(body)
+            .map(SlotMapKeyData::from)
+            .map_err(|e| corrupt(&e))
+    };
+    let read_value = |body: &mut &[u8]| {
+        let value = V::decode(body).map_err(|e| corrupt(&e))?;
+        if !body.is_empty() {
+            return Err(corrupt(&"value didn't use its whole record"));
+        }
+        Ok(value)
+    };
+
+    let generation = record_generation(body).map_err(|e| corrupt(&e))?;
+    if generation <= w.generation() {
+        // written before the map's snapshot was taken
+        return Ok(());
+    }
+    w.resume_at_generation(generation);
+
+    let tag = body[0];
+    let mut body = &body[9..];
+    match tag {
+        ADD => {
+            let key = read_key(&mut body)?;
+            let value = read_value(&mut body)?;
+            let added = w.try_refresh_with_operation(Operation::Add(value))?;
+            if added != Some(key) {
+                return Err(corrupt(&format_args!(
+                    "recorded key {:?} but replay handed out {:?}",
+                    key, added
+                )));
+            }
+        }
+        ADD_AT => {
+            let key = read_key(&mut body)?;
+            let value = read_value(&mut body)?;
+            let added =
+                w.try_refresh_with_operation(Operation::AddAt(key, value))?;
+            if added != Some(key) {
+                return Err(corrupt(&format_args!(
+                    "recorded key {:?} couldn't be inserted",
+                    key
+                )));
+            }
+        }
+        REPLACE => {
+            let key = read_key(&mut body)?;
+            let value = read_value(&mut body)?;
+            let present = w
+                .read_inner()
+                .map(|inner| inner.data.contains_key(&key))
+                .unwrap_or(false);
+            if !present {
+                return Err(corrupt(&format_args!(
+                    "recorded key {:?} isn't in the map",
+                    key
+                )));
+            }
+            let _ =
+                w.try_refresh_with_operation(Operation::Replace(key, value))?;
+        }
+        REMOVE => {
+            let key = read_key(&mut body)?;
+            let _ = w.try_refresh_with_operation(Operation::Remove(key))?;
+        }
+        CLEAR => {
+            let _ = w.try_refresh_with_operation(Operation::Clear)?;
+        }
+        tag => return Err(corrupt(&format_args!("unknown kind {}", tag))),
+    }
+    Ok(())
+}
+
+pub(crate) fn recover<K, P, V, M>(
+    snapshot: impl Read,
+    log: impl Read,
+) -> Result<(ReadHandle<K, P, V, M>, WriteHandle<K, P, V, M>), SnapshotError>
+where
+    K: Key<P>,
+    V: Decode + ShallowCopy,
+    M: Decode + ShallowCopy,
+{
+    let (r, mut w) = snapshot::load(snapshot)?;
+    replay(&mut w, log)?;
+    Ok((r, w))
+}
@@ -1,10 +1,28 @@
 use super::Operation;
-use crate::inner::{Inner, InnerKey};
+use crate::changes::ChangeKind;
+use crate::frozen::FrozenMap;
+use crate::index::{Bucket, IndexDef};
+use crate::inner::Inner;
 use crate::read::ReadHandle;
-use evmap::ShallowCopy;
-use one_way_slot_map::SlotMapKey as Key;
+use crate::reclaim::{DropPolicy, Reclaimer};
+use crate::replicate::{Followers, DEFAULT_REPLICATION_CAPACITY};
+use crate::slot_table::{generation_of, InsertAtError};
+use crate::snapshot::{Encode, SnapshotError};
+use crate::stats::{HeapSize, MemoryStats};
+use crate::subscribe::{
+    ChangeEvent, Subscribers, Subscription, DEFAULT_SUBSCRIPTION_CAPACITY,
+};
+use crate::wal::{
+    self, CheckpointPolicy, Checkpoints, OperationLog, WriteAheadLog,
+};
+use crate::{lock_epochs, ShallowCopy};
+use one_way_slot_map::{SlotMapKey as Key, SlotMapKeyData};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::io::{self, Write};
 use std::marker::PhantomData;
 use std::mem::ManuallyDrop;
+use std::path::{Path, PathBuf};
 use std::sync::atomic;
 use std::sync::{Arc, MutexGuard};
 use std::{fmt, mem, thread};
@@ -15,104 +33,180 @@ use std::{fmt, mem, thread};
 /// readers, causing all future lookups to return `None`.
 ///
 /// ```
-pub struct WriteHandle<K, P, V>
+pub struct WriteHandle<K, P, V, M = ()>
 where
     K: Key<P>,
     V: ShallowCopy,
+    M: ShallowCopy,
 {
     epochs: crate::Epochs,
-    w_handle: Option<Box<Inner<ManuallyDrop<V>>>>,
+    w_handle: Option<Box<Inner<ManuallyDrop<V>, ManuallyDrop<M>>>>,
     last_op: Option<Operation<V>>,
-    r_handle: ReadHandle<K, P, V>,
+    meta: Option<M>,
+    last_meta: Option<M>,
+    r_handle: ReadHandle<K, P, V, M>,
     last_epochs: Vec<usize>,
+    generation: u64,
+    subscribers: Option<Subscribers<K>>,
+    reclaimer: Reclaimer<V>,
+    log: Option<OperationLog<V>>,
+    checkpoints: Option<Checkpoints<K, P, V, M>>,
+    checkpoint_error: Option<SnapshotError>,
+    followers: Option<Followers<V>>,
+    frozen: bool,
+    freeze_on_panic: bool,
+    writing: bool,
 
     phantom_p: PhantomData<P>,
 }
 
-impl<K, P, V> fmt::Debug for WriteHandle<K, P, V>
+impl<K, P, V, M> fmt::Debug for WriteHandle<K, P, V, M>
 where
     K: Key<P> + fmt::Debug,
     V: fmt::Debug + ShallowCopy,
+    M: fmt::Debug + ShallowCopy,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("WriteHandle")
             .field("epochs", &self.epochs)
             .field("w_handle", &self.w_handle)
             .field("last_op", &self.last_op)
+            .field("meta", &self.meta)
             .field("r_handle", &self.r_handle)
+            .field("generation", &self.generation)
+            .field("subscribers", &self.subscribers)
+            .field("drop_policy", &self.drop_policy())
+            .field("log", &self.log)
+            .field("checkpoints", &self.checkpoints)
+            .field("checkpoint_error", &self.checkpoint_error)
+            .field("followers", &self.followers)
             .finish()
     }
 }
 
-pub(crate) fn new<K, P, V>(
-    w_handle: Inner<ManuallyDrop<V>>,
+pub(crate) fn new<K, P, V, M>(
+    w_handle: Inner<ManuallyDrop<V>, ManuallyDrop<M>>,
     epochs: crate::Epochs,
-    r_handle: ReadHandle<K, P, V>,
-) -> WriteHandle<K, P, V>
+    r_handle: ReadHandle<K, P, V, M>,
+) -> WriteHandle<K, P, V, M>
 where
     K: Key<P>,
     V: ShallowCopy,
+    M: ShallowCopy,
 {
     WriteHandle {
         epochs,
+        generation: w_handle.generation,
         w_handle: Some(Box::new(w_handle)),
         last_op: Default::default(),
+        meta: None,
+        last_meta: None,
         r_handle,
         last_epochs: Vec::new(),
+        subscribers: None,
+        reclaimer: Reclaimer::Inline,
+        log: None,
+        checkpoints: None,
+        checkpoint_error: None,
+        followers: None,
+        frozen: false,
+        freeze_on_panic: true,
+        writing: false,
 
         phantom_p: Default::default(),
     }
 }
 
-impl<K, P, V> Drop for WriteHandle<K, P, V>
+impl<K, P, V, M> Drop for WriteHandle<K, P, V, M>
 where
     K: Key<P>,
     V: ShallowCopy,
+    M: ShallowCopy,
 {
     fn drop(&mut self) {
         use std::ptr;
 
+        if self.writing {
+            // a write panicked part way through, so w_handle may be half
+            // updated, and may share values with an operation that has since
+            // been dropped. readers keep the last published version, but no
+            // writer can take it over, and both copies are leaked rather than
+            // risk dropping anything twice
+            self.r_handle.signal.close();
+            self.r_handle.inner.strand();
+            mem::forget(self.w_handle.take());
+            mem::forget(self.last_meta.take());
+            mem::replace(&mut self.reclaimer, Reclaimer::Inline).finish();
+            return;
+        }
+
+        // a writer that goes down with a panic leaves the map readable, so a
+        // new writer can take over from where it left off
+        let frozen =
+            self.frozen || (self.freeze_on_panic && thread::panicking());
+
         // first, ensure both maps are up to date
         // (otherwise safely dropping de-duplicated rows is a pain)
         while self.last_op.is_some() {
             self.refresh();
         }
 
-        // next, grab the read handle and set it to NULL
-        let r_handle = self
-            .r_handle
-            .inner
-            .swap(ptr::null_mut(), atomic::Ordering::Release);
+        // next, unless the map is being frozen, grab the read handle and set it to NULL
+        let r_handle = if frozen {
+            None
+        } else {
+            Some(
+                self.r_handle
+                    .inner
+                    .swap(ptr::null_mut(), atomic::Ordering::Release),
+            )
+        };
+
+        // release anyone waiting for a generation that will now never come
+        self.r_handle.signal.close();
 
-        // now, wait for all readers to depart
+        // now, wait for all readers to depart (from w_handle, at least, if the
+        // map is being frozen)
         let epochs = Arc::clone(&self.epochs);
-        let mut epochs = epochs.lock().unwrap();
+        let mut epochs = lock_epochs(&epochs);
         self.wait(&mut epochs);
 
         // ensure that the subsequent epoch reads aren't re-ordered to before the swap
         atomic::fence(atomic::Ordering::SeqCst);
 
-        let w_handle = &mut self.w_handle.as_mut().unwrap().data;
+        if let Some(r_handle) = r_handle {
+            let w_handle = self.w_handle.as_mut().unwrap();
+
+            // all readers have now observed the NULL, so we own both handles.
+            // all records are duplicated between w_handle and r_handle.
+            // since the two maps are exactly equal, we need to make sure that we *don't* call the
+            // destructors of any of the values that are in our map, as they'll all be called when the
+            // last read handle goes out of scope. to do so, we first clear w_handle, which won't drop
+            // any elements since its values are kept as ManuallyDrop:
+            w_handle.clear_values(false);
 
-        // all readers have now observed the NULL, so we own both handles.
-        // all records are duplicated between w_handle and r_handle.
-        // since the two maps are exactly equal, we need to make sure that we *don't* call the
-        // destructors of any of the values that are in our map, as they'll all be called when the
-        // last read handle goes out of scope. to do so, we first clear w_handle, which won't drop
-        // any elements since its values are kept as ManuallyDrop:
-        w_handle.clear();
+            // then we drop the values in r_handle as if they weren't ManuallyDrop, which will free all
+            // the records. this is safe, since we know that no readers are using this pointer anymore
+            // (due to the .wait() following swapping the pointer with NULL).
+            let mut r_handle = unsafe { Box::from_raw(r_handle) };
+            unsafe { r_handle.drop_values() };
+        } else {
+            // readers will only ever see r_handle from now on, and the up to
+            // date w_handle is left for the next writer to pick up
+            let w_handle = self.w_handle.take().unwrap();
+            self.r_handle.inner.freeze(w_handle);
+        }
 
-        // then we transmute r_handle to remove the ManuallyDrop, and then drop it, which will free
-        // all the records. this is safe, since we know that no readers are using this pointer
-        // anymore (due to the .wait() following swapping the pointer with NULL).
-        drop(unsafe { Box::from_raw(r_handle as *mut Inner<V>) });
+        // and wait for anything handed off for dropping to actually be dropped
+        mem::replace(&mut self.reclaimer, Reclaimer::Inline).finish();
     }
 }
 
-impl<K, P, V> WriteHandle<K, P, V>
+impl<K, P, V, M> WriteHandle<K, P, V, M>
 where
     K: Key<P>,
     V: ShallowCopy,
+    M: ShallowCopy,
 {
     fn wait(
         &mut self,
@@ -163,62 +257,181 @@ where
 
     #[allow(clippy::borrowed_box)]
     fn run_operation_first(
-        target: &mut Box<Inner<ManuallyDrop<V>>>,
+        target: &mut Box<Inner<ManuallyDrop<V>, ManuallyDrop<M>>>,
         op: &Operation<V>,
-    ) -> Option<InnerKey> {
+    ) -> Option<SlotMapKeyData> {
         let mut result = None;
 
         match op {
             Operation::NoOp => (),
             Operation::Add(value) => {
-                result = Some(
-                    target.data.insert((), unsafe { value.shallow_copy() }),
-                );
+                let key = target.data.insert(unsafe { value.shallow_copy() });
+                target.index_slot(&key);
+                result = Some(key);
+            }
+            Operation::AddAt(key, value) => {
+                let value = unsafe { value.shallow_copy() };
+                if target.data.insert_at(key, value).is_ok() {
+                    target.index_slot(key);
+                    result = Some(*key);
+                }
             }
             Operation::Replace(key, value) => {
-                let old_value = target
-                    .data
-                    .get_mut_unbounded(key)
-                    .expect("Tried to replace empty key");
-
-                *old_value = unsafe { value.shallow_copy() };
+                target.unindex_slot(key);
+                let _ = target.replace_value(
+                    key,
+                    unsafe { value.shallow_copy() },
+                    false,
+                );
+                target.index_slot(key);
+                result = Some(*key);
             }
             Operation::Remove(key) => {
-                let _ = target.data.remove_unbounded(key);
+                if target.data.get(key).is_some() {
+                    result = Some(*key);
+                }
+                target.unindex_slot(key);
+                target.remove_from_secondaries(key, false);
+                let _ = target.remove_value(key, false);
             }
             Operation::Clear => {
-                target.data.clear();
+                target.clear_indexes();
+                target.clear_secondaries(false);
+                target.clear_values(false);
+            }
+            Operation::Reserve(additional) => {
+                target.data.reserve(*additional);
+            }
+            Operation::ShrinkToFit => {
+                target.data.shrink_to_fit();
+            }
+            Operation::AddIndex(def) => {
+                target.add_index(def);
+            }
+            Operation::Secondary(secondary_op) => {
+                target.apply_secondary_first(secondary_op);
+            }
+            Operation::AddJoined(value, secondary_value) => {
+                let key = target.data.insert(unsafe { value.shallow_copy() });
+                target.index_slot(&key);
+                target.secondary_update_first(&key, secondary_value);
+                result = Some(key);
             }
         }
 
         result
     }
 
-    fn run_operation_second(target: &mut Inner<V>, op: Operation<V>) {
+    fn run_operation_second(
+        target: &mut Inner<ManuallyDrop<V>, ManuallyDrop<M>>,
+        op: Operation<V>,
+        reclaimer: &mut Reclaimer<V>,
+    ) {
+        // readers can still see the shallow copies of the values in these
+        // operations, so each one is wrapped before any index code runs. if
+        // that code panics, the value is leaked rather than freed under them
         match op {
             Operation::NoOp => (),
             Operation::Add(value) => {
-                let _ = target.data.insert((), value);
+                let value = ManuallyDrop::new(value);
+                let key = target.data.insert(value);
+                target.index_slot(&key);
+            }
+            Operation::AddAt(key, value) => {
+                let value = ManuallyDrop::new(value);
+                match target.data.insert_at(&key, value) {
+                    Ok(()) => target.index_slot(&key),
+                    Err(value) => {
+                        reclaimer.dispose(ManuallyDrop::into_inner(value))
+                    }
+                }
             }
             Operation::Replace(key, value) => {
-                let old_value = target
-                    .data
-                    .get_mut_unbounded(&key)
-                    .expect("Tried to replace empty key");
-
-                *old_value = value;
+                let value = ManuallyDrop::new(value);
+                target.unindex_slot(&key);
+                if let Some(old) = target.replace_value(&key, value, true) {
+                    reclaimer.dispose(old);
+                }
+                target.index_slot(&key);
             }
             Operation::Remove(key) => {
-                let _ = target.data.remove_unbounded(&key);
+                target.unindex_slot(&key);
+                target.remove_from_secondaries(&key, true);
+                if let Some(old) = target.remove_value(&key, true) {
+                    reclaimer.dispose(old);
+                }
             }
             Operation::Clear => {
-                target.data.clear();
+                target.clear_indexes();
+                target.clear_secondaries(true);
+                target.drain_values(|old| reclaimer.dispose(old));
+            }
+            Operation::Reserve(additional) => {
+                target.data.reserve(additional);
+            }
+            Operation::ShrinkToFit => {
+                target.data.shrink_to_fit();
+            }
+            Operation::AddIndex(def) => {
+                target.add_index(&def);
+            }
+            Operation::Secondary(secondary_op) => {
+                target.apply_secondary_second(secondary_op);
+            }
+            Operation::AddJoined(value, secondary_value) => {
+                let value = ManuallyDrop::new(value);
+                let secondary_value = ManuallyDrop::new(secondary_value);
+                let key = target.data.insert(value);
+                target.index_slot(&key);
+                target.secondary_update_second(
+                    key,
+                    ManuallyDrop::into_inner(secondary_value),
+                );
             }
         }
     }
 
-    /// refresh the write/read handle with the given operation
-    fn refresh_with_operation(&mut self, op: Operation<V>) -> Option<InnerKey> {
+    /// Describe the given operation for the change log, given the key it
+    /// added, replaced or removed, if any
+    fn change_kind(
+        op: &Operation<V>,
+        changed: Option<SlotMapKeyData>,
+    ) -> Option<ChangeKind> {
+        match op {
+            Operation::NoOp => None,
+            Operation::Add(_)
+            | Operation::AddAt(..)
+            | Operation::AddJoined(..) => changed.map(ChangeKind::Added),
+            Operation::Replace(..) => changed.map(ChangeKind::Replaced),
+            Operation::Remove(_) => changed.map(ChangeKind::Removed),
+            Operation::Clear => Some(ChangeKind::Cleared),
+            Operation::AddIndex(_)
+            | Operation::Secondary(_)
+            | Operation::Reserve(_)
+            | Operation::ShrinkToFit => None,
+        }
+    }
+
+    /// refresh the write/read handle with the given operation, panicking if
+    /// it can't be logged
+    pub(crate) fn refresh_with_operation(
+        &mut self,
+        op: Operation<V>,
+    ) -> Option<SlotMapKeyData> {
+        match self.try_refresh_with_operation(op) {
+            Ok(result) => result,
+            Err(e) => {
+                panic!("Failed to append to the write-ahead log: {}", e)
+            }
+        }
+    }
+
+    /// refresh the write/read handle with the given operation, or return the
+    /// error from logging it without publishing anything
+    pub(crate) fn try_refresh_with_operation(
+        &mut self,
+        op: Operation<V>,
+    ) -> io::Result<Option<SlotMapKeyData>> {
         // we need to wait until all epochs have changed since the swaps *or* until a "finished"
         // flag has been observed to be on for two subsequent iterations (there still may be some
         // readers present since we did the previous refresh)
@@ -226,11 +439,25 @@ where
         // NOTE: it is safe for us to hold the lock for the entire duration of the swap. we will
         // only block on pre-existing readers, and they are never waiting to push onto epochs
         // unless they have finished reading.
+        assert!(
+            !self.writing,
+            "Write handle used again after a write panicked part way through"
+        );
+        if self.log.is_some() || self.followers.is_some() {
+            self.record(&op)?;
+        }
+
         let epochs = Arc::clone(&self.epochs);
-        let mut epochs = epochs.lock().unwrap();
+        let mut epochs = lock_epochs(&epochs);
 
         self.wait(&mut epochs);
 
+        // until the swap below, w_handle can't be trusted if anything panics
+        self.writing = true;
+
+        let publishing = !matches!(op, Operation::NoOp);
+        let mut change = None;
+
         let result = {
             // all the readers have left!
             // we can safely bring the w_handle up to date.
@@ -238,18 +465,36 @@ where
 
             if let Some(last_op) = self.last_op.take() {
                 Self::run_operation_second(
-                    unsafe { w_handle.do_drop() },
+                    w_handle,
                     last_op,
+                    &mut self.reclaimer,
                 );
+                w_handle.mark_ready();
+            }
+            if let Some(meta) = self.last_meta.take() {
+                w_handle.replace_meta(ManuallyDrop::new(meta), true);
             }
 
+            // w_handle now holds what readers see, so even if nothing new is
+            // published it must carry the same generation once swapped in
+            w_handle.generation = self.generation;
+
             if let Operation::NoOp = &op {
                 None
             } else {
                 let result = Self::run_operation_first(w_handle, &op);
 
+                change = Self::change_kind(&op, result);
                 self.last_op = Some(op);
 
+                if let Some(meta) = self.meta.take() {
+                    w_handle
+                        .replace_meta(unsafe { meta.shallow_copy() }, false);
+                    self.last_meta = Some(meta);
+                }
+
+                self.generation += 1;
+                w_handle.generation = self.generation;
                 w_handle.mark_ready();
 
                 // w_handle (the old r_handle) is now fully up to date!
@@ -283,47 +528,621 @@ where
             self.last_epochs[ri] = epoch.load(atomic::Ordering::Acquire);
         }
 
+        // nothing below needs the epochs, and holding on to them would keep
+        // new readers waiting on a checkpoint
+        drop(epochs);
+
         // NOTE: at this point, there are likely still readers using the w_handle we got
         self.w_handle = Some(r_handle);
+        self.writing = false;
 
-        result
+        // let anyone waiting on the new version know it has arrived
+        if let Some(change) = change {
+            self.r_handle
+                .changes
+                .lock()
+                .unwrap()
+                .push(self.generation, change);
+        }
+        if publishing {
+            self.r_handle.signal.publish(self.generation);
+
+            if let Some(checkpoints) = self.checkpoints.as_ref() {
+                let checkpointed = checkpoints.run_if_due(
+                    &self.r_handle,
+                    self.generation,
+                    self.log.as_mut(),
+                );
+                if let Err(e) = checkpointed {
+                    self.checkpoint_error = Some(e);
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Log the given operation and send it to any followers, before it is
+    /// published
+    fn record(&mut self, op: &Operation<V>) -> io::Result<()> {
+        let published = match self.r_handle.handle() {
+            Some(published) => published,
+            None => return Ok(()),
+        };
+        let generation = self.generation + 1;
+
+        // a write that can't be logged is never published, or sent to
+        // followers
+        if let Some(log) = self.log.as_mut() {
+            log.append(op, &published, generation)?;
+        }
+        if let Some(followers) = self.followers.as_mut() {
+            followers.send(op, &published, generation);
+        }
+        Ok(())
+    }
+
+    /// Destroy the map instead of freezing it if this handle is dropped
+    /// during a panic
+    pub(crate) fn destroy_on_panic(&mut self) {
+        self.freeze_on_panic = false;
+    }
+
+    /// Make the next write publish the given generation. Used to replay
+    /// logged writes as the generations they were originally published as.
+    pub(crate) fn resume_at_generation(&mut self, generation: u64) {
+        debug_assert!(generation > self.generation);
+        self.generation = generation - 1;
+    }
+
+    /// Set the metadata published with the map.
+    ///
+    /// The new metadata rides along with the next write, so readers see it
+    /// become visible in the same version as that write's data, and never
+    /// see it next to data from before or after. If this is called more than
+    /// once between writes, only the last metadata is published.
+    pub fn set_meta(&mut self, meta: M) {
+        self.meta = Some(meta);
+    }
+
+    /// Returns the generation of the most recently published version of the
+    /// map. This is incremented every time a write becomes visible to readers.
+    pub fn generation(&self) -> u64 {
+        self.generation
     }
 
     pub(crate) fn refresh(&mut self) {
         let _ = self.refresh_with_operation(Operation::NoOp);
     }
 
+    /// Set the number of recent operations remembered for
+    /// [`ReadHandle::changes_since`]. Readers asking for changes since a
+    /// generation older than this window get a [`TooOld`] error.
+    ///
+    /// [`TooOld`]: crate::TooOld
+    pub fn set_change_log_capacity(&mut self, capacity: usize) {
+        self.r_handle.changes.lock().unwrap().set_capacity(capacity);
+    }
+
+    /// Add a secondary index over the values in the map.
+    ///
+    /// The index maps the key produced by `extractor` for each value to the
+    /// slots holding values with that key. It is stored in both copies of the
+    /// map and updated as part of every write, so lookups through
+    /// [`MapReadRef::lookup_index`] always agree with the data in the same
+    /// read. If an index with the same name already exists, it is replaced.
+    ///
+    /// [`MapReadRef::lookup_index`]: crate::MapReadRef::lookup_index
+    pub fn add_index<IK, F>(&mut self, name: impl Into<String>, extractor: F)
+    where
+        F: Fn(&V) -> IK + Send + Sync + 'static,
+        IK: Hash + Eq + Send + Sync + 'static,
+        V: 'static,
+    {
+        let def = IndexDef::new::<IK, HashMap<IK, Bucket>>(
+            name.into(),
+            Arc::new(extractor),
+        );
+        let _ = self.refresh_with_operation(Operation::AddIndex(def));
+    }
+
+    /// Same as [`WriteHandle::add_index`], but keeps the indexed keys in
+    /// order so the index can also be queried by range with
+    /// [`MapReadRef::lookup_index_range`].
+    ///
+    /// [`MapReadRef::lookup_index_range`]: crate::MapReadRef::lookup_index_range
+    pub fn add_ordered_index<IK, F>(
+        &mut self,
+        name: impl Into<String>,
+        extractor: F,
+    ) where
+        F: Fn(&V) -> IK + Send + Sync + 'static,
+        IK: Ord + Send + Sync + 'static,
+        V: 'static,
+    {
+        let def = IndexDef::new::<IK, BTreeMap<IK, Bucket>>(
+            name.into(),
+            Arc::new(extractor),
+        );
+        let _ = self.refresh_with_operation(Operation::AddIndex(def));
+    }
+
+    /// Make sure at least `additional` more values can be inserted without
+    /// either copy of the map reallocating.
+    ///
+    /// Like any other write, this is applied to the copy readers aren't using
+    /// right away, and to the other copy once readers have moved off of it, so
+    /// the allocation happens here and in the next write rather than in the
+    /// middle of some later insert.
+    pub fn reserve(&mut self, additional: usize) {
+        let _ = self.refresh_with_operation(Operation::Reserve(additional));
+    }
+
+    /// Shrink both copies of the map to use as little memory as they can
+    /// while keeping every existing slot. Empty slots are never released,
+    /// since they are what keep stale keys from finding newer values.
+    pub fn shrink_to_fit(&mut self) {
+        let _ = self.refresh_with_operation(Operation::ShrinkToFit);
+    }
+
+    /// Returns how much memory both copies of the map are using. See
+    /// [`MemoryStats`].
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.with_write_bytes(self.r_handle.memory_stats())
+    }
+
+    /// Same as [`WriteHandle::memory_stats`], but also adds up the heap memory
+    /// owned by every value.
+    pub fn memory_stats_deep(&self) -> MemoryStats
+    where
+        V: HeapSize,
+    {
+        self.with_write_bytes(self.r_handle.memory_stats_deep())
+    }
+
+    fn with_write_bytes(&self, mut stats: MemoryStats) -> MemoryStats {
+        stats.write_bytes = self.w_handle.as_ref().map(|w| w.bytes());
+        stats
+    }
+
+    /// Stop writing to the map, but leave its final state readable.
+    ///
+    /// Dropping a write handle takes the map away from every reader. Freezing
+    /// it instead keeps the last published version around for all existing
+    /// read handles and factories, and for the returned [`FrozenMap`], until
+    /// the last of them is dropped. Metadata set since the last write is
+    /// never published.
+    ///
+    /// A new writer can take over a frozen map with
+    /// [`ReadHandleFactory::try_claim_writer`]. Write handles dropped during a
+    /// panic freeze the map instead of destroying it, so a crashed writer can
+    /// be replaced the same way.
+    ///
+    /// That doesn't hold if the panic came from inside a write, say from an
+    /// index's extractor or from [`update`](WriteHandle::update) on a key
+    /// that isn't in the map. The writer's copy of the map is then left half
+    /// updated, so readers keep the last published version, but it can't be
+    /// claimed, and its memory is never freed. The write handle can't be
+    /// used again either, even if the panic is caught.
+    ///
+    /// [`ReadHandleFactory::try_claim_writer`]: crate::ReadHandleFactory::try_claim_writer
+    pub fn freeze(mut self) -> FrozenMap<K, P, V, M> {
+        let handle = self.r_handle.clone();
+        self.frozen = true;
+        drop(self);
+        FrozenMap::new(handle)
+    }
+
+    /// Returns when replaced and removed values are dropped. See
+    /// [`DropPolicy`].
+    pub fn drop_policy(&self) -> DropPolicy {
+        self.reclaimer.policy()
+    }
+
+    /// Drop every value waiting on the garbage list under
+    /// [`DropPolicy::Deferred`], and return how many there were. Under other
+    /// policies there is never anything to collect.
+    ///
+    /// A value only reaches the garbage list at the write after the one that
+    /// replaced or removed it, since until then the other copy of the map
+    /// still holds it.
+    pub fn collect_garbage(&mut self) -> usize {
+        self.reclaimer.collect()
+    }
+
+    /// Detach the write-ahead log attached with [`WriteHandle::log_to`], if
+    /// there is one. Writes made after this aren't logged.
+    pub fn take_log(&mut self) -> Option<WriteAheadLog> {
+        self.log.take().map(OperationLog::into_log)
+    }
+
+    /// Force everything appended to the attached write-ahead log to disk,
+    /// whatever its [`SyncPolicy`](crate::SyncPolicy). Does nothing if no log
+    /// is attached.
+    pub fn sync_log(&mut self) -> io::Result<()> {
+        self.log.as_mut().map_or(Ok(()), OperationLog::sync)
+    }
+
+    /// Returns how many followers this handle is streaming its writes to.
+    /// See [`WriteHandle::replicate_to`]. A follower that has failed or
+    /// fallen behind is still counted until the next write drops it.
+    pub fn follower_count(&self) -> usize {
+        self.followers.as_ref().map_or(0, Followers::len)
+    }
+
+    /// Returns true if the secondary map with the given id belongs to this
+    /// map
+    pub(crate) fn has_secondary(&self, id: u64) -> bool {
+        match self.r_handle.handle() {
+            Some(published) => published.secondaries.contains_key(&id),
+            None => false,
+        }
+    }
+
+    /// Deliver the given event to any subscribers
+    fn notify(&mut self, event: ChangeEvent<&K>) {
+        if let Some(subscribers) = self.subscribers.as_mut() {
+            subscribers.send(event);
+        }
+    }
+
+    /// Publish an operation that adds a value, and return the new key
+    pub(crate) fn insert_operation(&mut self, p: P, op: Operation<V>) -> K {
+        let key = self
+            .refresh_with_operation(op)
+            .expect("No key returned on insert");
+        let key = K::from((p, key));
+        self.notify(ChangeEvent::Inserted(&key));
+        key
+    }
+
+    /// Publish an operation that adds a value, and return the new key or the
+    /// error from logging it
+    fn try_insert_operation(
+        &mut self,
+        p: P,
+        op: Operation<V>,
+    ) -> io::Result<K> {
+        let key = self
+            .try_refresh_with_operation(op)?
+            .expect("No key returned on insert");
+        let key = K::from((p, key));
+        self.notify(ChangeEvent::Inserted(&key));
+        Ok(key)
+    }
+
     /// Insert the given value into the slot map and return the associated key
+    ///
+    /// Keys are handed out in an order that only depends on the writes made
+    /// to the map, so two maps given the same writes hand out the same keys:
+    ///
+    /// - The most recently emptied slot is filled first, at the generation
+    ///   after the one it was emptied at.
+    /// - Once there are no empty slots, a new one is added after the last,
+    ///   at generation 0.
+    /// - [`clear`](WriteHandle::clear) empties the filled slots lowest index
+    ///   first, so they are filled highest index first, before any slots
+    ///   that were already empty.
+    /// - Slots skipped over by [`insert_at`](WriteHandle::insert_at) count as
+    ///   emptied by it, lowest index first, and are filled at generation 0.
+    ///
+    /// Maps built with [`new_with_data`](crate::new_with_data), loaded from a
+    /// snapshot, or rebuilt from serialized data, carry on in the same order
+    /// as the map they came from.
+    ///
+    /// Panics if a [write-ahead log](WriteHandle::log_to) is attached and the
+    /// insert can't be appended to it. See [`WriteHandle::try_insert`].
     pub fn insert(&mut self, p: P, v: V) -> K {
-        self.refresh_with_operation(Operation::Add(v))
-            .expect("No key returned on insert")
-            .to_outer_key(p)
+        self.insert_operation(p, Operation::Add(v))
+    }
+
+    /// Like [`insert`](WriteHandle::insert), but if the insert can't be
+    /// appended to the attached write-ahead log, return the error instead of
+    /// panicking. Nothing is published then, and the value is dropped.
+    pub fn try_insert(&mut self, p: P, v: V) -> io::Result<K> {
+        self.try_insert_operation(p, Operation::Add(v))
     }
 
-    /// Replace the value of the given key with the given value.
+    /// Insert the given value under the given key, as handed out by another
+    /// map, for tools that replicate or replay a map from outside of it.
+    ///
+    /// This fails if the key's slot is filled, or has already been at the
+    /// key's generation or past it, since the value could then be reached
+    /// with keys that were handed out for something else. Inserting into an
+    /// empty slot takes it off the list of slots [`insert`] fills, and
+    /// inserting past the end adds empty slots for the ones skipped over,
+    /// which can later be filled at any generation. To keep a bad key from
+    /// taking up unbounded memory, this fails if it points more than 65536
+    /// slots past the end. See [`insert`] for the order keys are handed out
+    /// in afterwards.
+    ///
+    /// Panics if the key's generation is odd, since keys like that are never
+    /// handed out, or if the insert can't be appended to the attached
+    /// write-ahead log.
+    ///
+    /// [`insert`]: WriteHandle::insert
+    pub fn insert_at(
+        &mut self,
+        key: SlotMapKeyData,
+        p: P,
+        v: V,
+    ) -> Result<K, InsertAtError> {
+        assert!(
+            generation_of(&key) & 1 == 0,
+            "Key {:?} has an odd generation, so it was never handed out",
+            key
+        );
+
+        // until something is published, w_handle has no write to catch up on
+        let checked = match self.r_handle.handle() {
+            Some(published) => published.data.check_vacant(&key),
+            None => self
+                .w_handle
+                .as_ref()
+                .map(|w_handle| w_handle.data.check_vacant(&key))
+                .unwrap_or(Ok(())),
+        };
+        checked?;
+        Ok(self.insert_operation(p, Operation::AddAt(key, v)))
+    }
+
+    /// Replace the value of the given key with the given value. If the key
+    /// isn't in the map, nothing is published and the value is dropped.
+    ///
+    /// Panics if the update can't be appended to the attached write-ahead
+    /// log. See [`WriteHandle::try_update`].
     pub fn update(&mut self, k: K, v: V) {
-        let _ = self.refresh_with_operation(Operation::Replace(*k.borrow(), v));
+        if !self.holds_key(k.borrow()) {
+            return;
+        }
+        let op = Operation::Replace(*k.borrow(), v);
+        if self.refresh_with_operation(op).is_some() {
+            self.notify(ChangeEvent::Updated(&k));
+        }
+    }
+
+    /// Like [`update`](WriteHandle::update), but if the update can't be
+    /// appended to the attached write-ahead log, return the error instead of
+    /// panicking. Nothing is published then, and the value is dropped.
+    pub fn try_update(&mut self, k: K, v: V) -> io::Result<()> {
+        if !self.holds_key(k.borrow()) {
+            return Ok(());
+        }
+        let op = Operation::Replace(*k.borrow(), v);
+        if self.try_refresh_with_operation(op)?.is_some() {
+            self.notify(ChangeEvent::Updated(&k));
+        }
+        Ok(())
+    }
+
+    /// Returns true if the given key is in the map, checked before a write
+    /// so the write can't fail halfway through applying it
+    fn holds_key(&self, key: &SlotMapKeyData) -> bool {
+        // until something is published, w_handle has no write to catch up on
+        match self.r_handle.handle() {
+            Some(published) => published.data.contains_key(key),
+            None => self
+                .w_handle
+                .as_ref()
+                .map(|w_handle| w_handle.data.contains_key(key))
+                .unwrap_or(false),
+        }
     }
 
     /// Clear the slot map.
+    ///
+    /// Panics if the clear can't be appended to the attached write-ahead log.
+    /// See [`WriteHandle::try_clear`].
     pub fn clear(&mut self) {
         let _ = self.refresh_with_operation(Operation::Clear);
+        self.notify(ChangeEvent::Cleared);
+    }
+
+    /// Like [`clear`](WriteHandle::clear), but if the clear can't be appended
+    /// to the attached write-ahead log, return the error instead of
+    /// panicking. Nothing is published then.
+    pub fn try_clear(&mut self) -> io::Result<()> {
+        let _ = self.try_refresh_with_operation(Operation::Clear)?;
+        self.notify(ChangeEvent::Cleared);
+        Ok(())
     }
 
     /// Remove the value from the map for the given key
+    ///
+    /// Panics if the removal can't be appended to the attached write-ahead
+    /// log. See [`WriteHandle::try_remove`].
     pub fn remove(&mut self, k: &K) {
-        let _ = self.refresh_with_operation(Operation::Remove(*k.borrow()));
+        let op = Operation::Remove(*k.borrow());
+        if self.refresh_with_operation(op).is_some() {
+            self.notify(ChangeEvent::Removed(k));
+        }
+    }
+
+    /// Like [`remove`](WriteHandle::remove), but if the removal can't be
+    /// appended to the attached write-ahead log, return the error instead of
+    /// panicking. Nothing is published then.
+    pub fn try_remove(&mut self, k: &K) -> io::Result<()> {
+        let op = Operation::Remove(*k.borrow());
+        if self.try_refresh_with_operation(op)?.is_some() {
+            self.notify(ChangeEvent::Removed(k));
+        }
+        Ok(())
+    }
+}
+
+impl<K, P, V, M> WriteHandle<K, P, V, M>
+where
+    K: Key<P>,
+    V: ShallowCopy + Send + 'static,
+    M: ShallowCopy,
+{
+    /// Choose when replaced and removed values are dropped. See
+    /// [`DropPolicy`].
+    ///
+    /// Switching away from a policy first drops everything it was still
+    /// holding on to: the garbage list is emptied, and the reclamation thread
+    /// is joined once it has dropped everything sent to it.
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        if policy != self.drop_policy() {
+            mem::replace(&mut self.reclaimer, Reclaimer::new(policy)).finish();
+        }
+    }
+}
+
+impl<K, P, V, M> WriteHandle<K, P, V, M>
+where
+    K: Key<P>,
+    V: ShallowCopy + Encode,
+    M: ShallowCopy,
+{
+    /// Append every write made from now on to the given log before it is
+    /// published, replacing any log that was already attached.
+    ///
+    /// Inserts, updates, removals and clears are logged along with the keys
+    /// they hand out or touch, so [`recover`](crate::recover) can rebuild the
+    /// map from its last snapshot with the same keys. Take a snapshot or
+    /// [checkpoint](WriteHandle::checkpoint) before attaching a fresh log,
+    /// since the log only holds what came after. Metadata, indexes and
+    /// secondary maps aren't logged.
+    ///
+    /// A write that can't be appended to the log is never published. The
+    /// `try_` methods, such as [`try_insert`](WriteHandle::try_insert),
+    /// return the error and leave the handle usable, with the failed record
+    /// taken back out of the log. The other writes panic instead, which
+    /// freezes the map at its last logged write.
+    pub fn log_to(&mut self, log: WriteAheadLog) {
+        self.log = Some(OperationLog::new(log));
+    }
+}
+
+impl<K, P, V, M> WriteHandle<K, P, V, M>
+where
+    K: Key<P>,
+    V: ShallowCopy + Encode,
+    M: ShallowCopy + Encode,
+{
+    /// Checkpoint the map: atomically replace the snapshot at the given path
+    /// with one of the published map, then drop every write the snapshot
+    /// holds from the attached write-ahead log. Returns the generation the
+    /// snapshot was taken at.
+    ///
+    /// Both files are replaced by writing a new file next to them and
+    /// renaming it over the old one, so a crash leaves each of them whole.
+    /// If the crash comes between the two, [`recover`](crate::recover) skips
+    /// the logged writes the new snapshot already holds.
+    pub fn checkpoint(
+        &mut self,
+        snapshot: impl AsRef<Path>,
+    ) -> Result<u64, SnapshotError> {
+        wal::checkpoint(
+            &self.r_handle,
+            self.generation,
+            snapshot.as_ref(),
+            |r, writer| r.write_snapshot(writer),
+            self.log.as_mut(),
+        )?;
+        Ok(self.generation)
+    }
+
+    /// Checkpoint the map to the snapshot at the given path whenever the
+    /// attached write-ahead log has grown enough, as judged by the given
+    /// policy after each write is published. See
+    /// [`WriteHandle::checkpoint`].
+    ///
+    /// A checkpoint that fails leaves the log as it was, so it's tried again
+    /// after the next write. The write itself still succeeds; the error is
+    /// kept for [`WriteHandle::take_checkpoint_error`].
+    pub fn set_checkpoint_policy(
+        &mut self,
+        snapshot: impl Into<PathBuf>,
+        policy: CheckpointPolicy,
+    ) {
+        self.checkpoints = Some(Checkpoints::new(snapshot.into(), policy));
+    }
+
+    /// Returns the error from the most recent checkpoint made under the
+    /// [checkpoint policy](WriteHandle::set_checkpoint_policy) that failed,
+    /// if any has failed since this was last called.
+    pub fn take_checkpoint_error(&mut self) -> Option<SnapshotError> {
+        self.checkpoint_error.take()
+    }
+
+    /// Start streaming the map to a [`Follower`](crate::Follower) at the
+    /// other end of the given stream, such as a pipe or socket to another
+    /// process.
+    ///
+    /// A snapshot of the published map is sent first, then every insert,
+    /// update, removal and clear before it is published, along with the key
+    /// and generation it hands out or touches. Metadata, indexes and
+    /// secondary maps aren't sent.
+    ///
+    /// The stream is written to by a thread of its own, so a slow follower
+    /// never holds up the writer. A follower whose stream fails, or that
+    /// falls more than 1024 writes behind, is dropped at the next write. Its
+    /// thread exits once the stream returns from the write it is stuck in.
+    pub fn replicate_to(
+        &mut self,
+        stream: impl Write + Send + 'static,
+    ) -> Result<(), SnapshotError> {
+        self.replicate_to_with_capacity(stream, DEFAULT_REPLICATION_CAPACITY)
+    }
+
+    /// Same as [`WriteHandle::replicate_to`], but buffers at most `capacity`
+    /// writes before the follower is considered to have fallen behind.
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn replicate_to_with_capacity(
+        &mut self,
+        stream: impl Write + Send + 'static,
+        capacity: usize,
+    ) -> Result<(), SnapshotError> {
+        let published = self
+            .r_handle
+            .handle()
+            .ok_or(SnapshotError::MapUnavailable)?;
+        self.followers
+            .get_or_insert_with(Followers::new)
+            .add(&published, stream, capacity)
+    }
+}
+
+impl<K, P, V, M> WriteHandle<K, P, V, M>
+where
+    K: Key<P> + Clone,
+    V: ShallowCopy,
+    M: ShallowCopy,
+{
+    /// Subscribe to changes made through this handle.
+    ///
+    /// Each write that changes the map produces a [`ChangeEvent`] that is
+    /// delivered only after the change is visible to readers, so a subscriber
+    /// can read the new state of the affected key as soon as it receives the
+    /// event. Removing a key that isn't in the map produces nothing.
+    pub fn subscribe(&mut self) -> Subscription<K> {
+        self.subscribe_with_capacity(DEFAULT_SUBSCRIPTION_CAPACITY)
+    }
+
+    /// Same as [`WriteHandle::subscribe`], but buffers at most `capacity`
+    /// events before the subscriber is considered to be lagging.
+    pub fn subscribe_with_capacity(
+        &mut self,
+        capacity: usize,
+    ) -> Subscription<K> {
+        self.subscribers
+            .get_or_insert_with(Subscribers::new)
+            .subscribe(capacity)
     }
 }
 
 // allow using write handle for reads
 use std::ops::Deref;
-impl<K, P, V> Deref for WriteHandle<K, P, V>
+impl<K, P, V, M> Deref for WriteHandle<K, P, V, M>
 where
     K: Key<P>,
     V: ShallowCopy,
+    M: ShallowCopy,
 {
-    type Target = ReadHandle<K, P, V>;
+    type Target = ReadHandle<K, P, V, M>;
     fn deref(&self) -> &Self::Target {
         &self.r_handle
     }
@@ -0,0 +1,5 @@
+#[test]
+fn derive_rejects_unsupported_types() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
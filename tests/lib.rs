@@ -1,8 +1,18 @@
-use ev_slotmap::WriteHandle;
-use one_way_slot_map::{define_key_type, SlotMap};
+use ev_slotmap::{
+    ChangeEvent, CheckpointPolicy, Decode, DropPolicy, DumpFormat, Encode,
+    EvSecondaryMap, Follower, ForeignSecondaryMap, HeapSize, InsertAtError,
+    KeyStatus, RecvError, ShallowCopy, SnapshotError, SyncPolicy, TryRecvError,
+    WriteAheadLog, WriteHandle,
+};
+use one_way_slot_map::{define_key_type, SlotMap, SlotMapKeyData};
 use std::cell::RefCell;
+use std::future::Future;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+use std::time::Duration;
 use threadpool::ThreadPool;
 
 macro_rules! assert_match {
@@ -14,9 +24,14 @@ macro_rules! assert_match {
     };
 }
 
-define_key_type!(TestKey<()> : Default + Clone + Copy);
+define_key_type!(TestKey<()> : Default + Clone + Copy + Debug + PartialEq);
+
+fn key_data(key: &TestKey) -> SlotMapKeyData {
+    *std::borrow::Borrow::borrow(key)
+}
 
 #[test]
+#[allow(clippy::bool_assert_comparison, clippy::clone_on_copy)]
 fn it_works() {
     let x = 42;
 
@@ -243,3 +258,1537 @@ fn test_for_dropping_sanity() {
 
     drop_check.borrow().iter().for_each(|v| assert_eq!(*v, 1));
 }
+
+struct CountedDrop {
+    index: usize,
+    drops: Arc<Vec<AtomicUsize>>,
+}
+
+impl Drop for CountedDrop {
+    fn drop(&mut self) {
+        self.drops[self.index].fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn every_drop_policy_drops_values_exactly_once() {
+    let value_count = 1000;
+
+    for policy in [
+        DropPolicy::Inline,
+        DropPolicy::Deferred,
+        DropPolicy::Background,
+    ] {
+        let drops: Arc<Vec<_>> = Arc::new(
+            (0..value_count * 2).map(|_| AtomicUsize::new(0)).collect(),
+        );
+        let value = |index| {
+            Box::new(CountedDrop {
+                index,
+                drops: Arc::clone(&drops),
+            })
+        };
+
+        {
+            let (_, mut w) = ev_slotmap::new::<TestKey, (), _>();
+            w.set_drop_policy(policy);
+            assert_eq!(w.drop_policy(), policy);
+
+            let keys: Vec<_> =
+                (0..value_count).map(|i| w.insert((), value(i))).collect();
+
+            // replace the first half and remove the second half
+            for (i, key) in keys.iter().enumerate() {
+                if i < value_count / 2 {
+                    w.update(*key, value(value_count + i));
+                } else {
+                    w.remove(key);
+                }
+            }
+
+            if policy == DropPolicy::Deferred {
+                // the last removal is still held by the other copy
+                assert_eq!(w.collect_garbage(), value_count - 1);
+                assert_eq!(w.collect_garbage(), 0);
+            }
+
+            // switching policies drops anything still pending
+            w.set_drop_policy(DropPolicy::Inline);
+            w.clear();
+        }
+
+        for (index, count) in drops.iter().enumerate() {
+            let expected = if index < value_count * 3 / 2 { 1 } else { 0 };
+            assert_eq!(count.load(Ordering::SeqCst), expected);
+        }
+    }
+}
+
+#[test]
+fn generation_tracks_publishes() {
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), usize>();
+
+    // nothing has been published yet
+    assert_eq!(r.generation(), 0);
+    assert_eq!(w.generation(), 0);
+
+    let key = w.insert((), 1);
+    assert_eq!(r.generation(), 1);
+    assert_eq!(w.generation(), 1);
+
+    {
+        let read_ref = r.read().unwrap();
+        assert_eq!(read_ref.generation(), 1);
+    }
+
+    w.update(key, 2);
+    w.remove(&key);
+    w.clear();
+    assert_eq!(r.generation(), 4);
+    assert_eq!(w.generation(), 4);
+
+    // once the writer is gone, readers no longer see a published version
+    drop(w);
+    assert_eq!(r.generation(), 0);
+
+    // but freezing the map keeps the last published version, generation and
+    // all, since generations never go backwards
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), usize>();
+    let key = w.insert((), 1);
+    assert_eq!(r.generation(), 1);
+    let _frozen = w.freeze();
+    assert_eq!(r.generation(), 1);
+    assert_eq!(r.get(&key).map(|v| *v), Some(1));
+}
+
+#[test]
+fn wait_for_generation_blocks_until_publish() {
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), usize>();
+    let factory = r.factory();
+
+    // already reached generations return immediately
+    assert!(r.wait_for_generation(0));
+    assert!(!r.wait_for_generation_timeout(1, Duration::from_millis(10)));
+
+    let waiter = thread::spawn(move || {
+        let r = factory.handle();
+        let reached = r.wait_for_generation(2);
+        (reached, r.len())
+    });
+
+    w.insert((), 1);
+    w.insert((), 2);
+
+    assert_eq!(waiter.join().unwrap(), (true, 2));
+    assert!(r.wait_for_generation_timeout(2, Duration::from_millis(10)));
+
+    // dropping the writer releases anyone still waiting
+    let factory = r.factory();
+    let waiter = thread::spawn(move || factory.handle().wait_for_generation(3));
+    drop(w);
+    assert!(!waiter.join().unwrap());
+}
+
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[test]
+fn wait_for_generation_async() {
+    let (r, w) = ev_slotmap::new::<TestKey, (), usize>();
+    let w = Arc::new(Mutex::new(w));
+
+    let pending = r.wait_for_generation_async(1);
+
+    let writer = w.clone();
+    let publisher = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        writer.lock().unwrap().insert((), 42);
+    });
+
+    assert!(block_on(pending));
+    assert_eq!(r.len(), 1);
+    publisher.join().unwrap();
+
+    let pending = r.wait_for_generation_async(5);
+    drop(w);
+    assert!(!block_on(pending));
+}
+
+#[test]
+fn subscriptions_see_visible_changes() {
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), usize>();
+    let events = w.subscribe();
+
+    let key = w.insert((), 1);
+    w.update(key, 2);
+    w.remove(&key);
+    w.clear();
+
+    let first = events.recv().unwrap();
+    assert_eq!(first, ChangeEvent::Inserted(key));
+    assert_eq!(events.recv(), Ok(ChangeEvent::Updated(key)));
+    assert_eq!(events.recv(), Ok(ChangeEvent::Removed(key)));
+    assert_eq!(events.recv(), Ok(ChangeEvent::Cleared));
+    assert_eq!(events.try_recv(), Err(TryRecvError::Empty));
+
+    // writes that change nothing aren't reported
+    w.remove(&key);
+    assert_eq!(events.try_recv(), Err(TryRecvError::Empty));
+
+    // events are only delivered once the change can be read
+    let factory = r.factory();
+    let listener = thread::spawn(move || {
+        let r = factory.handle();
+        match events.recv() {
+            Ok(ChangeEvent::Inserted(k)) => *r.get(&k).unwrap(),
+            other => panic!("unexpected event {:?}", other),
+        }
+    });
+    w.insert((), 7);
+    assert_eq!(listener.join().unwrap(), 7);
+}
+
+#[test]
+fn lagging_subscriptions_are_signalled() {
+    let (_r, mut w) = ev_slotmap::new::<TestKey, (), usize>();
+    let events = w.subscribe_with_capacity(2);
+
+    let keys: Vec<_> = (0..5).map(|i| w.insert((), i)).collect();
+
+    assert_eq!(events.recv(), Ok(ChangeEvent::Inserted(keys[0])));
+    assert_eq!(events.recv(), Ok(ChangeEvent::Inserted(keys[1])));
+    assert_eq!(events.recv(), Err(RecvError::Lagged(3)));
+
+    w.remove(&keys[4]);
+    assert_eq!(events.recv(), Ok(ChangeEvent::Removed(keys[4])));
+
+    drop(w);
+    assert_eq!(events.recv(), Err(RecvError::Closed));
+}
+
+#[test]
+fn changes_since_reports_touched_slots() {
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), usize>();
+    w.set_change_log_capacity(4);
+
+    let a = w.insert((), 1);
+    let b = w.insert((), 2);
+    let since = r.generation();
+
+    let c = w.insert((), 3);
+    w.update(a, 10);
+
+    let changes = r.changes_since(since).unwrap();
+    assert_eq!(changes.generation(), r.generation());
+    assert!(!changes.was_cleared());
+    assert_eq!(changes.len(), 2);
+    assert!(changes.contains(&key_data(&a)));
+    assert!(changes.contains(&key_data(&c)));
+    assert!(!changes.contains(&key_data(&b)));
+
+    // nothing has happened since the latest generation
+    assert!(r.changes_since(r.generation()).unwrap().is_empty());
+
+    w.clear();
+    let d = w.insert((), 4);
+    let changes = r.changes_since(since).unwrap();
+    assert!(changes.was_cleared());
+    let keys: Vec<SlotMapKeyData> = changes.keys().copied().collect();
+    assert_eq!(keys, vec![key_data(&d)]);
+
+    // the oldest operations have now been forgotten
+    let err = r.changes_since(0).unwrap_err();
+    assert_eq!(err.oldest, 2);
+    assert!(r.changes_since(2).is_ok());
+}
+
+#[test]
+fn secondary_indexes_follow_writes() {
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), (String, u32)>();
+
+    let alice = w.insert((), ("alice".to_owned(), 30));
+    let bob = w.insert((), ("bob".to_owned(), 25));
+
+    // indexes are built from the existing data
+    w.add_index("name", |v: &(String, u32)| v.0.clone());
+    w.add_ordered_index("age", |v: &(String, u32)| v.1);
+
+    let carol = w.insert((), ("carol".to_owned(), 30));
+
+    let ages_of = |name: &str| -> Vec<u32> {
+        let read_ref = r.read().unwrap();
+        read_ref
+            .lookup_index("name", &name.to_owned())
+            .unwrap()
+            .map(|v| v.1)
+            .collect()
+    };
+
+    assert_eq!(ages_of("alice"), vec![30]);
+    assert_eq!(ages_of("carol"), vec![30]);
+    assert_eq!(ages_of("dave"), Vec::<u32>::new());
+
+    {
+        let read_ref = r.read().unwrap();
+        let mut thirty: Vec<SlotMapKeyData> = read_ref
+            .lookup_index_raw("age", &30u32)
+            .unwrap()
+            .map(|(k, _)| k)
+            .collect();
+        thirty.sort_by_key(|k| u64::from(*k));
+        assert_eq!(thirty, vec![key_data(&alice), key_data(&carol)]);
+
+        let names: Vec<&str> = read_ref
+            .lookup_index_range("age", 26u32..)
+            .unwrap()
+            .map(|v| v.0.as_str())
+            .collect();
+        assert_eq!(names.len(), 2);
+
+        // unknown names and mismatched key types find nothing
+        assert!(read_ref.lookup_index("height", &1u32).is_none());
+        assert!(read_ref.lookup_index("age", &1u64).is_none());
+        assert!(read_ref.lookup_index_range("name", 1u32..).is_none());
+    }
+
+    w.update(alice, ("alice".to_owned(), 31));
+    w.remove(&bob);
+
+    assert_eq!(ages_of("alice"), vec![31]);
+    assert_eq!(ages_of("bob"), Vec::<u32>::new());
+    {
+        let read_ref = r.read().unwrap();
+        assert_eq!(read_ref.lookup_index("age", &30u32).unwrap().count(), 1);
+    }
+
+    w.clear();
+    assert_eq!(ages_of("carol"), Vec::<u32>::new());
+
+    // both copies of the index keep up with the data
+    w.insert((), ("carol".to_owned(), 40));
+    w.insert((), ("erin".to_owned(), 40));
+    assert_eq!(ages_of("carol"), vec![40]);
+}
+
+#[test]
+fn secondary_maps_share_keys() {
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), String>();
+    let flags = EvSecondaryMap::<TestKey, Arc<bool>>::new(&mut w);
+    let tracker = Arc::new(true);
+
+    let a = w.insert((), "a".to_owned());
+    let b = flags
+        .insert_with(&mut w, (), "b".to_owned(), tracker.clone())
+        .unwrap();
+    flags.insert(&mut w, &a, tracker.clone()).unwrap();
+
+    {
+        let read_ref = r.read().unwrap();
+        let (value, flag) = read_ref.get_joined(&flags, &b);
+        assert_eq!(value.map(String::as_str), Some("b"));
+        assert_eq!(flag.map(|f| **f), Some(true));
+        assert!(read_ref.get_secondary(&flags, &a).is_some());
+    }
+    assert_eq!(Arc::strong_count(&tracker), 3);
+
+    // replacing a secondary value drops the old one exactly once, as soon as
+    // the next write brings the other copy of the map up to date
+    flags.insert(&mut w, &a, Arc::new(false)).unwrap();
+    assert_eq!(
+        r.read().unwrap().get_secondary(&flags, &a).map(|f| **f),
+        Some(false)
+    );
+    w.insert((), "x".to_owned());
+    assert_eq!(Arc::strong_count(&tracker), 2);
+
+    // removing the primary key takes the secondary value with it
+    w.remove(&b);
+    w.insert((), "c".to_owned());
+    {
+        let read_ref = r.read().unwrap();
+        assert_eq!(read_ref.get_joined(&flags, &b), (None, None));
+    }
+    assert_eq!(Arc::strong_count(&tracker), 1);
+
+    // secondary values can't be stored for keys the primary map doesn't have
+    flags.insert(&mut w, &b, tracker.clone()).unwrap();
+    assert!(r.read().unwrap().get_secondary(&flags, &b).is_none());
+
+    flags.insert(&mut w, &a, tracker.clone()).unwrap();
+    flags.remove(&mut w, &a).unwrap();
+    w.insert((), "d".to_owned());
+    assert_eq!(Arc::strong_count(&tracker), 1);
+
+    flags.insert(&mut w, &a, tracker.clone()).unwrap();
+    w.clear();
+    w.insert((), "e".to_owned());
+    assert_eq!(Arc::strong_count(&tracker), 1);
+
+    // a secondary map can't be written through another map's handle, and
+    // trying leaves both maps as they were
+    let (other_r, mut other) = ev_slotmap::new::<TestKey, (), String>();
+    let c = other.insert((), "c".to_owned());
+    assert_eq!(
+        flags.insert(&mut other, &c, tracker.clone()),
+        Err(ForeignSecondaryMap)
+    );
+    assert_eq!(flags.remove(&mut other, &c), Err(ForeignSecondaryMap));
+    assert_eq!(
+        flags.insert_with(&mut other, (), "d".to_owned(), tracker.clone()),
+        Err(ForeignSecondaryMap)
+    );
+    assert_eq!(other.generation(), 1);
+    assert_eq!(other_r.len(), 1);
+    assert_eq!(Arc::strong_count(&tracker), 1);
+    drop(other);
+
+    flags.insert(&mut w, &a, tracker.clone()).unwrap();
+    drop(w);
+    assert_eq!(Arc::strong_count(&tracker), 1);
+}
+
+#[test]
+fn capacity_is_applied_to_both_copies() {
+    let (r, mut w) = ev_slotmap::with_capacity::<TestKey, (), usize>(100);
+
+    let first = w.insert((), 0);
+    assert!(r.capacity() >= 100);
+
+    // the reserve reaches the second copy with the next write
+    w.reserve(1000);
+    assert!(r.capacity() >= 1000);
+    w.remove(&first);
+    assert!(r.capacity() >= 1000);
+
+    for i in 0..10 {
+        let _ = w.insert((), i);
+    }
+
+    w.shrink_to_fit();
+    assert!(r.capacity() < 1000);
+    assert_eq!(r.len(), 10);
+    assert!(r.get(&first).is_none());
+}
+
+#[test]
+fn new_with_data_keeps_keys() {
+    let tracker = Arc::new(());
+    let mut data = SlotMap::<TestKey, (), Arc<()>>::new();
+    let removed = data.insert((), tracker.clone());
+    let kept = data.insert((), tracker.clone());
+    let _ = data.remove(&removed);
+
+    let (r, mut w) = ev_slotmap::new_with_data(data);
+    assert_eq!(Arc::strong_count(&tracker), 2);
+    assert_eq!(r.len(), 1);
+    assert!(r.read().unwrap().contains_key(&kept));
+
+    // the slot that was already empty is filled again, at a new generation
+    let added = w.insert((), tracker.clone());
+    assert_eq!(
+        u64::from(key_data(&added)),
+        u64::from(key_data(&removed)) + (2 << 40)
+    );
+    assert!(!r.read().unwrap().contains_key(&removed));
+
+    drop(w);
+    assert_eq!(Arc::strong_count(&tracker), 1);
+
+    // new values go where the slot map would have put them, whether or not
+    // it has any live values left
+    for live in [true, false] {
+        let mut data = SlotMap::<TestKey, (), usize>::new();
+        let keys: Vec<_> = (0..8).map(|i| data.insert((), i)).collect();
+        for i in [5, 1, 6, 2] {
+            let _ = data.remove(&keys[i]);
+        }
+        if !live {
+            data.clear();
+        }
+        let mut twin = SlotMap::<TestKey, (), usize>::new();
+        let keys: Vec<_> = (0..8).map(|i| twin.insert((), i)).collect();
+        for i in [5, 1, 6, 2] {
+            let _ = twin.remove(&keys[i]);
+        }
+        if !live {
+            twin.clear();
+        }
+
+        let (_, mut w) = ev_slotmap::new_with_data(data);
+        for i in 0..10 {
+            assert_eq!(
+                key_data(&w.insert((), i)),
+                key_data(&twin.insert((), i))
+            );
+        }
+    }
+
+    // values left behind in the slot map by removals are dropped exactly
+    // once, right away, and the live ones once the map is gone
+    let count = 300;
+    let drops = Arc::new((0..count).map(|_| AtomicUsize::new(0)).collect());
+    let mut data = SlotMap::<TestKey, (), Box<CountedDrop>>::new();
+    let keys: Vec<_> = (0..count)
+        .map(|index| {
+            let drops = Arc::clone(&drops);
+            data.insert((), Box::new(CountedDrop { index, drops }))
+        })
+        .collect();
+    for key in keys.iter().step_by(3) {
+        let _ = data.remove(key);
+    }
+
+    let (r, w) = ev_slotmap::new_with_data(data);
+    assert_eq!(r.len(), count - count / 3);
+    for (index, drop_count) in drops.iter().enumerate() {
+        let expected = if index % 3 == 0 { 1 } else { 0 };
+        assert_eq!(drop_count.load(Ordering::SeqCst), expected);
+    }
+    assert_eq!(r.get(&keys[1]).map(|v| v.index), Some(1));
+    drop(w);
+    assert!(drops.iter().all(|c| c.load(Ordering::SeqCst) == 1));
+
+    // the same goes for zero sized values
+    static UNIT_DROPS: AtomicUsize = AtomicUsize::new(0);
+    struct UnitDrop;
+    impl Drop for UnitDrop {
+        fn drop(&mut self) {
+            UNIT_DROPS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    impl ShallowCopy for UnitDrop {
+        unsafe fn shallow_copy(&self) -> std::mem::ManuallyDrop<Self> {
+            std::mem::ManuallyDrop::new(UnitDrop)
+        }
+    }
+
+    let mut data = SlotMap::<TestKey, (), UnitDrop>::new();
+    let keys: Vec<_> = (0..5).map(|_| data.insert((), UnitDrop)).collect();
+    let _ = data.remove(&keys[1]);
+    let _ = data.remove(&keys[3]);
+    let (r, w) = ev_slotmap::new_with_data(data);
+    assert_eq!(UNIT_DROPS.load(Ordering::SeqCst), 2);
+    assert_eq!(r.len(), 3);
+    assert!(r.contains_key(&keys[4]));
+    assert!(!r.contains_key(&keys[3]));
+    drop(w);
+    assert_eq!(UNIT_DROPS.load(Ordering::SeqCst), 5);
+}
+
+#[test]
+fn lookup_status_explains_missing_values() {
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), usize>();
+    let never = TestKey::from(((), SlotMapKeyData::from(1000)));
+    assert_eq!(r.lookup_status(&never), KeyStatus::MapUninitialized);
+
+    let key = w.insert((), 1);
+    assert_eq!(r.lookup_status(&key), KeyStatus::Live);
+    assert_eq!(r.lookup_status(&never), KeyStatus::OutOfRange);
+
+    w.remove(&key);
+    assert_eq!(
+        r.lookup_status(&key),
+        KeyStatus::Stale {
+            current_generation: 1
+        }
+    );
+
+    let _ = w.insert((), 2);
+    assert_eq!(
+        r.lookup_status(&key),
+        KeyStatus::Stale {
+            current_generation: 2
+        }
+    );
+
+    drop(w);
+    assert_eq!(r.lookup_status(&key), KeyStatus::MapDestroyed);
+
+    // slots that were empty in a map the ev slotmap was built from keep the
+    // generation they were left at
+    let mut data = SlotMap::<TestKey, (), usize>::new();
+    let removed = data.insert((), 1);
+    let _ = data.insert((), 2);
+    let _ = data.remove(&removed);
+    let (r, _w) = ev_slotmap::new_with_data(data);
+    assert_eq!(
+        r.lookup_status(&removed),
+        KeyStatus::Stale {
+            current_generation: 1
+        }
+    );
+}
+
+#[test]
+fn multi_map_keeps_a_bag_per_key() {
+    let (r, mut w) = ev_slotmap::new_multi::<TestKey, (), Arc<usize>>();
+    let topic = w.insert(());
+    let other = w.insert(());
+    assert_eq!(r.get(&topic).unwrap().len(), 0);
+
+    let tracker = Arc::new(0);
+    for i in 1..=3 {
+        w.push(&topic, Arc::new(i));
+        w.push(&other, tracker.clone());
+    }
+    let values: Vec<usize> =
+        r.get(&topic).unwrap().iter().map(|v| **v).collect();
+    assert_eq!(values, vec![1, 2, 3]);
+
+    assert!(w.remove_value(&topic, &Arc::new(2)));
+    assert!(!w.remove_value(&topic, &Arc::new(5)));
+    let values: Vec<usize> =
+        r.get(&topic).unwrap().iter().map(|v| **v).collect();
+    assert_eq!(values, vec![1, 3]);
+
+    w.clear_key(&other);
+    assert_eq!(r.get(&other).unwrap().len(), 0);
+    assert!(r.contains_key(&other));
+
+    // values are dropped once both copies have let go of them
+    w.remove(&topic);
+    assert!(r.get(&topic).is_none());
+    assert_eq!(Arc::strong_count(&tracker), 1);
+    assert_eq!(r.len(), 1);
+}
+
+#[test]
+fn meta_is_published_with_the_next_write() {
+    let (r, mut w) =
+        ev_slotmap::with_meta::<TestKey, (), usize, Arc<u64>>(Arc::new(0));
+    let first = w.insert((), 1);
+    assert_eq!(**r.meta().unwrap(), 0);
+
+    let offset = Arc::new(10);
+    w.set_meta(offset.clone());
+    assert_eq!(**r.meta().unwrap(), 0);
+
+    let second = w.insert((), 2);
+    {
+        let read = r.read().unwrap();
+        assert_eq!(**read.meta(), 10);
+        assert!(read.contains_key(&first));
+        assert!(read.contains_key(&second));
+    }
+
+    w.set_meta(Arc::new(20));
+    w.remove(&first);
+    assert_eq!(**r.meta().unwrap(), 20);
+
+    // the old metadata is dropped once both copies have moved on from it
+    let _ = w.insert((), 3);
+    assert_eq!(Arc::strong_count(&offset), 1);
+
+    drop(w);
+    assert!(r.meta().is_none());
+}
+
+#[derive(ShallowCopy, Debug, PartialEq)]
+struct Subscriber<T> {
+    name: String,
+    topics: Vec<u32>,
+    state: Arc<T>,
+}
+
+#[derive(ShallowCopy, Debug, PartialEq)]
+enum Entry<T> {
+    Empty,
+    Single(Subscriber<T>),
+    Pair(Box<u32>, Option<String>),
+}
+
+#[test]
+fn derived_shallow_copy_values() {
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), Entry<usize>>();
+    let state = Arc::new(7);
+
+    let empty = w.insert((), Entry::Empty);
+    let single = w.insert(
+        (),
+        Entry::Single(Subscriber {
+            name: "a".into(),
+            topics: vec![1, 2],
+            state: state.clone(),
+        }),
+    );
+    let pair = w.insert((), Entry::Pair(Box::new(3), Some("b".into())));
+
+    assert_eq!(*r.get(&empty).unwrap(), Entry::Empty);
+    assert_eq!(
+        *r.get(&pair).unwrap(),
+        Entry::Pair(Box::new(3), Some("b".into()))
+    );
+    match &*r.get(&single).unwrap() {
+        Entry::Single(subscriber) => {
+            assert_eq!(subscriber.name, "a");
+            assert_eq!(subscriber.topics, vec![1, 2]);
+        }
+        other => panic!("unexpected entry {:?}", other),
+    }
+
+    // only one of the two copies of each value is ever dropped
+    assert_eq!(Arc::strong_count(&state), 2);
+    drop(w);
+    assert_eq!(Arc::strong_count(&state), 1);
+}
+
+#[test]
+fn cloned_mode_stores_any_value() {
+    // a RefCell can't be shallow copied safely
+    let (r, mut w) = ev_slotmap::new_cloned::<TestKey, (), RefCell<Vec<u32>>>();
+    let key = w.insert((), RefCell::new(vec![1, 2]));
+
+    assert_eq!(*r.get(&key).unwrap().borrow(), vec![1, 2]);
+    assert_eq!(r.get_cloned(&key).unwrap().into_inner(), vec![1, 2]);
+
+    w.update(key, RefCell::new(vec![3]));
+    assert_eq!(*r.get(&key).unwrap().borrow(), vec![3]);
+
+    w.remove(&key);
+    assert!(!r.contains_key(&key));
+    assert!(r.is_empty());
+}
+
+#[test]
+fn rcu_mode_frees_values_after_readers_move_on() {
+    let drops = RefCell::new(vec![0; 3]);
+    let drop_checker = |index: usize| drops.borrow_mut()[index] += 1;
+    let value = |index| DropCheckingType {
+        index,
+        inner: &drop_checker,
+    };
+
+    let (r, mut w) = ev_slotmap::new_rcu::<TestKey, (), _>();
+    let key = w.insert((), value(0));
+
+    {
+        let guard = r.get(&key).unwrap();
+
+        // the old value is unpublished, but the guard can still see it
+        w.update(key, value(1));
+        w.reclaim();
+        assert_eq!(w.pending_reclamation(), 1);
+        assert_eq!(guard.index, 0);
+        assert_eq!(drops.borrow()[0], 0);
+    }
+
+    w.reclaim();
+    assert_eq!(w.pending_reclamation(), 0);
+    assert_eq!(drops.borrow()[0], 1);
+    assert_eq!(r.get(&key).unwrap().index, 1);
+
+    // nothing is reading, so removed values are freed right away
+    w.remove(&key);
+    assert_eq!(w.pending_reclamation(), 0);
+    assert_eq!(*drops.borrow(), vec![1, 1, 0]);
+
+    let other = w.insert((), value(2));
+    drop(w);
+    assert!(!r.contains_key(&other));
+    assert_eq!(*drops.borrow(), vec![1, 1, 1]);
+
+    let mut data = SlotMap::<TestKey, (), String>::new();
+    let key = data.insert((), "large".to_string());
+    let (r, _w) = ev_slotmap::new_rcu_with_data(data);
+    assert_eq!(*r.get(&key).unwrap(), "large");
+}
+
+#[test]
+fn memory_stats_cover_both_copies() {
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), String>();
+
+    // nothing is published yet, but both handles are registered readers
+    let stats = r.memory_stats();
+    assert_eq!(stats.occupied, 0);
+    assert_eq!(stats.readers, 2);
+    assert_eq!(stats.write_bytes, None);
+
+    w.reserve(8);
+    let key = w.insert((), String::with_capacity(100));
+    w.insert((), String::with_capacity(20));
+    w.remove(&key);
+
+    let stats = w.memory_stats_deep();
+    assert!(stats.slot_capacity >= 8);
+    assert_eq!(stats.occupied, 1);
+    assert_eq!(stats.free_slots, 1);
+    assert!(stats.published_bytes > 0);
+    assert_eq!(stats.write_bytes, Some(stats.published_bytes));
+    assert_eq!(stats.value_heap_bytes, Some(20));
+    assert_eq!(stats.total_bytes(), 2 * stats.published_bytes + 20);
+
+    assert_eq!(r.memory_stats().value_heap_bytes, None);
+    let strings = vec![String::from("abc")];
+    assert_eq!(strings.heap_size(), std::mem::size_of::<String>() + 3);
+}
+
+#[test]
+fn frozen_map_outlives_its_writer() {
+    let drops: Arc<Vec<_>> =
+        Arc::new((0..2).map(|_| AtomicUsize::new(0)).collect());
+    let value = |index| {
+        Box::new(CountedDrop {
+            index,
+            drops: Arc::clone(&drops),
+        })
+    };
+
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), _>();
+    let factory = r.factory();
+    let key = w.insert((), value(0));
+    w.update(key, value(1));
+
+    let frozen = w.freeze();
+    assert!(r.is_frozen());
+    assert!(!r.is_destroyed());
+    assert_eq!(r.get(&key).unwrap().index, 1);
+    assert_eq!(frozen.handle().len(), 1);
+
+    // readers made after the freeze see the same final state
+    let late = thread::spawn(move || factory.handle().get(&key).unwrap().index);
+    assert_eq!(late.join().unwrap(), 1);
+
+    drop(frozen);
+    drop(r);
+    assert_eq!(drops[0].load(Ordering::SeqCst), 1);
+    assert_eq!(drops[1].load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn new_writer_takes_over_after_a_crash() {
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), usize>();
+    let factory = r.factory();
+    let key = w.insert((), 1);
+
+    // nobody can claim the map while its writer is around
+    assert!(factory.try_claim_writer().is_none());
+
+    let crashed = thread::spawn(move || {
+        w.update(key, 2);
+        panic!("writer crashed");
+    });
+    assert!(crashed.join().is_err());
+
+    // readers keep serving the last published state
+    assert!(r.is_frozen());
+    assert_eq!(*r.get(&key).unwrap(), 2);
+
+    let mut w = factory.try_claim_writer().unwrap();
+    assert!(factory.try_claim_writer().is_none());
+    assert!(!r.is_frozen());
+    assert_eq!(w.generation(), 2);
+
+    let other = w.insert((), 3);
+    assert!(r.wait_for_generation(3));
+    assert_eq!(*r.get(&key).unwrap(), 2);
+    assert_eq!(*r.get(&other).unwrap(), 3);
+
+    // a writer that is dropped normally destroys the map for good
+    drop(w);
+    assert!(r.is_destroyed());
+    assert!(factory.try_claim_writer().is_none());
+
+    // a panic inside a write leaves the last published version readable, but
+    // nobody can take over from a half applied write
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), usize>();
+    let factory = r.factory();
+    let key = w.insert((), 1);
+    let removed = w.insert((), 2);
+    w.remove(&removed);
+
+    // updating a key that isn't in the map is caught before the write starts
+    w.update(removed, 3);
+    w.try_update(removed, 3).unwrap();
+    assert!(!r.is_frozen());
+    assert_eq!(w.generation(), 3);
+    assert!(r.get(&removed).is_none());
+
+    w.add_index("value", |v: &usize| {
+        assert_ne!(*v, 4, "extractor failed");
+        *v
+    });
+    let crashed = thread::spawn(move || {
+        w.insert((), 4);
+    });
+    assert!(crashed.join().is_err());
+
+    assert!(r.is_frozen());
+    assert!(!r.is_destroyed());
+    assert_eq!(r.generation(), 4);
+    assert_eq!(*r.get(&key).unwrap(), 1);
+    assert!(factory.try_claim_writer().is_none());
+    let r2 = factory.handle();
+    assert_eq!(r2.len(), 1);
+    drop(r2);
+
+    // an rcu writer that goes down with a panic takes the map with it, since
+    // it frees the values
+    let (r, mut w) = ev_slotmap::new_rcu::<TestKey, (), String>();
+    let key = w.insert((), "a".to_owned());
+    let crashed = thread::spawn(move || {
+        w.update(key, "b".to_owned());
+        panic!("writer crashed");
+    });
+    assert!(crashed.join().is_err());
+    assert!(r.is_destroyed());
+    assert!(r.get(&key).is_none());
+}
+
+#[test]
+fn panicking_index_extractors_leak_values_readers_can_see() {
+    let drops: Arc<Vec<_>> =
+        Arc::new((0..3).map(|_| AtomicUsize::new(0)).collect());
+    let value = |index| {
+        Box::new(CountedDrop {
+            index,
+            drops: Arc::clone(&drops),
+        })
+    };
+
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), Box<CountedDrop>>();
+    let armed = Arc::new(AtomicBool::new(false));
+    let calls = Arc::new(AtomicUsize::new(0));
+    #[allow(clippy::borrowed_box)]
+    let extractor = {
+        let armed = Arc::clone(&armed);
+        let calls = Arc::clone(&calls);
+        move |v: &Box<CountedDrop>| {
+            if v.index == 0
+                && armed.load(Ordering::SeqCst)
+                && calls.fetch_add(1, Ordering::SeqCst) == 1
+            {
+                panic!("extractor failed");
+            }
+            v.index
+        }
+    };
+    w.add_index("index", extractor);
+    let key = w.insert((), value(0));
+    w.insert((), value(2));
+    armed.store(true, Ordering::SeqCst);
+
+    // the first copy takes the new value fine, then the second copy panics
+    // while the new value is only held by the pending operation
+    w.update(key, value(1));
+    assert_eq!(r.get(&key).unwrap().index, 1);
+    let next = value(2);
+    let crashed = thread::spawn(move || {
+        w.insert((), next);
+    });
+    assert!(crashed.join().is_err());
+
+    assert!(r.is_frozen());
+    assert_eq!(r.get(&key).unwrap().index, 1);
+    assert_eq!(drops[1].load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn snapshots_restore_keys_and_catch_corruption() {
+    let (r, mut w) = ev_slotmap::with_meta::<TestKey, (), String, u64>(1);
+    let keys: Vec<_> = (0..4).map(|i| w.insert((), i.to_string())).collect();
+    w.remove(&keys[2]);
+    w.set_meta(42);
+    w.update(keys[0], "zero".to_string());
+
+    let mut snapshot = Vec::new();
+    r.write_snapshot(&mut snapshot).unwrap();
+
+    let (r2, mut w2) =
+        ev_slotmap::load_snapshot::<TestKey, (), String, u64>(&snapshot[..])
+            .unwrap();
+    assert_eq!(r2.len(), 3);
+    assert_eq!(*r2.get(&keys[0]).unwrap(), "zero");
+    assert_eq!(*r2.get(&keys[3]).unwrap(), "3");
+    assert_eq!(r2.lookup_status(&keys[2]), r.lookup_status(&keys[2]));
+    assert_eq!(*r2.meta().unwrap(), 42);
+    assert_eq!(
+        w.insert((), "a".to_string()),
+        w2.insert((), "a".to_string())
+    );
+
+    let mut corrupt = snapshot.clone();
+    let last = corrupt.len() - 5;
+    corrupt[last] ^= 1;
+    assert_match!(
+        ev_slotmap::load_snapshot::<TestKey, (), String, u64>(&corrupt[..]),
+        Err(SnapshotError::ChecksumMismatch)
+    );
+    assert_match!(
+        ev_slotmap::load_snapshot::<TestKey, (), String, u64>(&b"nonsense"[..]),
+        Err(SnapshotError::NotASnapshot)
+    );
+    assert_match!(
+        ev_slotmap::load_snapshot::<TestKey, (), String, u64>(&snapshot[..20]),
+        Err(SnapshotError::Io(_))
+    );
+}
+
+#[test]
+fn write_ahead_log_recovers_the_same_keys() {
+    let path = std::env::temp_dir()
+        .join(format!("ev_slotmap-wal-{}.log", std::process::id()));
+
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), String>();
+    let kept = w.insert((), "kept".to_string());
+    let gone = w.insert((), "gone".to_string());
+
+    let mut snapshot = Vec::new();
+    r.write_snapshot(&mut snapshot).unwrap();
+    let log = WriteAheadLog::create(&path).unwrap();
+    w.log_to(log.with_sync_policy(SyncPolicy::Every(2)));
+
+    w.remove(&gone);
+    w.update(kept, "updated".to_string());
+    let reused = w.insert((), "reused".to_string());
+    w.clear();
+    let keys: Vec<_> = (0..3).map(|i| w.insert((), i.to_string())).collect();
+    w.remove(&keys[1]);
+    w.sync_log().unwrap();
+
+    let mut bytes = std::fs::read(&path).unwrap();
+    let (r2, mut w2) = ev_slotmap::recover::<TestKey, (), String, ()>(
+        &snapshot[..],
+        &bytes[..],
+    )
+    .unwrap();
+    assert_eq!(r2.len(), 2);
+    assert_eq!(*r2.get(&keys[0]).unwrap(), "0");
+    assert_eq!(*r2.get(&keys[2]).unwrap(), "2");
+    for key in [kept, gone, reused, keys[1]].iter() {
+        assert_eq!(r2.lookup_status(key), r.lookup_status(key));
+    }
+    assert_eq!(
+        w.insert((), "next".to_string()),
+        w2.insert((), "next".to_string())
+    );
+
+    // a crash part way through appending leaves a torn record behind, which
+    // recovery ignores
+    let whole = bytes.len();
+    w.take_log().unwrap();
+    bytes.extend_from_slice(&[9, 0, 0]);
+    let (r3, _w3) = ev_slotmap::recover::<TestKey, (), String, ()>(
+        &snapshot[..],
+        &bytes[..],
+    )
+    .unwrap();
+    assert_eq!(r3.len(), 2);
+
+    // but damage before the end of the log is reported
+    bytes.truncate(whole);
+    bytes[20] ^= 1;
+    assert_match!(
+        ev_slotmap::recover::<TestKey, (), String, ()>(
+            &snapshot[..],
+            &bytes[..]
+        ),
+        Err(SnapshotError::Corrupt(_))
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// A value that can only be logged while it's short enough
+#[derive(ShallowCopy, Debug, PartialEq)]
+struct Short(String);
+
+impl Encode for Short {
+    #[allow(clippy::io_other_error)]
+    fn encode(&self, writer: &mut dyn std::io::Write) -> std::io::Result<()> {
+        if self.0.len() > 4 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "too long to log",
+            ));
+        }
+        self.0.encode(writer)
+    }
+}
+
+impl Decode for Short {
+    fn decode(reader: &mut dyn std::io::Read) -> std::io::Result<Self> {
+        String::decode(reader).map(Short)
+    }
+}
+
+#[test]
+fn failed_log_appends_publish_nothing() {
+    let path = std::env::temp_dir()
+        .join(format!("ev_slotmap-wal-failed-{}.log", std::process::id()));
+
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), Short>();
+    let first = w.insert((), Short("a".to_string()));
+    let mut snapshot = Vec::new();
+    r.write_snapshot(&mut snapshot).unwrap();
+    w.log_to(WriteAheadLog::create(&path).unwrap());
+    let events = w.subscribe();
+
+    let kept = w.try_insert((), Short("kept".to_string())).unwrap();
+    assert!(w.try_insert((), Short("too long".to_string())).is_err());
+    assert!(w.try_update(kept, Short("too long".to_string())).is_err());
+    assert_eq!(w.generation(), 2);
+    assert_eq!(r.len(), 2);
+    assert_eq!(r.get(&kept).unwrap().0, "kept");
+    assert_eq!(events.try_recv(), Ok(ChangeEvent::Inserted(kept)));
+    assert_eq!(events.try_recv(), Err(TryRecvError::Empty));
+
+    // the handle carries on as if the failed writes were never made
+    let next = w.try_insert((), Short("next".to_string())).unwrap();
+    w.try_remove(&kept).unwrap();
+    w.sync_log().unwrap();
+
+    let bytes = std::fs::read(&path).unwrap();
+    let (r2, mut w2) = ev_slotmap::recover::<TestKey, (), Short, ()>(
+        &snapshot[..],
+        &bytes[..],
+    )
+    .unwrap();
+    assert_eq!(r2.len(), 2);
+    assert_eq!(r2.get(&first).unwrap().0, "a");
+    assert_eq!(r2.get(&next).unwrap().0, "next");
+    assert_eq!(w2.generation(), w.generation());
+    assert_eq!(
+        w.insert((), Short("same".to_string())),
+        w2.insert((), Short("same".to_string()))
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn checkpoints_compact_the_write_ahead_log() {
+    let dir = std::env::temp_dir()
+        .join(format!("ev_slotmap-checkpoint-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let snapshot = dir.join("map.snapshot");
+    let log = dir.join("map.log");
+
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), String>();
+    let mut keys = vec![w.insert((), "0".to_string())];
+    w.checkpoint(&snapshot).unwrap();
+    w.log_to(WriteAheadLog::create(&log).unwrap());
+    w.set_checkpoint_policy(&snapshot, CheckpointPolicy::EveryOps(4));
+
+    keys.extend((1..3).map(|i| w.insert((), i.to_string())));
+    let before_checkpoint = std::fs::read(&log).unwrap();
+    w.remove(&keys[1]);
+    w.update(keys[0], "zero".to_string());
+    let last = w.insert((), "last".to_string());
+
+    // the fourth logged write checkpointed, leaving only the one after it
+    let compacted = std::fs::read(&log).unwrap();
+    assert!(compacted.len() < before_checkpoint.len());
+    let (r2, _w2) = ev_slotmap::recover::<TestKey, (), String, ()>(
+        &std::fs::read(&snapshot).unwrap()[..],
+        &compacted[..],
+    )
+    .unwrap();
+    assert_eq!(r2.generation(), r.generation());
+    assert_eq!(*r2.get(&keys[0]).unwrap(), "zero");
+    assert_eq!(*r2.get(&last).unwrap(), "last");
+    assert_eq!(r2.lookup_status(&keys[1]), r.lookup_status(&keys[1]));
+
+    // a crash after the snapshot was replaced but before the log was
+    // truncated leaves writes in the log the snapshot already holds
+    let generation = w.checkpoint(&snapshot).unwrap();
+    assert_eq!(generation, r.generation());
+    let (r3, _w3) = ev_slotmap::recover::<TestKey, (), String, ()>(
+        &std::fs::read(&snapshot).unwrap()[..],
+        &compacted[..],
+    )
+    .unwrap();
+    assert_eq!(r3.len(), 3);
+    assert_eq!(r3.generation(), r.generation());
+
+    // a checkpoint that fails doesn't fail the write that set it off, but
+    // its error is kept for the caller
+    assert!(w.take_checkpoint_error().is_none());
+    let missing = dir.join("missing").join("map.snapshot");
+    w.set_checkpoint_policy(&missing, CheckpointPolicy::EveryOps(1));
+    let kept = w.insert((), "kept".to_string());
+    assert_eq!(*r.get(&kept).unwrap(), "kept");
+    assert_match!(w.take_checkpoint_error(), Some(SnapshotError::Io(_)));
+    assert!(w.take_checkpoint_error().is_none());
+    assert!(!missing.exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// One end of an in-memory pipe
+struct PipeWriter(std::sync::mpsc::Sender<Vec<u8>>);
+
+impl std::io::Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.send(buf.to_vec()).map_err(|_| {
+            std::io::Error::from(std::io::ErrorKind::BrokenPipe)
+        })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The other end of an in-memory pipe
+struct PipeReader(std::sync::mpsc::Receiver<Vec<u8>>, std::io::Cursor<Vec<u8>>);
+
+impl std::io::Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.1.position() == self.1.get_ref().len() as u64 {
+            match self.0.recv() {
+                Ok(bytes) => self.1 = std::io::Cursor::new(bytes),
+                Err(_) => return Ok(0),
+            }
+        }
+        self.1.read(buf)
+    }
+}
+
+fn replicate_through(
+    leader_end: impl std::io::Write + Send + 'static,
+    follower_end: impl std::io::Read + Send + 'static,
+) {
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), String>();
+    let early = w.insert((), "early".to_string());
+    let gone = w.insert((), "gone".to_string());
+
+    let follower = thread::spawn(move || {
+        let mut follower =
+            Follower::<_, TestKey, (), String>::new(follower_end).unwrap();
+        follower.run().unwrap();
+        follower.into_writer()
+    });
+
+    w.replicate_to(leader_end).unwrap();
+    assert_eq!(w.follower_count(), 1);
+    w.remove(&gone);
+    w.update(early, "updated".to_string());
+    let keys: Vec<_> = (0..3).map(|i| w.insert((), i.to_string())).collect();
+    w.remove(&keys[0]);
+    let generation = w.generation();
+    let leader = w.freeze();
+
+    let mut promoted = follower.join().unwrap();
+    assert_eq!(promoted.generation(), generation);
+    assert_eq!(promoted.len(), leader.len());
+    assert_eq!(*promoted.get(&early).unwrap(), "updated");
+    for key in keys.iter().chain(Some(&gone)) {
+        assert_eq!(promoted.lookup_status(key), r.lookup_status(key));
+        assert_eq!(
+            promoted.get(key).map(|v| v.clone()),
+            r.get(key).map(|v| v.clone())
+        );
+    }
+
+    // the follower can take over once the leader is gone
+    let next = promoted.insert((), "next".to_string());
+    assert_eq!(*promoted.get(&next).unwrap(), "next");
+}
+
+#[test]
+fn followers_replicate_the_leaders_keys() {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    replicate_through(
+        PipeWriter(sender),
+        PipeReader(receiver, Default::default()),
+    );
+
+    #[cfg(unix)]
+    {
+        let (leader_end, follower_end) =
+            std::os::unix::net::UnixStream::pair().unwrap();
+        replicate_through(leader_end, follower_end);
+    }
+}
+
+/// A stream whose reader never reads, so writes to it block until it's
+/// released
+struct StalledWriter(std::sync::mpsc::Receiver<()>);
+
+impl std::io::Write for StalledWriter {
+    fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+        let _ = self.0.recv();
+        Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn stalled_followers_dont_hold_up_the_leader() {
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), String>();
+    w.insert((), "first".to_string());
+
+    let (release, stalled) = std::sync::mpsc::channel();
+    w.replicate_to_with_capacity(StalledWriter(stalled), 4)
+        .unwrap();
+    let (sender, receiver) = std::sync::mpsc::channel();
+    w.replicate_to(PipeWriter(sender)).unwrap();
+    let follower = thread::spawn(move || {
+        let mut follower = Follower::<_, TestKey, (), String>::new(PipeReader(
+            receiver,
+            Default::default(),
+        ))
+        .unwrap();
+        follower.run().unwrap();
+        follower.into_writer()
+    });
+
+    // the stalled follower is dropped once its buffer is full, while the
+    // other one keeps up
+    for i in 0..4 {
+        w.insert((), i.to_string());
+    }
+    assert_eq!(w.follower_count(), 2);
+    let last = w.insert((), "last".to_string());
+    assert_eq!(w.follower_count(), 1);
+
+    let len = r.len();
+    drop(w);
+    let promoted = follower.join().unwrap();
+    assert_eq!(promoted.len(), len);
+    assert_eq!(*promoted.get(&last).unwrap(), "last");
+    drop(release);
+}
+
+#[test]
+fn keys_are_handed_out_in_the_documented_order() {
+    fn raw(index: u64, generation: u64) -> SlotMapKeyData {
+        SlotMapKeyData::from(index | generation << 40)
+    }
+
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), usize>();
+    let keys: Vec<_> = (0..4).map(|i| w.insert((), i)).collect();
+    let keys: Vec<_> = keys.iter().map(key_data).collect();
+    assert_eq!(keys, (0..4).map(|i| raw(i, 0)).collect::<Vec<_>>());
+
+    // a follower replays every insert with the same key
+    let (sender, receiver) = std::sync::mpsc::channel();
+    w.replicate_to(PipeWriter(sender)).unwrap();
+
+    // the most recently emptied slot is filled first, then new ones
+    w.remove(&TestKey::from(((), keys[1])));
+    w.remove(&TestKey::from(((), keys[3])));
+    assert_eq!(key_data(&w.insert((), 4)), raw(3, 2));
+    assert_eq!(key_data(&w.insert((), 5)), raw(1, 2));
+    assert_eq!(key_data(&w.insert((), 6)), raw(4, 0));
+
+    // a clear empties the slots lowest first, so the highest is filled first
+    w.clear();
+    assert_eq!(key_data(&w.insert((), 7)), raw(4, 2));
+    assert_eq!(key_data(&w.insert((), 8)), raw(3, 4));
+
+    // insert_at refuses filled slots and generations a slot has been at
+    assert_eq!(
+        w.insert_at(raw(3, 6), (), 9),
+        Err(InsertAtError::Occupied {
+            current_generation: 4
+        })
+    );
+    assert_eq!(
+        w.insert_at(raw(2, 0), (), 9),
+        Err(InsertAtError::Occupied {
+            current_generation: 1
+        })
+    );
+    let at = w.insert_at(raw(2, 6), (), 9).unwrap();
+    assert_eq!(*r.get(&at).unwrap(), 9);
+
+    // skipped slots have never held a value, so insert_at can fill them at
+    // any generation, in any order
+    w.insert_at(raw(8, 0), (), 10).unwrap();
+    assert_eq!(
+        r.lookup_status(&TestKey::from(((), raw(6, 0)))),
+        KeyStatus::OutOfRange
+    );
+    let mut snapshot = Vec::new();
+    r.write_snapshot(&mut snapshot).unwrap();
+    w.insert_at(raw(6, 0), (), 11).unwrap();
+    assert_eq!(
+        w.insert_at(raw(6, 0), (), 11),
+        Err(InsertAtError::Occupied {
+            current_generation: 0
+        })
+    );
+
+    // and the rest are filled lowest first, ahead of older empty ones
+    assert_eq!(key_data(&w.insert((), 12)), raw(5, 0));
+    assert_eq!(key_data(&w.insert((), 12)), raw(7, 0));
+    assert_eq!(key_data(&w.insert((), 13)), raw(1, 4));
+    assert_eq!(key_data(&w.insert((), 14)), raw(0, 2));
+    assert_eq!(key_data(&w.insert((), 15)), raw(9, 0));
+    assert_eq!(r.len(), 10);
+
+    // snapshots and dumps keep track of which slots have never held a value
+    let (loaded, _loaded_w) =
+        ev_slotmap::load_snapshot::<TestKey, (), usize, ()>(&snapshot[..])
+            .unwrap();
+    for &format in [DumpFormat::Json, DumpFormat::Csv, DumpFormat::Text].iter()
+    {
+        let mut dump = Vec::new();
+        loaded.read().unwrap().dump(&mut dump, format).unwrap();
+        let (_, mut reloaded) = ev_slotmap::load_dump::<TestKey, (), usize, _>(
+            &dump[..],
+            format,
+            |text| text.parse::<usize>(),
+        )
+        .unwrap();
+        reloaded.insert_at(raw(7, 0), (), 11).unwrap();
+        assert_eq!(key_data(&reloaded.insert((), 12)), raw(5, 0));
+        assert_eq!(key_data(&reloaded.insert((), 12)), raw(6, 0));
+    }
+
+    // keys far past the end are refused rather than filling memory with
+    // empty slots
+    assert_eq!(
+        w.insert_at(raw(10 + 65537, 0), (), 16),
+        Err(InsertAtError::TooFarPastEnd { slot_count: 10 })
+    );
+    w.insert_at(raw(10 + 65536, 0), (), 16).unwrap();
+    let (_, mut empty) = ev_slotmap::new::<TestKey, (), usize>();
+    assert_eq!(
+        empty.insert_at(raw(1 << 39, 0), (), 0),
+        Err(InsertAtError::TooFarPastEnd { slot_count: 0 })
+    );
+
+    let generation = w.generation();
+    let leader = w.freeze();
+    let mut follower = Follower::<_, TestKey, (), usize>::new(PipeReader(
+        receiver,
+        Default::default(),
+    ))
+    .unwrap();
+    follower.run().unwrap();
+    assert_eq!(follower.generation(), generation);
+    let values = |map: &ev_slotmap::ReadHandle<TestKey, (), usize>| {
+        let map = map.read().unwrap();
+        map.iter_raw().map(|(k, v)| (k, *v)).collect::<Vec<_>>()
+    };
+    assert_eq!(values(&follower), values(&leader));
+}
+
+#[test]
+fn dumps_load_back_into_the_same_map() {
+    let (r, mut w) = ev_slotmap::new::<TestKey, (), String>();
+    let keys: Vec<_> = ["zero", "one \"quoted\", with a comma", "two", "three"]
+        .iter()
+        .map(|v| w.insert((), v.to_string()))
+        .collect();
+    w.remove(&keys[2]);
+    w.remove(&keys[0]);
+
+    let unquote = |text: &str| -> Result<String, String> {
+        text.strip_prefix('"')
+            .and_then(|text| text.strip_suffix('"'))
+            .map(|text| text.replace("\\\"", "\""))
+            .ok_or_else(|| format!("{} isn't quoted", text))
+    };
+
+    for &format in [DumpFormat::Json, DumpFormat::Csv, DumpFormat::Text].iter()
+    {
+        let mut dump = Vec::new();
+        r.read().unwrap().dump(&mut dump, format).unwrap();
+
+        let (r2, mut w2) = ev_slotmap::load_dump::<TestKey, (), String, _>(
+            &dump[..],
+            format,
+            unquote,
+        )
+        .unwrap();
+        assert_eq!(r2.len(), 2);
+        for key in keys.iter() {
+            assert_eq!(r2.lookup_status(key), r.lookup_status(key));
+            assert_eq!(
+                r2.get(key).map(|v| v.clone()),
+                r.get(key).map(|v| v.clone())
+            );
+        }
+        // the emptied slots are refilled in the same order
+        assert_eq!(
+            key_data(&w2.insert((), "a".to_string())),
+            SlotMapKeyData::from(2 << 40)
+        );
+        assert_eq!(
+            key_data(&w2.insert((), "b".to_string())),
+            SlotMapKeyData::from(2 | 2 << 40)
+        );
+    }
+
+    // fixtures can be written by hand
+    let fixture = "\
+        # a map with a gap\n\
+        0 gen 0 occupied 10\n\
+        1 gen 1 vacant -> end\n\
+        2 gen 4 occupied 12\n";
+    let (r3, mut w3) = ev_slotmap::load_dump::<TestKey, (), u32, _>(
+        fixture.as_bytes(),
+        DumpFormat::Text,
+        str::parse,
+    )
+    .unwrap();
+    assert_eq!(r3.len(), 2);
+    assert_eq!(
+        key_data(&w3.insert((), 11)),
+        SlotMapKeyData::from(1 | 2 << 40)
+    );
+    assert_match!(
+        ev_slotmap::load_dump::<TestKey, (), u32, _>(
+            "0 gen 1 occupied 10".as_bytes(),
+            DumpFormat::Text,
+            str::parse,
+        ),
+        Err(_)
+    );
+}
+
+#[test]
+fn maps_build_from_std_collections() {
+    let values = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+    let (r, mut w, keys) =
+        ev_slotmap::from_iter_with_keys::<TestKey, (), _>(values, |_| ());
+
+    // readers see the values without any refresh
+    assert_eq!(r.len(), 3);
+    for (i, (key, value)) in keys.iter().zip(&["a", "b", "c"]).enumerate() {
+        assert_eq!(key_data(key), SlotMapKeyData::from(i as u64));
+        assert_eq!(r.get(key).map(|v| v.clone()), Some(value.to_string()));
+    }
+    // and new keys follow on after them
+    assert_eq!(
+        key_data(&w.insert((), "d".to_string())),
+        SlotMapKeyData::from(3)
+    );
+    assert_eq!(r.len(), 4);
+
+    let mut ids = std::collections::HashMap::new();
+    ids.insert(7u32, 70u32);
+    ids.insert(8, 80);
+    ids.insert(9, 90);
+    let (r2, _w2, keys2) =
+        ev_slotmap::from_hash_map::<TestKey, (), _, _, _>(ids, |_| ());
+    assert_eq!(r2.len(), 3);
+    assert_eq!(keys2.len(), 3);
+    for (id, key) in &keys2 {
+        assert_eq!(r2.get(key).map(|v| *v), Some(id * 10));
+    }
+}
@@ -0,0 +1,67 @@
+#![cfg(feature = "serde")]
+
+use ev_slotmap::SerializedMap;
+use one_way_slot_map::define_key_type;
+use serde::{Deserialize, Serialize};
+
+define_key_type!(TestKey<()> : Default + Clone + Copy + Debug + PartialEq);
+
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    #[serde(with = "ev_slotmap::serde_key")]
+    key: TestKey,
+    json: String,
+}
+
+#[test]
+fn keys_resolve_after_a_round_trip() {
+    let (r, mut w) = ev_slotmap::with_meta::<TestKey, (), String, u32>(7);
+    let keys: Vec<_> = (0..5).map(|i| w.insert((), i.to_string())).collect();
+    w.remove(&keys[1]);
+    w.remove(&keys[3]);
+    w.set_meta(8);
+    w.update(keys[0], "zero".to_string());
+
+    let json = serde_json::to_string(&r.read().unwrap()).unwrap();
+    let saved = serde_json::to_string(&Saved { key: keys[4], json }).unwrap();
+
+    let saved: Saved = serde_json::from_str(&saved).unwrap();
+    let map: SerializedMap<String, u32> =
+        serde_json::from_str(&saved.json).unwrap();
+    assert_eq!(map.len(), 3);
+    assert_eq!(*map.meta(), 8);
+
+    let (r2, mut w2) =
+        ev_slotmap::new_from_serialized::<TestKey, (), _, _>(map);
+    assert_eq!(*r2.get(&keys[0]).unwrap(), "zero");
+    assert_eq!(*r2.get(&keys[2]).unwrap(), "2");
+    assert_eq!(*r2.get(&saved.key).unwrap(), "4");
+    assert!(!r2.contains_key(&keys[1]));
+    assert!(!r2.contains_key(&keys[3]));
+    assert_eq!(*r2.meta().unwrap(), 8);
+
+    // both maps reuse the same empty slots in the same order
+    assert_eq!(
+        w.insert((), "a".to_string()),
+        w2.insert((), "a".to_string())
+    );
+    assert_eq!(
+        w.insert((), "b".to_string()),
+        w2.insert((), "b".to_string())
+    );
+    assert_eq!(
+        w.insert((), "c".to_string()),
+        w2.insert((), "c".to_string())
+    );
+}
+
+#[test]
+fn broken_layouts_are_rejected() {
+    let json = r#"{
+        "meta": null,
+        "free_head": 0,
+        "slots": [{ "Vacant": { "generation": 1, "next": 0 } }]
+    }"#;
+    let error = serde_json::from_str::<SerializedMap<u32>>(json).unwrap_err();
+    assert!(error.to_string().contains("Free list is broken"));
+}
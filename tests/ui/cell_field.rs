@@ -0,0 +1,10 @@
+use ev_slotmap::ShallowCopy;
+use std::cell::Cell;
+
+#[derive(ShallowCopy)]
+struct Counter {
+    name: String,
+    count: Cell<u32>,
+}
+
+fn main() {}
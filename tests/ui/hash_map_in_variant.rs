@@ -0,0 +1,10 @@
+use ev_slotmap::ShallowCopy;
+use std::collections::HashMap;
+
+#[derive(ShallowCopy)]
+enum Config {
+    Empty,
+    Values(HashMap<String, String>),
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use ev_slotmap::ShallowCopy;
+
+#[derive(ShallowCopy)]
+union Bits {
+    int: u32,
+    float: f32,
+}
+
+fn main() {}